@@ -1,9 +1,10 @@
-#![feature(try_from)]
+#![allow(dead_code)]
 extern crate hyperspeed;
 use hyperspeed::network::*;
-use hyperspeed::script::{PythonInterpreter, InterpreterResult};
+use hyperspeed::script::PythonInterpreter;
 use bytes::{BytesMut};
 use std::convert::TryFrom;
+use std::error::Error;
 
 #[derive(Clone, Debug)]
 pub struct Message {
@@ -20,8 +21,8 @@ impl TryFrom<ClientMessage> for Message {
     }
 }
 
-impl Into<ClientMessage> for Message {
-    fn into(self) -> ClientMessage {
+impl From<Message> for ClientMessage {
+    fn from(_: Message) -> Self {
         ClientMessage {
             bytes: BytesMut::new()
         }
@@ -29,7 +30,7 @@ impl Into<ClientMessage> for Message {
 }
 
 
-fn main() -> InterpreterResult<()> {
+fn main() -> Result<(), Box<dyn Error>> {
     let mut py = PythonInterpreter::new();
     py.include("./examples")?;
     let module = py.load_module("example")?;
@@ -37,8 +38,8 @@ fn main() -> InterpreterResult<()> {
     let test_value: Box<u32> = py.convert(&test_value)?;
     println!("Test value from Python: {}", test_value);
 
-    let server = Server::<Message>::new().build().run();
-    loop {}
-
-    Ok(())
+    let _server = Server::<Message>::new().build().expect("The server could not start listening").run();
+    loop {
+        std::thread::park();
+    }
 }
\ No newline at end of file
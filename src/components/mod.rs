@@ -1,30 +1,35 @@
 use super::*;
+use specs::prelude::*;
 
-pub type ZLevelID = &'static str;
+pub type ZLevelID = String;
 pub type SpriteID = u64;
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Position {
     pub x: f32,
     pub y: f32,
     z_level: ZLevelID
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Visible {
     pub sprite: SpriteID
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PositionTiled {
     pub x: u32,
     pub y: u32,
     pub z_level: ZLevelID
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Camera {
     pub view_range: u16,
     pub offset: (u32, u32)
 }
 
-define_component!(Position);
-define_component!(Visible);
-define_component!(PositionTiled);
-define_component!(Camera);
\ No newline at end of file
+define_component!(Position, "position");
+define_component!(Visible, "visible");
+define_component!(PositionTiled, "position_tiled");
+define_component!(Camera, "camera");
\ No newline at end of file
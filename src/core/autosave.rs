@@ -0,0 +1,212 @@
+use super::snapshot::{Snapshot, SnapshotError};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_EXTENSION: &str = ".json";
+const TEMPORARY_EXTENSION: &str = ".tmp";
+
+/// How often the engine writes an autosave.
+#[derive(Clone, Copy, Debug)]
+pub enum AutosaveInterval {
+    Ticks(u64),
+    Time(Duration)
+}
+
+/// Settings for periodic snapshots, passed to `EngineBuilder::with_autosave`.
+#[derive(Clone, Debug)]
+pub struct AutosaveConfig {
+    pub directory: PathBuf,
+    pub interval: AutosaveInterval,
+    /// How many snapshots to keep. Older ones are deleted after each save.
+    pub keep: usize
+}
+
+/// Writes snapshots on a background thread, so saving never holds up a tick.
+pub(crate) struct Autosaver {
+    config: AutosaveConfig,
+    last_tick: u64,
+    last_time: Instant,
+    writer: Option<Sender<Snapshot>>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl AutosaveConfig {
+    /// Saves into `directory` once a minute, keeping the last five snapshots.
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        AutosaveConfig {
+            directory: directory.into(),
+            interval: AutosaveInterval::Time(Duration::from_secs(60)),
+            keep: 5
+        }
+    }
+
+    pub fn every_ticks(mut self, ticks: u64) -> Self {
+        self.interval = AutosaveInterval::Ticks(ticks);
+        self
+    }
+
+    pub fn every(mut self, time: Duration) -> Self {
+        self.interval = AutosaveInterval::Time(time);
+        self
+    }
+
+    pub fn keep(mut self, count: usize) -> Self {
+        self.keep = count.max(1);
+        self
+    }
+}
+
+/// The files in a directory whose names start with the snapshot prefix and end with `extension`.
+fn files_ending_with(directory: &Path, extension: &str) -> Vec<PathBuf> {
+    match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                let name = p.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(extension)
+            })
+            .collect(),
+        Err(_) => vec![]
+    }
+}
+
+/// Which save a snapshot file was, from the counter at the start of its name. The tick can't be
+/// used: a restarted or rolled back engine saves lower ticks after higher ones. Files named by
+/// tick alone come from before the counter, so they count as older than any that have one.
+fn save_number(path: &Path) -> u64 {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let stem = name.trim_start_matches(SNAPSHOT_PREFIX).trim_end_matches(SNAPSHOT_EXTENSION);
+    match stem.split_once('-') {
+        Some((number, _)) => number.parse().unwrap_or(0),
+        None => 0
+    }
+}
+
+/// The snapshot files in a directory, newest first.
+pub(crate) fn snapshot_files(directory: &Path) -> Vec<PathBuf> {
+    let mut files = files_ending_with(directory, SNAPSHOT_EXTENSION);
+    files.sort_by_key(|path| (save_number(path), path.clone()));
+    files.reverse();
+    files
+}
+
+/// Loads the newest snapshot in a directory that can be read. Snapshots that fail to load are
+/// skipped, so a file that was damaged by a crash doesn't stop recovery.
+pub fn latest_valid_snapshot<P: AsRef<Path>>(directory: P) -> Option<(PathBuf, Snapshot)> {
+    for path in snapshot_files(directory.as_ref()) {
+        match Snapshot::read(&path) {
+            Ok(snapshot) => return Some((path, snapshot)),
+            Err(e) => println!("Skipping unreadable snapshot {}: {}", path.display(), e)
+        }
+    }
+    None
+}
+
+/// Writes the snapshot to a temporary file and renames it into place, so a crash mid-write
+/// never leaves a partial snapshot behind. A write that fails removes its temporary file.
+fn write_atomically(directory: &Path, number: u64, snapshot: &Snapshot) -> Result<PathBuf, SnapshotError> {
+    fs::create_dir_all(directory)?;
+    let name = format!("{}{:020}-{:020}{}", SNAPSHOT_PREFIX, number, snapshot.tick, SNAPSHOT_EXTENSION);
+    let path = directory.join(&name);
+    let tmp_path = directory.join(format!("{}{}", name, TEMPORARY_EXTENSION));
+
+    let written = snapshot.to_json().and_then(|json| {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&tmp_path, &path).map_err(SnapshotError::from)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(path)
+}
+
+/// Removes temporary files left behind by writes that a crash cut short.
+fn remove_temporary_files(directory: &Path) {
+    for tmp in files_ending_with(directory, TEMPORARY_EXTENSION) {
+        if let Err(e) = fs::remove_file(&tmp) {
+            println!("Could not remove unfinished snapshot {}: {}", tmp.display(), e);
+        }
+    }
+}
+
+fn rotate(directory: &Path, keep: usize) {
+    for old in snapshot_files(directory).into_iter().skip(keep) {
+        if let Err(e) = fs::remove_file(&old) {
+            println!("Could not remove old snapshot {}: {}", old.display(), e);
+        }
+    }
+}
+
+impl Autosaver {
+    pub(crate) fn new(config: AutosaveConfig) -> Self {
+        let (writer, snapshots) = channel::<Snapshot>();
+        let directory = config.directory.clone();
+        let keep = config.keep;
+        remove_temporary_files(&directory);
+        // Saves carry on numbering from the newest one already in the directory.
+        let mut number = snapshot_files(&directory).first().map(|path| save_number(path)).unwrap_or(0);
+        let handle = spawn(move || {
+            for snapshot in snapshots {
+                number += 1;
+                match write_atomically(&directory, number, &snapshot) {
+                    Ok(_) => rotate(&directory, keep),
+                    Err(e) => println!("Autosave at tick {} failed: {}", snapshot.tick, e)
+                }
+            }
+        });
+        Autosaver {
+            config,
+            last_tick: 0,
+            last_time: Instant::now(),
+            writer: Some(writer),
+            handle: Some(handle)
+        }
+    }
+
+    pub(crate) fn directory(&self) -> &Path {
+        &self.config.directory
+    }
+
+    pub(crate) fn is_due(&self, tick: u64) -> bool {
+        match self.config.interval {
+            AutosaveInterval::Ticks(ticks) => tick >= self.last_tick + ticks,
+            AutosaveInterval::Time(time) => self.last_time.elapsed() >= time
+        }
+    }
+
+    /// Hands a snapshot to the writer thread.
+    pub(crate) fn save(&mut self, snapshot: Snapshot) {
+        self.last_tick = snapshot.tick;
+        self.last_time = Instant::now();
+        if let Some(ref writer) = self.writer {
+            if writer.send(snapshot).is_err() {
+                println!("Autosave writer thread has exited. The snapshot was dropped.");
+            }
+        }
+    }
+
+    /// Continues the save schedule from a recovered snapshot.
+    pub(crate) fn resume_from(&mut self, tick: u64) {
+        self.last_tick = tick;
+        self.last_time = Instant::now();
+    }
+}
+
+impl Drop for Autosaver {
+    fn drop(&mut self) {
+        // Let the writer finish the snapshots it has been given before the engine goes away.
+        self.writer = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -1,38 +1,103 @@
 use super::world::*;
 use super::Server;
 use super::ServerConfig;
-use super::PlayerInputBuffer;
+use super::ServerControl;
 use crate::utils::*;
 
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Sender, Receiver};
-use std::thread::spawn;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
 
 use std::collections::{HashMap, VecDeque};
 use specs::Component;
 use super::server::InputBufferMutex;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use std::path::Path;
+use specs::Entity;
+use serde_json::{Map, Value};
 use crate::core::world::Connection;
 use crate::core::server::StreamData;
-use std::net::TcpStream;
+use crate::core::snapshot::{Snapshot, SnapshotError};
+use crate::core::autosave::{Autosaver, AutosaveConfig, snapshot_files};
+use crate::core::replay::{Recorder, Replay, ReplayError, RoomMove, TickRecord};
+use crate::error::HyperspeedError;
+use crate::core::room::{Room, RoomManager, RoomError};
+use crate::core::matchmaking::{Match, Matchmaker, MatchmakingQueue, MATCH_ROOM_PREFIX};
+use crate::core::metrics::{MetricsExporter, MetricsSchedule};
+use crate::core::shutdown::{Goodbye, ShutdownReport};
+use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use crate::utils::server::ClientStream;
+use crate::utils::bind::BindAddress;
+use crate::utils::tls::TlsConfig;
+use crate::utils::heartbeat::HeartbeatConfig;
+use crate::utils::ratelimit::RateLimitConfig;
+use crate::utils::queue::{QueueSender, SendQueueConfig};
 use crate::components::{Position, Camera, Visible};
+use crate::script::{PythonInterpreter, ScriptError, Script, ScriptEvents};
 
 pub struct Engine<'a, 'b, E: Sync + Send + Clone + 'static> {
     pub world: World<'a, 'b>,
-    master_controller: Box<MasterController<ObserverEvent=E>>,
+    master_controller: Box<dyn MasterController<ObserverEvent=E>>,
     input_buffer: Option<InputBufferMutex>,
     server_conf: ServerConfig,
+    server: Option<ServerControl>,
     prev_time: Instant,
-    connection_channel: Receiver<(Connection, Sender<ClientView>)>,
-    view_channels: HashMap<String, Sender<ClientView>>,
-    server_stream_handler: Option<StreamHandler>
+    connection_channel: Receiver<(Connection, QueueSender<Outbound>)>,
+    event_channel: Receiver<ConnectionEvent>,
+    // Each connection's channel, and its stats so the send queue can be counted.
+    view_channels: HashMap<String, (QueueSender<Outbound>, LinkStats)>,
+    server_stream_handler: Option<StreamHandler>,
+    scripts: Option<PythonInterpreter>,
+    blueprints: BlueprintRegistry,
+    components: ComponentRegistry,
+    tick_count: u64,
+    autosaver: Option<Autosaver>,
+    recover: bool,
+    recording: Option<PathBuf>,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    // Moves made between ticks, recorded with the next tick.
+    room_moves: Vec<RoomMove>,
+    rooms: RoomManager<'a, 'b, E>,
+    matchmaker: Option<(Matchmaker, MatchFactory<'a, 'b, E>)>,
+    local_components: Vec<fn(&mut specs::World)>,
+    metrics: Option<MetricsSchedule>
 }
 
 pub struct EngineBuilder<'a, 'b, E: Sync + Send + Clone + 'static> {
     server_conf: ServerConfig,
     system_executor_builder: SystemExecutorBuilder<'a, 'b>,
-    master_controller: Option<Box<MasterController<ObserverEvent=E>>>,
-    server_stream_handler: Option<StreamHandler>
+    master_controller: Option<Box<dyn MasterController<ObserverEvent=E>>>,
+    server_stream_handler: Option<StreamHandler>,
+    scripts: Option<PythonInterpreter>,
+    autosave: Option<AutosaveConfig>,
+    recover: bool,
+    recording: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    matchmaker: Option<(Matchmaker, MatchFactory<'a, 'b, E>)>,
+    metrics: Option<MetricsSchedule>
+}
+
+/// Builds the systems and master controller of the room a new match is played in.
+pub type MatchFactory<'a, 'b, E> = Box<dyn FnMut(&Match) -> (SystemExecutorBuilder<'a, 'b>, Box<dyn MasterController<ObserverEvent=E>>) + 'a>;
+
+/// Adds the resources every world the engine runs needs.
+pub(crate) fn add_default_resources<E: Sync + Send + 'static>(world: &mut specs::World) {
+    // This is the event/messaging
+    world.add_resource(Messages::<E>::new());
+    world.add_resource(InputMap::new());
+    world.add_resource(ViewMap::new());
+    world.add_resource(Outbox::new());
+    world.add_resource(ConnectionCollection::new());
+    world.add_resource(ConnectionStats::new());
+    world.add_resource(ConnectionEvents::new());
+    world.add_resource(ScriptEvents::new());
+    world.add_resource(DeltaTime::default());
+}
+
+fn register_storage<T: Component>(world: &mut specs::World)
+where <T as Component>::Storage : std::default::Default {
+    world.register::<T>();
 }
 
 impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
@@ -41,136 +106,549 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
             server_conf: ServerConfig::new(),
             system_executor_builder: SystemExecutor::new(),
             master_controller: None,
-            server_stream_handler: None
+            server_stream_handler: None,
+            scripts: None,
+            autosave: None,
+            recover: false,
+            recording: None,
+            replay: None,
+            matchmaker: None,
+            metrics: None
         }
     }
 
     pub fn init_resources(&mut self) {
-        // This is the event/messaging
-        self.world.ecs_world.add_resource(Messages::<E>::new());
-        self.world.ecs_world.add_resource(InputMap::new());
-        self.world.ecs_world.add_resource(ViewMap::new());
-        self.world.ecs_world.add_resource(ConnectionCollection::new());
+        add_default_resources::<E>(&mut self.world.ecs_world);
+        // Players queue from the main world, so only it has a matchmaking queue.
+        let mut queue = MatchmakingQueue::new();
+        if let Some((ref matchmaker, _)) = self.matchmaker {
+            for (name, match_size) in matchmaker.match_sizes() {
+                queue.set_match_size(name, match_size);
+            }
+        }
+        self.world.ecs_world.add_resource(queue);
 
         // Register default components
 
-        self.world.ecs_world.register::<Position>();
-        self.world.ecs_world.register::<Visible>();
-        self.world.ecs_world.register::<Camera>();
+        self.register::<Position>();
+        self.register::<Visible>();
+        self.register::<Camera>();
+        self.register_local::<Script>();
     }
 
-    pub fn register<T: Component>(&mut self)
+    /// Registers a component with the world, and records it in the engine's component registry.
+    /// Registered components can be used in blueprints and snapshots.
+    /// Rooms created afterwards get the component too.
+    pub fn register<T: NamedComponent>(&mut self)
     where <T as Component>::Storage : std::default::Default {
+        self.components.register::<T>();
         self.world.ecs_world.register::<T>();
     }
 
-    pub fn start_server(&mut self) {
-        fn default(t: &mut TcpStream) -> StreamData {
-            StreamData::do_connect_str("default_key")
+    /// Registers a component that can't be serialized. It stays out of blueprints and snapshots.
+    pub fn register_local<T: Component>(&mut self)
+    where <T as Component>::Storage : std::default::Default {
+        self.local_components.push(register_storage::<T>);
+        self.world.ecs_world.register::<T>();
+    }
+
+    /// Starts a new room with its own world, systems and master controller. The room is empty
+    /// until connections are moved into it with `move_to_room`. IDs starting with
+    /// `MATCH_ROOM_PREFIX` are kept for the rooms matchmaking starts.
+    pub fn create_room<M>(&mut self, id: &str, systems: SystemExecutorBuilder<'a, 'b>, master_controller: M) -> Result<(), RoomError>
+    where
+        M: MasterController<ObserverEvent=E> + 'static {
+        if id.starts_with(MATCH_ROOM_PREFIX) {
+            return Err(RoomError::ReservedID(id.to_string()));
+        }
+        self.add_room(id, systems, Box::new(master_controller))
+    }
+
+    fn add_room(&mut self, id: &str, systems: SystemExecutorBuilder<'a, 'b>, master_controller: Box<dyn MasterController<ObserverEvent=E>>) -> Result<(), RoomError> {
+        if self.rooms.contains(id) {
+            return Err(RoomError::AlreadyExists(id.to_string()));
         }
+        let room = Room::new(id, systems, master_controller, &self.components, &self.local_components);
+        self.rooms.insert(room)
+    }
 
+    /// Stops a room and deletes its world. Its connections go back to the main world.
+    pub fn destroy_room(&mut self, id: &str) -> Result<(), RoomError> {
+        let room = self.rooms.remove(id)?;
+        for connection in room.world.connections.connections {
+            self.world.connections.push(connection);
+        }
+        Ok(())
+    }
 
-        let (sender, reciever) = channel();
+    /// Moves a connection into a room, or back to the main world when `room` is `None`. The
+    /// connection shows up as a new connection in the world it moves to. While replaying, moves
+    /// come from the recording instead, so this does nothing.
+    pub fn move_to_room(&mut self, key: &str, room: Option<&str>) -> Result<(), RoomError> {
+        if self.replay.is_some() {
+            return Ok(());
+        }
+        self.move_connection(key, room)?;
+        if self.recorder.is_some() {
+            self.room_moves.push(RoomMove {
+                key: key.to_string(),
+                room: room.map(|id| id.to_string())
+            });
+        }
+        Ok(())
+    }
+
+    fn move_connection(&mut self, key: &str, room: Option<&str>) -> Result<(), RoomError> {
+        if let Some(id) = room {
+            if !self.rooms.contains(id) {
+                return Err(RoomError::UnknownRoom(id.to_string()));
+            }
+        }
+        let connection = match self.rooms.leave(key) {
+            Some(connection) => connection,
+            None => {
+                self.world.take_inputs(key);
+                self.world.connections.take(key)
+                    .ok_or_else(|| RoomError::UnknownConnection(key.to_string()))?
+            }
+        };
+        match room {
+            Some(id) => self.rooms.join(id, connection),
+            None => {
+                self.world.connections.push(connection);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn rooms(&mut self) -> &mut RoomManager<'a, 'b, E> {
+        &mut self.rooms
+    }
+
+    /// Forms matches out of the main world's `MatchmakingQueue`, and starts a room for each one
+    /// with the players moved into it. This runs every tick when the engine has a matchmaker.
+    /// While replaying, the matches come from the recording instead.
+    fn run_matchmaking(&mut self, record: &mut TickRecord) {
+        let matches = match self.matchmaker {
+            Some((ref mut matchmaker, _)) => {
+                let mut queue = self.world.ecs_world.write_resource::<MatchmakingQueue>();
+                if self.replay.is_some() {
+                    for player in record.matches.iter().flat_map(|found| &found.players) {
+                        queue.leave(&player.key);
+                    }
+                    record.matches.clone()
+                } else {
+                    matchmaker.find_matches(&mut queue)
+                }
+            },
+            None => return
+        };
+        if self.recorder.is_some() {
+            record.matches = matches.clone();
+        }
+        for found in matches {
+            let (systems, master_controller) = (self.matchmaker.as_mut().unwrap().1)(&found);
+            if let Err(e) = self.add_room(&found.id, systems, master_controller) {
+                println!("Engine could not start a room for match {}: {}. Its players are queued again.", found.id, e);
+                self.world.ecs_world.write_resource::<MatchmakingQueue>().put_back(found);
+                continue;
+            }
+            for player in &found.players {
+                if let Err(e) = self.move_connection(&player.key, Some(&found.id)) {
+                    println!("Engine could not move a player into match {}: {}", found.id, e);
+                }
+            }
+        }
+    }
+
+    /// Saves a resource in snapshots. Connections are never saved, because clients have to
+    /// reconnect after a restart anyway.
+    pub fn persist_resource<R: NamedResource>(&mut self) {
+        self.components.register_resource::<R>();
+    }
+
+    /// The number of ticks the engine has run.
+    pub fn tick_count(&self) -> u64 {
+        self.tick_count
+    }
+
+    /// Copies every registered component and persisted resource out of the world.
+    pub fn snapshot(&self) -> Result<Snapshot, SnapshotError> {
+        Snapshot::capture(&self.world.ecs_world, &self.components, self.tick_count)
+    }
 
-        let mut handler = None;
+    /// Writes every registered component and persisted resource to a file.
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        self.snapshot()?.write(path)
+    }
 
-        ::std::mem::swap(&mut self.server_stream_handler, &mut handler);
+    /// Replaces the world with the contents of a snapshot file. Connections are left as they are.
+    pub fn load_snapshot<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SnapshotError> {
+        let snapshot = Snapshot::read(path)?;
+        let tick = snapshot.tick;
+        snapshot.restore(&mut self.world.ecs_world, &self.components)?;
+        self.tick_count = tick;
+        Ok(())
+    }
 
-        let handler = handler
+    /// Loads the newest autosave that can be restored, and returns the tick it was saved at.
+    /// Snapshots that can't be read or restored are skipped in favour of older ones. Without any
+    /// autosaves this is `Ok(None)`; if every one fails, the last error is returned.
+    pub fn recover(&mut self) -> Result<Option<u64>, SnapshotError> {
+        let directory = match self.autosaver {
+            Some(ref autosaver) => autosaver.directory().to_path_buf(),
+            None => return Ok(None)
+        };
+        let mut failure = None;
+        for path in snapshot_files(&directory) {
+            let restored = Snapshot::read(&path).and_then(|snapshot| {
+                let tick = snapshot.tick;
+                snapshot.restore(&mut self.world.ecs_world, &self.components).map(|_| tick)
+            });
+            match restored {
+                Ok(tick) => {
+                    println!("Engine recovered from snapshot {}", path.display());
+                    self.tick_count = tick;
+                    if let Some(ref mut autosaver) = self.autosaver {
+                        autosaver.resume_from(tick);
+                    }
+                    return Ok(Some(tick));
+                },
+                Err(e) => {
+                    println!("Engine could not recover from snapshot {}: {}", path.display(), e);
+                    failure = Some(e);
+                }
+            }
+        }
+        failure.map_or(Ok(None), Err)
+    }
+
+    fn autosave(&mut self) {
+        let due = match self.autosaver {
+            Some(ref autosaver) => autosaver.is_due(self.tick_count),
+            None => false
+        };
+        if !due {
+            return;
+        }
+        // Capturing serializes every component on this thread. Only turning the snapshot into
+        // text and writing it out happen on the autosaver's.
+        match self.snapshot() {
+            Ok(snapshot) => self.autosaver.as_mut().unwrap().save(snapshot),
+            Err(e) => println!("Engine could not take an autosave snapshot: {}", e)
+        }
+    }
+
+    /// The registry of every serializable component.
+    pub fn components(&self) -> &ComponentRegistry {
+        &self.components
+    }
+
+    /// Serializes every registered component of an entity, keyed by component name. This is the
+    /// form entities are saved in by snapshots.
+    pub fn serialize_entity(&self, entity: Entity) -> Result<Map<String, Value>, String> {
+        self.components.serialize_entity(&self.world.ecs_world, entity)
+    }
+
+    /// Reads one component of an entity in its serialized form.
+    pub fn component_value(&self, entity: Entity, name: &str) -> Option<Value> {
+        self.components.get(name)?
+            .serialize(&self.world.ecs_world, entity)?
+            .ok()
+    }
+
+    /// Replaces one component of an entity from its serialized form, for example with a value a
+    /// script has changed.
+    pub fn set_component_value(&mut self, entity: Entity, name: &str, value: Value) -> Result<(), String> {
+        let entry = self.components.get(name)
+            .ok_or_else(|| format!("Component '{}' isn't registered", name))?;
+        entry.deserialize(&mut self.world.ecs_world, entity, value)
+    }
+
+    /// Adds every prefab in a `.json`, `.ron` or `.toml` file to the engine's blueprints.
+    pub fn load_blueprints<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<String>, BlueprintError> {
+        self.blueprints.load_file(path)
+    }
+
+    pub fn blueprints(&mut self) -> &mut BlueprintRegistry {
+        &mut self.blueprints
+    }
+
+    /// Creates an entity from the prefab called `name`.
+    pub fn spawn(&mut self, name: &str) -> Result<Entity, BlueprintError> {
+        self.blueprints.spawn(name, &self.components, &mut self.world.ecs_world)
+    }
+
+    /// Spawns the blueprints that scripts asked for with `hyperspeed.spawn`. This runs every tick
+    /// when the engine has an interpreter, and does nothing when it doesn't.
+    pub fn spawn_script_requests(&mut self) {
+        let requests = match self.scripts {
+            Some(ref mut scripts) => scripts.take_spawn_requests(),
+            None => return
+        };
+        self.spawn_requested(requests);
+    }
+
+    /// Spawns the blueprints that scripts in another interpreter (a `PythonScriptSystem`'s, for
+    /// example) asked for. Call this between ticks.
+    pub fn spawn_script_requests_from(&mut self, interpreter: &mut PythonInterpreter) {
+        let requests = interpreter.take_spawn_requests();
+        self.spawn_requested(requests);
+    }
+
+    /// Spawns the blueprints that scripts in another interpreter asked for into the world of
+    /// `room`, for interpreters that run that room's scripts. Call this between ticks.
+    pub fn spawn_room_script_requests_from(&mut self, room: &str, interpreter: &mut PythonInterpreter) -> Result<(), RoomError> {
+        if !self.rooms.contains(room) {
+            return Err(RoomError::UnknownRoom(room.to_string()));
+        }
+        let requests = match interpreter.take_spawn_requests() {
+            Ok(requests) => requests,
+            Err(e) => {
+                self.log_script_error(&e);
+                return Ok(());
+            }
+        };
+        let world = &mut self.rooms.get_mut(room).unwrap().world;
+        for name in requests {
+            if let Err(e) = self.blueprints.spawn(&name, &self.components, &mut world.ecs_world) {
+                println!("Engine could not spawn blueprint for a script in room '{}': {}", room, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn_requested(&mut self, requests: Result<Vec<String>, ScriptError>) {
+        match requests {
+            Ok(requests) => {
+                for name in requests {
+                    if let Err(e) = self.spawn(&name) {
+                        println!("Engine could not spawn blueprint for a script: {}", e);
+                    }
+                }
+            },
+            Err(e) => self.log_script_error(&e)
+        }
+    }
+
+    /// Starts accepting connections and calls the master controller's `start`. In replay mode no
+    /// sockets are opened; connections come from the recording instead. Fails, before any client
+    /// is accepted, if the recording can't be created, if recovery finds only broken autosaves,
+    /// or if a listener can't be bound.
+    pub fn start_server(&mut self) -> Result<(), HyperspeedError> {
+        if let Some(ref path) = self.recording {
+            self.recorder = Some(Recorder::create(path)?);
+        }
+        if self.replay.is_some() {
+            self.prev_time = Instant::now();
+            self.master_controller.start(&mut self.world, 0.0);
+            return Ok(());
+        }
+        if self.recover {
+            self.recover()?;
+        }
+
+        fn default(_t: &mut dyn ClientStream) -> StreamData {
+            StreamData::do_connect_str("default_key")
+        }
+
+
+        let (sender, reciever) = channel();
+        let (event_sender, event_receiver) = channel();
+
+        let handler = self.server_stream_handler
             .unwrap_or(default);
 
-        let mut server = Server::new(self.server_conf.clone(), sender, handler);
+        let server = Server::new(self.server_conf.clone(), sender, event_sender, handler)?;
 
         self.connection_channel = reciever;
+        self.event_channel = event_receiver;
 
         self.input_buffer = Some(server.get_input_buffer()); // Get a reference to the input buffer even after it gets moved to another thread
 
-        spawn( move || server.main_loop());
+        self.server = Some(server.start());
 
         self.prev_time = Instant::now();
 
         // Call MC init
         self.master_controller.start(&mut self.world, 0.0);
+        Ok(())
     }
 
-    fn get_new_connection(&mut self) -> Option<(Connection, Sender<ClientView>)> {
-        match self.connection_channel.try_recv() {
-            Ok(C) => Some(C),
-            Err(E) => match E {
-                Empty => None,
-                Disconnected => panic!("Engine fault: Server channel was disconnected")
+    /// Where the server is listening for TCP clients. When a port of 0 was asked for, this has
+    /// the port that was picked. Empty until the server has started.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        self.server.as_ref().map(|server| &server.addresses[..]).unwrap_or(&[])
+    }
+
+    /// Where the server is listening for WebSocket clients.
+    pub fn websocket_addrs(&self) -> &[SocketAddr] {
+        self.server.as_ref().map(|server| &server.websocket_addresses[..]).unwrap_or(&[])
+    }
+
+    /// Stops the engine. The master controllers' `shutdown` hooks run first, then no more clients
+    /// are accepted, and every client is sent what is left for it and a goodbye with `reason`.
+    /// Waits up to `timeout` for that to go out and for the server's threads to finish. The
+    /// engine shouldn't be ticked afterwards.
+    pub fn shutdown(&mut self, reason: &str, timeout: Duration) -> ShutdownReport {
+        let deadline = Instant::now() + timeout;
+        self.master_controller.shutdown(&mut self.world);
+        let mut outgoing = self.world.take_outgoing();
+        outgoing.extend(self.rooms.shutdown());
+        // Dropping the autosaver waits for the snapshot it is writing, if any.
+        self.autosaver = None;
+        self.recorder = None;
+
+        let server = self.server.take();
+        if let Some(ref server) = server {
+            server.stop_accepting();
+        }
+        // Clients that were let in just before get a goodbye too. Any later ones are turned away
+        // once the channel is dropped.
+        let connections = ::std::mem::replace(&mut self.connection_channel, channel().1);
+        for (conn, sender) in connections.try_iter() {
+            self.view_channels.insert(conn.key.clone(), (sender, conn.link.clone()));
+        }
+        drop(connections);
+
+        for (key, outbound) in outgoing {
+            if let Some((channel, _)) = self.view_channels.get(&key) {
+                let _ = channel.send(outbound);
+            }
+        }
+        let goodbye = ClientMessage::from(&Goodbye::new(reason)).map(Outbound::Message);
+        let mut clients = 0;
+        for (_, (channel, _)) in self.view_channels.drain() {
+            if let Ok(ref goodbye) = goodbye {
+                if channel.send(goodbye.clone()).is_ok() {
+                    clients += 1;
+                }
             }
+            // The client's thread sends what is queued, then closes the connection.
+            channel.finish();
+        }
+
+        ShutdownReport {
+            clients,
+            unfinished: server.map(|server| server.wait(deadline)).unwrap_or(0)
+        }
+    }
+
+    fn get_new_connection(&mut self) -> Result<Option<(Connection, QueueSender<Outbound>)>, HyperspeedError> {
+        match self.connection_channel.try_recv() {
+            Ok(connection) => Ok(Some(connection)),
+            Err(TryRecvError::Empty) => Ok(None),
+            // There is no server before `start_server` or after `shutdown`, so no connections
+            // are expected then.
+            Err(TryRecvError::Disconnected) if self.server.is_none() => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(HyperspeedError::ServerStopped)
         }
     }
 
     fn get_inputs(&mut self) -> HashMap<String, VecDeque<Input>> {
-        let mut lock = self.input_buffer.as_mut().unwrap().lock();
-        match lock {
-            Ok(ref mut lock) => {
-                let mut input_map = HashMap::new();
-                ::std::mem::swap(&mut input_map, lock);
-                input_map
+        let mut lock = match self.input_buffer {
+            // A client thread that panicked while holding the lock leaves the inputs intact,
+            // so the engine carries on with them.
+            Some(ref mut buffer) => buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner()),
+            None => return HashMap::new()
+        };
+        let mut input_map = HashMap::new();
+        ::std::mem::swap(&mut input_map, &mut **lock);
+        input_map
+    }
+    
+    /// Runs one tick. In replay mode, the tick's time, connections, inputs and disconnections
+    /// come from the recording instead, and nothing happens once it has run out. Problems with
+    /// single connections are dealt with here; an error means the engine itself can't go on.
+    pub fn tick(&mut self) -> Result<(), HyperspeedError> {
+        let replaying = self.replay.is_some();
+        let mut record = match self.replay {
+            Some(ref mut replay) => match replay.next_record() {
+                Some(Ok(record)) => record,
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(())
+            },
+            None => {
+                let tmp = self.prev_time;
+                self.prev_time = Instant::now();
+                let time = self.prev_time - tmp;
+                TickRecord::new(self.tick_count + 1, time.as_secs_f64())
             }
-            _ => {
-                panic!("The input buffer mutex was poisoned!");
+        };
+        self.tick_count = record.tick;
+        let delta_time = record.delta_time;
+
+        if replaying {
+            for moved in &record.room_moves {
+                if let Err(e) = self.move_connection(&moved.key, moved.room.as_deref()) {
+                    println!("Engine could not replay a room move: {}", e);
+                }
             }
+        } else {
+            record.room_moves = std::mem::take(&mut self.room_moves);
         }
 
-    }
-    
-    pub fn tick(&mut self) {
-        let tmp = self.prev_time;
-        self.prev_time = Instant::now();
-        let time = self.prev_time - tmp;
-        let instruction = self.master_controller.tick(&mut self.world, time.as_float_secs());
+        let inputs = if replaying {
+            record.inputs.clone()
+        } else {
+            let inputs = self.get_inputs();
+            if self.recorder.is_some() {
+                record.inputs = inputs.clone();
+            }
+            inputs
+        };
+        // Inputs wait in the world they belong to until it runs its dispatcher. Master controllers
+        // can look at them first.
+        let inputs = self.rooms.route_inputs(inputs);
+        self.world.queue_inputs(inputs);
+        self.deliver_connection_events();
 
-        let mut new_connection = self.get_new_connection();
+        let instruction = self.master_controller.tick(&mut self.world, delta_time);
 
-        while new_connection.is_some() {
-            println!("Processing new connection!");
-            match new_connection.unwrap() {
-                (conn, sender) => {
-                    self.view_channels.insert(conn.key.clone(), sender);
-                    self.world.connections.push(conn);
-                }
+        if replaying {
+            for conn in &record.connections {
+                self.world.connections.push(conn.clone());
+            }
+        } else {
+            while let Some((conn, sender)) = self.get_new_connection()? {
+                println!("Processing new connection!");
+                record.connections.push(conn.clone());
+                self.view_channels.insert(conn.key.clone(), (sender, conn.link.clone()));
+                self.world.connections.push(conn);
             }
-            new_connection = self.get_new_connection();
         }
 
-        let mut conn_ref = self.world.ecs_world.write_resource::<ConnectionCollection>();
-        ::std::mem::swap(&mut *conn_ref, &mut self.world.connections);
-        drop(conn_ref);
+        self.world.publish_connections();
+        self.world.update_connection_stats();
+        self.world.ecs_world.write_resource::<MatchmakingQueue>().tick = self.tick_count;
 
-        match instruction {
-            EngineInstruction::Run {
-                run_dispatcher
-            } => {
-                if run_dispatcher {
-                    let inputs = self.get_inputs();
-                    self.world.ecs_world.add_resource(inputs);
-                    self.world.system_executor.run(&mut self.world.ecs_world);
-                    self.world.ecs_world.maintain();
-                }
+        if let EngineInstruction::Run { run_dispatcher } = instruction {
+            if run_dispatcher {
+                self.world.run_systems(delta_time);
+                self.run_scripts(delta_time);
+                self.spawn_script_requests();
+                self.world.ecs_world.maintain();
             }
-            _ => {}
         }
+        self.run_matchmaking(&mut record);
 
-        // Get views
-        let mut view_ref = self.world.ecs_world.write_resource::<ViewMap>();
-        let mut views = ViewMap::new();
-        // Swap views
-        ::std::mem::swap(&mut *view_ref, &mut views);
-        drop(view_ref);
-        // Send views through view channels
-        for (key, view) in views {
+        // Get messages and views, from the main world and then from every room
+        let mut outgoing = self.world.take_outgoing();
+        outgoing.extend(self.rooms.tick(delta_time));
+        // Send them through view channels
+        for (key, outbound) in outgoing {
             match self.view_channels.get_mut(&key) {
-                Some(channel) => {
-                    match channel.send(view) {
-                            Ok(_) => {},
-                            Err(_) => {
-                            println!("Engine detects client stream thread has exited. Deleting connection.");
+                Some((channel, link)) => {
+                    match channel.send(outbound) {
+                            Ok(dropped) => {
+                                link.dropped(dropped);
+                                link.set_send_queue(channel.len());
+                            },
+                            Err(e) => {
+                            println!("Engine is deleting connection {}: {}", key, e);
                             self.view_channels.remove(&key); // TODO: Remove connection
                             self.remove_connection(&key);
+                            record.disconnections.push(key);
                         }
                     }
                 },
@@ -179,10 +657,93 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
                 }
             }
         }
+        self.export_metrics();
+        if replaying {
+            for key in &record.disconnections {
+                self.remove_connection(key);
+            }
+        }
+
+        if let Some(ref mut recorder) = self.recorder {
+            if let Err(e) = recorder.record(&record) {
+                println!("Engine stopped recording: {}", e);
+                self.recorder = None;
+            }
+        }
+
+        self.autosave();
+        Ok(())
+    }
+
+    /// Replaces last tick's connection events with the new ones, in every world. Each event goes
+    /// to the world its connection is in.
+    fn deliver_connection_events(&mut self) {
+        self.world.ecs_world.write_resource::<ConnectionEvents>().clear();
+        for room in self.rooms.iter_mut() {
+            room.world.ecs_world.write_resource::<ConnectionEvents>().clear();
+        }
+        while let Ok(event) = self.event_channel.try_recv() {
+            let key = match event {
+                ConnectionEvent::RateLimited { ref key, .. } => key.clone()
+            };
+            let world = match self.rooms.room_of(&key).cloned() {
+                Some(id) => &mut self.rooms.get_mut(&id).unwrap().world,
+                None => &mut self.world
+            };
+            world.ecs_world.write_resource::<ConnectionEvents>().push(event);
+        }
+    }
+
+    fn export_metrics(&mut self) {
+        let (world, rooms) = (&self.world, &self.rooms);
+        if let Some(ref mut metrics) = self.metrics {
+            metrics.tick(|| {
+                let mut stats = world.ecs_world.read_resource::<ConnectionStats>().clone();
+                for room in rooms.iter() {
+                    stats.merge(&room.world.ecs_world.read_resource::<ConnectionStats>());
+                }
+                stats
+            });
+        }
+    }
+
+    /// Whether the engine is replaying a recording that has run out.
+    pub fn replay_finished(&self) -> bool {
+        self.replay.as_ref().map(|r| r.is_finished()).unwrap_or(false)
+    }
+
+    /// Gives access to the interpreter that runs entity scripts, so scripts can be loaded into it.
+    pub fn scripts(&mut self) -> Option<&mut PythonInterpreter> {
+        self.scripts.as_mut()
+    }
+
+    fn run_scripts(&mut self, delta_time: f64) {
+        let scripts = match self.scripts {
+            Some(ref mut scripts) => scripts,
+            None => return
+        };
+        // In watch mode, edited scripts are picked up before they run. One that fails to reload
+        // keeps running its previous version.
+        let reload_errors: Vec<ScriptError> = scripts.reload_changed().into_iter()
+            .filter_map(|(_, result)| result.err())
+            .collect();
+        let errors = scripts.run_entity_scripts(&mut self.world.ecs_world, delta_time);
+        for error in reload_errors.iter().chain(errors.iter()) {
+            self.log_script_error(error);
+        }
+    }
+
+    /// Reports a script failure in the engine's log. The traceback is included so scripters can
+    /// see exactly which line broke.
+    pub fn log_script_error(&self, error: &ScriptError) {
+        println!("Engine caught a script error: {}", error);
     }
 
     fn remove_connection(&mut self, key: &String) {
         self.world.connections.remove(key);
+        self.world.take_inputs(key);
+        self.rooms.leave(key);
+        self.world.ecs_world.write_resource::<MatchmakingQueue>().leave(key);
     }
 }
 
@@ -192,11 +753,68 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> EngineBuilder<'a, 'b, E> {
         self
     }
     
-    pub fn on_port(mut self, port: u16) -> Self {
-        self.server_conf.port = port;
+    /// Listens on `port` on every IPv4 interface, instead of the default port 1212.
+    pub fn on_port(self, port: u16) -> Self {
+        self.on_address(SocketAddr::from(([0, 0, 0, 0], port)))
+    }
+
+    /// Listens on `address` only, which can be IPv4 or IPv6. Port 0 picks a free port, which
+    /// `Engine::local_addrs` reports once the server has started.
+    pub fn on_address(mut self, address: SocketAddr) -> Self {
+        self.server_conf.addresses = vec![address.into()];
+        self
+    }
+
+    /// Listens on the first free port in `ports`, such as `4000..=4010`, on every IPv4 interface.
+    pub fn on_port_range(self, ports: RangeInclusive<u16>) -> Self {
+        self.on_address_range(IpAddr::from([0, 0, 0, 0]), ports)
+    }
+
+    /// Listens on the first free port in `ports` on `ip` only.
+    pub fn on_address_range(mut self, ip: IpAddr, ports: RangeInclusive<u16>) -> Self {
+        self.server_conf.addresses = vec![BindAddress::new(ip, ports)];
+        self
+    }
+
+    /// Listens on `address` as well, for example to take IPv4 and IPv6 clients on separate
+    /// sockets.
+    pub fn with_listener(mut self, address: SocketAddr) -> Self {
+        self.server_conf.addresses.push(address.into());
         self
     }
     
+    /// Also accepts WebSocket clients, such as browsers, on `port`. They go through the same
+    /// stream handler as TCP clients.
+    pub fn with_websocket(self, port: u16) -> Self {
+        self.with_websocket_on(SocketAddr::from(([0, 0, 0, 0], port)))
+    }
+
+    /// Also accepts WebSocket clients on `address`. This can be called more than once.
+    pub fn with_websocket_on(mut self, address: SocketAddr) -> Self {
+        self.server_conf.websocket_addresses.push(address.into());
+        self
+    }
+
+    /// Only accepts TLS connections, on the TCP and WebSocket ports alike. Keep a clone of
+    /// `tls` to reload the certificate while the engine runs.
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.server_conf.tls = Some(tls);
+        self
+    }
+
+    /// How often clients are sent heartbeats, and how long they can stay quiet before they are
+    /// disconnected.
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatConfig) -> Self {
+        self.server_conf.heartbeat = heartbeat;
+        self
+    }
+
+    /// Caps how many spectators can watch at once. Players aren't counted.
+    pub fn max_spectators(mut self, max: usize) -> Self {
+        self.server_conf.max_spectators = Some(max);
+        self
+    }
+
     pub fn with_system<S>(mut self, system: S, name: &str, dep: &[&str]) -> Self
     where
         S: for<'c> specs::System<'c> + Send + 'a {
@@ -204,9 +822,9 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> EngineBuilder<'a, 'b, E> {
         self
     }
     
-    pub fn with_mc<M: 'static>(mut self, master_controller: M) -> Self
+    pub fn with_mc<M>(mut self, master_controller: M) -> Self
     where
-        M: MasterController<ObserverEvent=E> {
+        M: MasterController<ObserverEvent=E> + 'static {
         self.master_controller = Some(Box::new(master_controller));
         self
     }
@@ -214,24 +832,107 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> EngineBuilder<'a, 'b, E> {
         self.server_stream_handler = Some(handler);
         self
     }
+
+    /// Writes a snapshot in the background on a schedule, keeping the last few.
+    pub fn with_autosave(mut self, config: AutosaveConfig) -> Self {
+        self.autosave = Some(config);
+        self
+    }
+
+    /// Loads the newest readable autosave when the server starts. This needs `with_autosave`.
+    pub fn with_recovery(mut self, recover: bool) -> Self {
+        self.recover = recover;
+        self
+    }
+
+    /// Records every tick's time, connections, disconnections and inputs to a file, so the session
+    /// can be replayed with `with_replay`.
+    pub fn with_recording<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.recording = Some(path.into());
+        self
+    }
+
+    /// Drives the engine from a recording made with `with_recording`, without opening any sockets.
+    pub fn with_replay<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.replay = Some(path.into());
+        self
+    }
+
+    /// Groups queued players into matches with `matchmaker`, and starts a room for each match
+    /// with the systems and master controller `factory` builds for it.
+    pub fn with_matchmaking<F>(mut self, matchmaker: Matchmaker, factory: F) -> Self
+    where
+        F: FnMut(&Match) -> (SystemExecutorBuilder<'a, 'b>, Box<dyn MasterController<ObserverEvent=E>>) + 'a {
+        self.matchmaker = Some((matchmaker, Box::new(factory)));
+        self
+    }
+
+    /// Changes the limits on how much each client can send.
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        self.server_conf.rate_limit = Some(config);
+        self
+    }
+
+    /// Lets clients send as much as they like.
+    pub fn without_rate_limit(mut self) -> Self {
+        self.server_conf.rate_limit = None;
+        self
+    }
+
+    /// Caps how much can wait to be sent to each client, and picks what happens to clients that
+    /// fall behind.
+    pub fn with_send_queue(mut self, config: SendQueueConfig) -> Self {
+        self.server_conf.send_queue = config;
+        self
+    }
+
+    /// Sends every connection's network stats to `exporter` every `interval`.
+    pub fn with_metrics<X: MetricsExporter + 'static>(mut self, exporter: X, interval: Duration) -> Self {
+        self.metrics = Some(MetricsSchedule::new(Box::new(exporter), interval));
+        self
+    }
+
+    /// Runs the `Script` components of entities with this interpreter every tick. If its watch
+    /// mode is on, scripts whose files changed are reloaded first.
+    pub fn with_scripts(mut self, interpreter: PythonInterpreter) -> Self {
+        self.scripts = Some(interpreter);
+        self
+    }
     
-    pub fn build(mut self) -> Option<Engine<'a, 'b, E>> {
+    /// Fails without a master controller, or if the replay file can't be opened.
+    pub fn build(self) -> Result<Engine<'a, 'b, E>, HyperspeedError> {
+        let master_controller = self.master_controller.ok_or(HyperspeedError::MissingMasterController)?;
         let mut engine = Engine {
-            world: World {
-                system_executor: self.system_executor_builder.build(),
-                ecs_world: specs::prelude::World::new(),
-                connections: ConnectionCollection::new(),
-            },
-            master_controller: self.master_controller?,
+            world: World::new(self.system_executor_builder.build()),
+            master_controller,
             server_conf: self.server_conf,
+            server: None,
             input_buffer: None,
             prev_time: Instant::now(),
             // This is a fake channel
             connection_channel: channel().1,
+            event_channel: channel().1,
             view_channels: HashMap::new(),
-            server_stream_handler: self.server_stream_handler
+            server_stream_handler: self.server_stream_handler,
+            scripts: self.scripts,
+            blueprints: BlueprintRegistry::new(),
+            components: ComponentRegistry::new(),
+            tick_count: 0,
+            autosaver: self.autosave.map(Autosaver::new),
+            recover: self.recover,
+            recording: self.recording,
+            recorder: None,
+            replay: match self.replay {
+                Some(path) => Some(Replay::open(path).map_err(ReplayError::Io)?),
+                None => None
+            },
+            room_moves: vec![],
+            rooms: RoomManager::new(),
+            matchmaker: self.matchmaker,
+            local_components: vec![],
+            metrics: self.metrics
         };
         engine.init_resources();
-        Some(engine)
+        Ok(engine)
     }
 }
\ No newline at end of file
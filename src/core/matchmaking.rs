@@ -0,0 +1,335 @@
+use super::room::RoomID;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Every room the matchmaker starts has an ID beginning with this. `Engine::create_room` refuses
+/// IDs that do, so a match can never take the name of a room made by hand, or the other way round.
+pub const MATCH_ROOM_PREFIX: &str = "match:";
+
+/// A player (or one member of a party) waiting for a match.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueueEntry {
+    pub key: String,
+    pub rating: f64,
+    /// Entries without a region can be matched with anyone.
+    pub region: Option<String>,
+    /// Entries with the same party are always put in the same match.
+    pub party: Option<String>,
+    /// How many members the party has. None of them are matched until that many are queued.
+    /// Without it, only the members queued at the time are matched together, so members that
+    /// join on different ticks can end up in different matches.
+    #[serde(default)]
+    pub party_size: Option<usize>,
+    /// The engine tick the entry joined the queue on.
+    pub queued_at: u64
+}
+
+/// Every matchmaking queue and who is waiting in it. This is a resource in the engine's main
+/// world, so systems can add players to queues and lobby UIs can show how long the wait is.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MatchmakingQueue {
+    /// The current engine tick, kept up to date by the engine.
+    pub tick: u64,
+    queues: BTreeMap<String, Vec<QueueEntry>>,
+    /// The match size of each queue the matchmaker knows about, so oversized parties can be
+    /// turned away when they join.
+    #[serde(default)]
+    match_sizes: BTreeMap<String, usize>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueueError {
+    /// The party has more members than fit in one match, so it could never be matched.
+    PartyTooLarge { party: String, match_size: usize }
+}
+
+/// Decides whether two entries may play in the same match.
+pub trait MatchRule: Send {
+    fn compatible(&self, a: &QueueEntry, b: &QueueEntry, tick: u64) -> bool;
+}
+
+/// Only matches entries from the same region.
+pub struct SameRegion;
+
+/// Only matches entries whose ratings are close. The allowed gap grows by `widen_per_tick` for
+/// every tick the newer of the two entries has waited, so nobody waits forever.
+pub struct RatingWithin {
+    pub max_difference: f64,
+    pub widen_per_tick: f64
+}
+
+/// A queue players can join, the size of the matches it makes and the rules every pair of
+/// players in a match has to pass.
+pub struct QueueConfig {
+    pub name: String,
+    pub match_size: usize,
+    rules: Vec<Box<dyn MatchRule>>
+}
+
+/// A group of players the matchmaker has put together. The engine starts a room called `id` for
+/// them and moves their connections into it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Match {
+    pub id: RoomID,
+    pub queue: String,
+    pub players: Vec<QueueEntry>
+}
+
+/// Forms matches out of the entries in a `MatchmakingQueue`.
+pub struct Matchmaker {
+    queues: Vec<QueueConfig>,
+    match_count: u64
+}
+
+impl QueueEntry {
+    pub fn new(key: &str, rating: f64) -> Self {
+        QueueEntry {
+            key: key.to_string(),
+            rating,
+            region: None,
+            party: None,
+            party_size: None,
+            queued_at: 0
+        }
+    }
+
+    pub fn in_region(mut self, region: &str) -> Self {
+        self.region = Some(region.to_string());
+        self
+    }
+
+    pub fn in_party(mut self, party: &str) -> Self {
+        self.party = Some(party.to_string());
+        self
+    }
+
+    /// Like `in_party`, but holds the party back until all `size` of its members are queued.
+    pub fn in_party_of(mut self, party: &str, size: usize) -> Self {
+        self.party_size = Some(size);
+        self.in_party(party)
+    }
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueError::PartyTooLarge { party, match_size } =>
+                write!(f, "Party '{}' has more members than fit in a match of {}", party, match_size)
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+impl MatchmakingQueue {
+    pub fn new() -> Self {
+        MatchmakingQueue::default()
+    }
+
+    /// Adds an entry to a queue. A connection can only wait in one queue, so it leaves any other
+    /// queue it is in. Fails, leaving the entry where it was, if it would make its party bigger
+    /// than the queue's matches.
+    pub fn join(&mut self, queue: &str, mut entry: QueueEntry) -> Result<(), QueueError> {
+        if let (Some(party), Some(&match_size)) = (&entry.party, self.match_sizes.get(queue)) {
+            let others = self.entries(queue).iter()
+                .filter(|e| e.key != entry.key && e.party.as_ref() == Some(party))
+                .count();
+            if others + 1 > match_size || entry.party_size.unwrap_or(0) > match_size {
+                return Err(QueueError::PartyTooLarge { party: party.clone(), match_size });
+            }
+        }
+        self.leave(&entry.key);
+        entry.queued_at = self.tick;
+        self.queues.entry(queue.to_string()).or_default().push(entry);
+        Ok(())
+    }
+
+    /// Limits the size of parties in a queue. The engine sets this for every queue its
+    /// matchmaker has a config for.
+    pub fn set_match_size(&mut self, queue: &str, match_size: usize) {
+        self.match_sizes.insert(queue.to_string(), match_size);
+    }
+
+    /// Puts the players of a match that couldn't be started back at the front of their queue,
+    /// keeping the ticks they originally joined on.
+    pub(crate) fn put_back(&mut self, found: Match) {
+        let entries = self.queues.entry(found.queue).or_default();
+        for (index, player) in found.players.into_iter().enumerate() {
+            entries.insert(index, player);
+        }
+    }
+
+    /// Takes a connection out of whichever queue it is in.
+    pub fn leave(&mut self, key: &str) -> Option<QueueEntry> {
+        for entries in self.queues.values_mut() {
+            if let Some(index) = entries.iter().position(|e| e.key == key) {
+                return Some(entries.remove(index));
+            }
+        }
+        None
+    }
+
+    /// The queue a connection is waiting in and its place in that queue, starting at 0.
+    pub fn position(&self, key: &str) -> Option<(&str, usize)> {
+        self.queues.iter()
+            .filter_map(|(name, entries)| entries.iter().position(|e| e.key == key).map(|i| (name.as_str(), i)))
+            .next()
+    }
+
+    /// The entries waiting in a queue, oldest first.
+    pub fn entries(&self, queue: &str) -> &[QueueEntry] {
+        self.queues.get(queue).map(|e| e.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn len(&self, queue: &str) -> usize {
+        self.entries(queue).len()
+    }
+
+    /// How many ticks a connection has been waiting.
+    pub fn waited(&self, key: &str) -> Option<u64> {
+        let (queue, index) = self.position(key)?;
+        Some(self.tick.saturating_sub(self.entries(queue)[index].queued_at))
+    }
+}
+
+impl MatchRule for SameRegion {
+    fn compatible(&self, a: &QueueEntry, b: &QueueEntry, _tick: u64) -> bool {
+        match (&a.region, &b.region) {
+            (Some(a), Some(b)) => a == b,
+            _ => true
+        }
+    }
+}
+
+impl MatchRule for RatingWithin {
+    fn compatible(&self, a: &QueueEntry, b: &QueueEntry, tick: u64) -> bool {
+        let waited = tick.saturating_sub(a.queued_at.max(b.queued_at)) as f64;
+        (a.rating - b.rating).abs() <= self.max_difference + self.widen_per_tick * waited
+    }
+}
+
+impl QueueConfig {
+    /// A queue that matches anyone with anyone, `match_size` at a time.
+    pub fn new(name: &str, match_size: usize) -> Self {
+        QueueConfig {
+            name: name.to_string(),
+            match_size: match_size.max(1),
+            rules: vec![]
+        }
+    }
+
+    pub fn with_rule<R: MatchRule + 'static>(mut self, rule: R) -> Self {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    fn compatible(&self, a: &QueueEntry, b: &QueueEntry, tick: u64) -> bool {
+        self.rules.iter().all(|rule| rule.compatible(a, b, tick))
+    }
+
+    /// Puts entries together into matches, oldest entries first. Parties are placed as a whole,
+    /// once every member they declared is queued. Returns the matches and the entries that are
+    /// still waiting.
+    fn form_matches(&self, entries: Vec<QueueEntry>, tick: u64) -> (Vec<Vec<QueueEntry>>, Vec<QueueEntry>) {
+        // Group party members together, keeping the place of the party's oldest member.
+        let mut groups: Vec<Vec<QueueEntry>> = vec![];
+        for entry in entries {
+            let party = entry.party.clone();
+            match groups.iter_mut().find(|g| party.is_some() && g[0].party == party) {
+                Some(group) => group.push(entry),
+                None => groups.push(vec![entry])
+            }
+        }
+        let mut complete: Vec<bool> = groups.iter()
+            .map(|g| g.len() >= g.iter().filter_map(|e| e.party_size).max().unwrap_or(0))
+            .collect();
+
+        let mut matches = vec![];
+        'search: loop {
+            for first in 0..groups.len() {
+                if !complete[first] {
+                    continue;
+                }
+                let mut picked = vec![first];
+                let mut size = groups[first].len();
+                for next in first + 1..groups.len() {
+                    if size == self.match_size {
+                        break;
+                    }
+                    if !complete[next] || size + groups[next].len() > self.match_size {
+                        continue;
+                    }
+                    let fits = groups[next].iter().all(|candidate| {
+                        picked.iter().all(|&p| groups[p].iter().all(|member| self.compatible(candidate, member, tick)))
+                    });
+                    if fits {
+                        picked.push(next);
+                        size += groups[next].len();
+                    }
+                }
+                if size == self.match_size {
+                    // Remove from the back, so the earlier indices stay valid.
+                    let mut picked_groups = vec![];
+                    for &index in picked.iter().rev() {
+                        picked_groups.push(groups.remove(index));
+                        complete.remove(index);
+                    }
+                    picked_groups.reverse();
+                    matches.push(picked_groups.into_iter().flatten().collect());
+                    continue 'search;
+                }
+            }
+            break;
+        }
+        (matches, groups.into_iter().flatten().collect())
+    }
+}
+
+impl Matchmaker {
+    pub fn new() -> Self {
+        Matchmaker {
+            queues: vec![],
+            match_count: 0
+        }
+    }
+
+    pub fn with_queue(mut self, queue: QueueConfig) -> Self {
+        self.queues.push(queue);
+        self
+    }
+
+    /// The name and match size of every queue the matchmaker has a config for.
+    pub fn match_sizes(&self) -> impl Iterator<Item=(&str, usize)> {
+        self.queues.iter().map(|config| (config.name.as_str(), config.match_size))
+    }
+
+    /// Takes every match that can be made out of the queue. Entries in queues the matchmaker has
+    /// no config for are left waiting.
+    pub fn find_matches(&mut self, queue: &mut MatchmakingQueue) -> Vec<Match> {
+        let tick = queue.tick;
+        let mut found = vec![];
+        for config in &self.queues {
+            let entries = match queue.queues.remove(&config.name) {
+                Some(entries) => entries,
+                None => continue
+            };
+            let (matches, waiting) = config.form_matches(entries, tick);
+            queue.queues.insert(config.name.clone(), waiting);
+            for players in matches {
+                self.match_count += 1;
+                found.push(Match {
+                    id: format!("{}{}-{}", MATCH_ROOM_PREFIX, config.name, self.match_count),
+                    queue: config.name.clone(),
+                    players
+                });
+            }
+        }
+        found
+    }
+}
+
+impl Default for Matchmaker {
+    fn default() -> Self {
+        Matchmaker::new()
+    }
+}
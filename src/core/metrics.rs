@@ -0,0 +1,98 @@
+use super::world::{ConnectionStats, NetworkStats};
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Somewhere the engine's metrics are sent, passed to `EngineBuilder::with_metrics`.
+pub trait MetricsExporter: Send {
+    /// Called with the stats of every connection, in every room.
+    fn export(&mut self, connections: &ConnectionStats) -> io::Result<()>;
+}
+
+/// Writes metrics in the Prometheus text format to a file, for a node exporter's textfile
+/// collector to pick up. The file is replaced in one step, so it is never read half written.
+pub struct PrometheusFile {
+    path: PathBuf
+}
+
+/// Runs an exporter every `interval`.
+pub(crate) struct MetricsSchedule {
+    exporter: Box<dyn MetricsExporter>,
+    interval: Duration,
+    last: Instant
+}
+
+impl PrometheusFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        PrometheusFile {
+            path: path.into()
+        }
+    }
+}
+
+impl MetricsExporter for PrometheusFile {
+    fn export(&mut self, connections: &ConnectionStats) -> io::Result<()> {
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, render_prometheus(connections))?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+impl MetricsSchedule {
+    pub(crate) fn new(exporter: Box<dyn MetricsExporter>, interval: Duration) -> Self {
+        MetricsSchedule {
+            exporter,
+            interval,
+            last: Instant::now()
+        }
+    }
+
+    pub(crate) fn tick(&mut self, connections: impl FnOnce() -> ConnectionStats) {
+        if self.last.elapsed() < self.interval {
+            return;
+        }
+        self.last = Instant::now();
+        if let Err(e) = self.exporter.export(&connections()) {
+            println!("Engine could not export metrics: {}", e);
+        }
+    }
+}
+
+/// Renders connection stats in the Prometheus text format. Connection keys are login
+/// credentials, so they are never used as labels: each metric is aggregated over every
+/// connection instead, as its largest value plus either the mean (for round trip times) or the
+/// sum (for everything else).
+pub fn render_prometheus(connections: &ConnectionStats) -> String {
+    let metrics: [(&str, &str, bool, fn(&NetworkStats) -> Option<f64>); 8] = [
+        ("rtt_seconds", "Smoothed round trip time.", true, |s| s.rtt.map(|d| d.as_secs_f64())),
+        ("jitter_seconds", "How much the round trip time varies.", true, |s| s.jitter.map(|d| d.as_secs_f64())),
+        ("bytes_in_per_second", "Bytes received from clients.", false, |s| Some(s.bytes_in_per_sec)),
+        ("bytes_out_per_second", "Bytes sent to clients.", false, |s| Some(s.bytes_out_per_sec)),
+        ("messages_in_per_second", "Messages received from clients.", false, |s| Some(s.messages_in_per_sec)),
+        ("messages_out_per_second", "Messages sent to clients.", false, |s| Some(s.messages_out_per_sec)),
+        ("send_queue", "Messages waiting to be sent.", false, |s| Some(s.send_queue as f64)),
+        ("dropped", "Messages that were never sent, by connections that are still open.", false, |s| Some(s.dropped as f64))
+    ];
+    let mut out = String::new();
+    writeln!(out, "# HELP hyperspeed_connections Open connections.").unwrap();
+    writeln!(out, "# TYPE hyperspeed_connections gauge").unwrap();
+    writeln!(out, "hyperspeed_connections {}", connections.len()).unwrap();
+    for (name, help, averaged, value) in metrics.iter() {
+        // Round trip times are only counted for connections that have measured one.
+        let values: Vec<f64> = connections.iter().filter_map(|(_, stats)| value(stats)).collect();
+        let total: f64 = values.iter().sum();
+        writeln!(out, "# HELP hyperspeed_connection_{} {}", name, help).unwrap();
+        writeln!(out, "# TYPE hyperspeed_connection_{} gauge", name).unwrap();
+        if *averaged {
+            let mean = if values.is_empty() { 0.0 } else { total / values.len() as f64 };
+            writeln!(out, "hyperspeed_connection_{}{{stat=\"mean\"}} {}", name, mean).unwrap();
+        } else {
+            writeln!(out, "hyperspeed_connection_{}{{stat=\"sum\"}} {}", name, total).unwrap();
+        }
+        let max = values.iter().cloned().fold(0.0, f64::max);
+        writeln!(out, "hyperspeed_connection_{}{{stat=\"max\"}} {}", name, max).unwrap();
+    }
+    out
+}
@@ -1,8 +1,23 @@
+mod autosave;
 mod engine;
+mod matchmaking;
+mod metrics;
+mod replay;
+mod room;
 mod server;
+mod shutdown;
+mod snapshot;
 mod world;
 
+pub use autosave::{AutosaveConfig, AutosaveInterval, latest_valid_snapshot};
 pub use engine::*;
+pub use matchmaking::{QueueEntry, MatchmakingQueue, MatchRule, SameRegion, RatingWithin, QueueConfig, QueueError, Match, Matchmaker,
+                      MATCH_ROOM_PREFIX};
+pub use metrics::{MetricsExporter, PrometheusFile, render_prometheus};
+pub use replay::{Recorder, Replay, ReplayError, RoomMove, TickRecord};
+pub use room::{Room, RoomID, RoomManager, RoomError};
+pub use shutdown::{Goodbye, ShutdownReport};
+pub use snapshot::{Snapshot, SnapshotError, SNAPSHOT_FORMAT_VERSION};
 
 use server::*;
 
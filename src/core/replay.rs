@@ -0,0 +1,130 @@
+use crate::core::world::Connection;
+use crate::core::matchmaking::Match;
+use crate::utils::InputMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::Path;
+
+/// Everything from outside the engine that influenced one tick. Replaying these in order
+/// reproduces a session, as long as the systems themselves are deterministic.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TickRecord {
+    pub tick: u64,
+    pub delta_time: f64,
+    /// The connections that joined at the start of the tick.
+    pub connections: Vec<Connection>,
+    /// Keys of the connections that were dropped at the end of the tick.
+    pub disconnections: Vec<String>,
+    pub inputs: InputMap,
+    /// Connections moved with `Engine::move_to_room` since the previous tick.
+    #[serde(default)]
+    pub room_moves: Vec<RoomMove>,
+    /// The matches matchmaking formed during the tick.
+    #[serde(default)]
+    pub matches: Vec<Match>
+}
+
+/// A connection moved between worlds. `room` is `None` when it went back to the main world.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomMove {
+    pub key: String,
+    pub room: Option<String>
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    Parse { line: usize, message: String }
+}
+
+/// Writes a `TickRecord` per tick to a file, one JSON object per line.
+pub struct Recorder {
+    writer: BufWriter<File>
+}
+
+/// Reads back a file written by a `Recorder`.
+pub struct Replay {
+    lines: Lines<BufReader<File>>,
+    line: usize,
+    finished: bool
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "Could not read replay: {}", e),
+            ReplayError::Parse { line, message } => write!(f, "Replay line {} is malformed: {}", line, message)
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl TickRecord {
+    pub fn new(tick: u64, delta_time: f64) -> Self {
+        TickRecord {
+            tick,
+            delta_time,
+            ..TickRecord::default()
+        }
+    }
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        Ok(Recorder {
+            writer: BufWriter::new(File::create(path)?)
+        })
+    }
+
+    pub fn record(&mut self, record: &TickRecord) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")?;
+        // Flushing every tick means a crash loses at most the tick that crashed, which is
+        // usually the one worth reproducing.
+        self.writer.flush()
+    }
+}
+
+impl Replay {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Replay> {
+        Ok(Replay {
+            lines: BufReader::new(File::open(path)?).lines(),
+            line: 0,
+            finished: false
+        })
+    }
+
+    /// Reads the next tick. Returns `None` once the recording has run out.
+    pub fn next_record(&mut self) -> Option<Result<TickRecord, ReplayError>> {
+        if self.finished {
+            return None;
+        }
+        loop {
+            self.line += 1;
+            let line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => {
+                    self.finished = true;
+                    return Some(Err(ReplayError::Io(e)));
+                },
+                None => {
+                    self.finished = true;
+                    return None;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(serde_json::from_str(&line).map_err(|e| {
+                self.finished = true;
+                ReplayError::Parse { line: self.line, message: e.to_string() }
+            }));
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
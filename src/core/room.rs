@@ -0,0 +1,232 @@
+use super::engine::add_default_resources;
+use super::world::*;
+use crate::utils::InputMap;
+use std::collections::{btree_map, BTreeMap, HashMap};
+use std::fmt;
+
+pub type RoomID = String;
+
+/// An isolated world inside the engine, such as one match. A room has its own entities,
+/// systems and master controller, and only sees the connections that have been moved into it.
+pub struct Room<'a, 'b, E> {
+    id: RoomID,
+    pub world: World<'a, 'b>,
+    master_controller: Box<dyn MasterController<ObserverEvent=E>>,
+    tick_count: u64
+}
+
+/// Every room the engine is running, and which room each connection is in. Connections that
+/// aren't in a room stay in the engine's main world.
+pub struct RoomManager<'a, 'b, E> {
+    // Rooms tick in ID order, so replays run them the same way every time.
+    rooms: BTreeMap<RoomID, Room<'a, 'b, E>>,
+    routes: HashMap<String, RoomID>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoomError {
+    AlreadyExists(RoomID),
+    UnknownRoom(RoomID),
+    UnknownConnection(String),
+    /// Room IDs starting with `MATCH_ROOM_PREFIX` are kept for matchmaking.
+    ReservedID(RoomID)
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoomError::AlreadyExists(id) => write!(f, "A room called '{}' already exists", id),
+            RoomError::UnknownRoom(id) => write!(f, "No room is called '{}'", id),
+            RoomError::UnknownConnection(key) => write!(f, "No connection has the key '{}'", key),
+            RoomError::ReservedID(id) => write!(f, "Room ID '{}' is kept for matchmaking", id)
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+impl<'a, 'b, E: Sync + Send + Clone + 'static> Room<'a, 'b, E> {
+    /// Creates a room with the engine's resources and every component in `components`. Components
+    /// that were registered with `Engine::register_local` are passed in `local_components`.
+    pub(crate) fn new(id: &str,
+                      systems: SystemExecutorBuilder<'a, 'b>,
+                      master_controller: Box<dyn MasterController<ObserverEvent=E>>,
+                      components: &ComponentRegistry,
+                      local_components: &[fn(&mut specs::World)]) -> Self {
+        let mut world = World::new(systems.build());
+        add_default_resources::<E>(&mut world.ecs_world);
+        for entry in components.iter() {
+            entry.register_with(&mut world.ecs_world);
+        }
+        for register in local_components {
+            register(&mut world.ecs_world);
+        }
+        let mut room = Room {
+            id: id.to_string(),
+            world,
+            master_controller,
+            tick_count: 0
+        };
+        room.master_controller.start(&mut room.world, 0.0);
+        room
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The number of ticks the room has run since it was created.
+    pub fn tick_count(&self) -> u64 {
+        self.tick_count
+    }
+
+    /// The keys of the connections in the room.
+    pub fn members(&self) -> impl Iterator<Item=&String> {
+        self.world.connections.connections.iter().map(|c| &c.key)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.world.connections.contains(key)
+    }
+
+    /// Runs one tick and returns the messages and views the room produced.
+    pub(crate) fn tick(&mut self, delta_time: f64) -> Vec<(String, Outbound)> {
+        self.tick_count += 1;
+        let instruction = self.master_controller.tick(&mut self.world, delta_time);
+        self.world.publish_connections();
+        self.world.update_connection_stats();
+
+        if let EngineInstruction::Run { run_dispatcher: true } = instruction {
+            self.world.run_systems(delta_time);
+            self.world.ecs_world.maintain();
+        }
+
+        self.world.take_outgoing()
+    }
+
+    /// Runs the master controller's `shutdown` hook and returns the last messages it sent.
+    pub(crate) fn shutdown(&mut self) -> Vec<(String, Outbound)> {
+        self.master_controller.shutdown(&mut self.world);
+        self.world.take_outgoing()
+    }
+}
+
+impl<'a, 'b, E: Sync + Send + Clone + 'static> RoomManager<'a, 'b, E> {
+    pub fn new() -> Self {
+        RoomManager {
+            rooms: BTreeMap::new(),
+            routes: HashMap::new()
+        }
+    }
+
+    pub(crate) fn insert(&mut self, room: Room<'a, 'b, E>) -> Result<(), RoomError> {
+        if self.rooms.contains_key(&room.id) {
+            return Err(RoomError::AlreadyExists(room.id));
+        }
+        self.rooms.insert(room.id.clone(), room);
+        Ok(())
+    }
+
+    /// Takes a room out of the manager. Its connections are no longer routed to it.
+    pub(crate) fn remove(&mut self, id: &str) -> Result<Room<'a, 'b, E>, RoomError> {
+        let room = self.rooms.remove(id).ok_or_else(|| RoomError::UnknownRoom(id.to_string()))?;
+        self.routes.retain(|_, room_id| room_id != id);
+        Ok(room)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Room<'a, 'b, E>> {
+        self.rooms.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Room<'a, 'b, E>> {
+        self.rooms.get_mut(id)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.rooms.contains_key(id)
+    }
+
+    pub fn ids(&self) -> btree_map::Keys<'_, RoomID, Room<'a, 'b, E>> {
+        self.rooms.keys()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Room<'a, 'b, E>> {
+        self.rooms.values()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=&mut Room<'a, 'b, E>> {
+        self.rooms.values_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+
+    /// The room a connection is in, or `None` if it is in the main world.
+    pub fn room_of(&self, key: &str) -> Option<&RoomID> {
+        self.routes.get(key)
+    }
+
+    /// Puts a connection into a room. It shows up as a new connection there.
+    pub(crate) fn join(&mut self, id: &str, connection: Connection) -> Result<(), RoomError> {
+        let room = self.rooms.get_mut(id).ok_or_else(|| RoomError::UnknownRoom(id.to_string()))?;
+        self.routes.insert(connection.key.clone(), id.to_string());
+        room.world.connections.push(connection);
+        Ok(())
+    }
+
+    /// Takes a connection out of whichever room it is in. Inputs it sent that the room hasn't
+    /// used yet are dropped.
+    pub(crate) fn leave(&mut self, key: &str) -> Option<Connection> {
+        let id = self.routes.remove(key)?;
+        let room = self.rooms.get_mut(&id)?;
+        room.world.take_inputs(key);
+        room.world.connections.take(key)
+    }
+
+    /// Hands each connection's inputs to the room it is in. Returns the inputs of connections
+    /// that aren't in a room.
+    pub(crate) fn route_inputs(&mut self, inputs: InputMap) -> InputMap {
+        let mut unrouted = InputMap::new();
+        let rooms = &mut self.rooms;
+        for (key, queue) in inputs {
+            match self.routes.get(&key).and_then(|id| rooms.get_mut(id)) {
+                Some(room) => {
+                    let mut routed = InputMap::new();
+                    routed.insert(key, queue);
+                    room.world.queue_inputs(routed);
+                },
+                None => { unrouted.insert(key, queue); }
+            }
+        }
+        unrouted
+    }
+
+    /// Ticks every room and collects the messages and views they produced.
+    pub(crate) fn tick(&mut self, delta_time: f64) -> Vec<(String, Outbound)> {
+        let mut outgoing = vec![];
+        for room in self.rooms.values_mut() {
+            outgoing.extend(room.tick(delta_time));
+        }
+        outgoing
+    }
+
+    /// Shuts every room down and collects their last messages.
+    pub(crate) fn shutdown(&mut self) -> Vec<(String, Outbound)> {
+        let mut outgoing = vec![];
+        for room in self.rooms.values_mut() {
+            outgoing.extend(room.shutdown());
+        }
+        outgoing
+    }
+}
+
+impl<'a, 'b, E: Sync + Send + Clone + 'static> Default for RoomManager<'a, 'b, E> {
+    fn default() -> Self {
+        RoomManager::new()
+    }
+}
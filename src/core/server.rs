@@ -1,15 +1,22 @@
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::thread::{JoinHandle, spawn, sleep};
-use super::world::{Input, Connection, ClientView};
+use std::thread::{spawn, sleep};
+use super::world::{Input, Connection, ConnectionEvent, ConnectionRole, LinkStats, Outbound, ClientMessage};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::collections::{HashMap, VecDeque};
 use bytes::{BytesMut, BufMut};
 use std::ops::{Deref, DerefMut};
-use std::io::{Read, Write};
-use std::sync::mpsc::{Sender, channel, Receiver};
-use std::time::Duration;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 use crate::utils::server::*;
+use crate::utils::bind::{BindAddress, BindError};
+use crate::utils::websocket::WebSocketStream;
+use crate::utils::tls::TlsConfig;
+use crate::utils::heartbeat::{Heartbeat, HeartbeatConfig, HeartbeatReply, HANDSHAKE_TIMEOUT};
+use crate::utils::ratelimit::{RateLimitAction, RateLimitConfig, RateLimiter};
+use crate::utils::queue::{send_queue, QueueReceiver, QueueSender, SendQueueConfig};
 use crate::utils::StreamHandler;
 
 pub(crate) type InputBufferMutex = Arc<Mutex<PlayerInputBuffer>>;
@@ -21,7 +28,9 @@ pub(crate) struct PlayerInputBuffer {
 #[derive(Clone)]
 pub struct StreamData {
     login_key: String,
-    should_connect: bool
+    should_connect: bool,
+    role: ConnectionRole,
+    follow: Option<String>
 }
 
 impl StreamData {
@@ -33,40 +42,105 @@ impl StreamData {
         self.login_key.clone()
     }
 
+    pub fn role(&self) -> ConnectionRole {
+        self.role
+    }
+
     pub fn do_connect(login_key: String) -> Self {
         StreamData {
             login_key,
-            should_connect: true
+            should_connect: true,
+            role: ConnectionRole::Player,
+            follow: None
         }
     }
 
     pub fn do_connect_str(login_key: &str) -> Self {
+        StreamData::do_connect(login_key.to_string())
+    }
+
+    /// Connects as a spectator. If `follow` is given, the spectator sees what that player sees;
+    /// otherwise it gets a free camera.
+    pub fn do_spectate(login_key: String, follow: Option<String>) -> Self {
         StreamData {
-            login_key: login_key.to_string(),
-            should_connect: true
+            follow,
+            ..StreamData::do_connect(login_key).with_role(ConnectionRole::Spectator)
         }
     }
 
+    pub fn with_role(mut self, role: ConnectionRole) -> Self {
+        self.role = role;
+        self
+    }
+
     pub fn dont_connect() -> Self {
         StreamData {
             login_key: "".to_string(),
-            should_connect: false
+            should_connect: false,
+            role: ConnectionRole::Player,
+            follow: None
         }
     }
 }
 
 
 pub(crate) struct Server {
+    tcp_listeners: Vec<TcpListener>,
+    websocket_listeners: Vec<TcpListener>,
+    tls: Option<TlsConfig>,
+    gate: Gate
+}
+
+/// Stops a running server, and waits for its threads.
+pub(crate) struct ServerControl {
+    /// Where the listeners are bound, with the real port for any that asked for port 0.
+    pub(crate) addresses: Vec<SocketAddr>,
+    pub(crate) websocket_addresses: Vec<SocketAddr>,
+    stopping: Arc<AtomicBool>,
+    threads: Threads
+}
+
+/// Counts the threads a server has started, so shutdown can wait for them.
+#[derive(Clone, Default)]
+struct Threads {
+    running: Arc<AtomicUsize>
+}
+
+/// Counts a thread as finished when it is dropped, even if the thread panics.
+struct ThreadGuard(Arc<AtomicUsize>);
+
+/// Lets clients in. Every listener has a copy, so they all share one stream handler, input
+/// buffer and spectator count.
+#[derive(Clone)]
+struct Gate {
     stream_handle: StreamHandler,
-    tcp_listener: TcpListener,
     input_stream: InputBufferMutex,
-    connection_channel: Sender<(Connection, Sender<ClientView>)>,
+    connection_channel: Sender<(Connection, QueueSender<Outbound>)>,
+    max_spectators: Option<usize>,
+    spectators: Arc<AtomicUsize>,
+    heartbeat: HeartbeatConfig,
+    rate_limit: Option<RateLimitConfig>,
+    send_queue: SendQueueConfig,
+    events: Sender<ConnectionEvent>,
+    stopping: Arc<AtomicBool>,
+    threads: Threads
 }
 
 #[derive(Clone)]
 pub(crate) struct ServerConfig {
-    pub port: u16,
-    pub server_name: String
+    /// Where TCP clients connect. Port 0 picks a free port, and a range takes its first free one.
+    pub addresses: Vec<BindAddress>,
+    /// Browser clients connect here, alongside the TCP addresses.
+    pub websocket_addresses: Vec<BindAddress>,
+    /// When set, every listener only takes TLS connections.
+    pub tls: Option<TlsConfig>,
+    pub server_name: String,
+    pub max_spectators: Option<usize>,
+    pub heartbeat: HeartbeatConfig,
+    /// Limits on what each client can send. `None` turns rate limiting off.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// How much can wait to be sent to each client, and what happens past that.
+    pub send_queue: SendQueueConfig
 }
 
 impl PlayerInputBuffer {
@@ -77,7 +151,7 @@ impl PlayerInputBuffer {
     }
 
     pub fn push_input(&mut self, player: String, input: Input) {
-        if let Some(mut input_v) = self.inner.get_mut(&player) {
+        if let Some(input_v) = self.inner.get_mut(&player) {
             input_v.push_back(input);
         } else {
             self.inner.insert(player, cascade::cascade! {
@@ -88,7 +162,7 @@ impl PlayerInputBuffer {
     }
 
     pub fn pop_input(&mut self, player: String) -> Option<Input> {
-        if let Some(mut input_v) = self.inner.get_mut(&player) {
+        if let Some(input_v) = self.inner.get_mut(&player) {
             input_v.pop_front()
         } else {
             None
@@ -99,43 +173,223 @@ impl PlayerInputBuffer {
 impl ServerConfig {
     pub fn new() -> Self {
         ServerConfig {
-            port: 1212, // the default port for Hyperspeed
-            server_name: "default_name".to_string()
+            addresses: vec![SocketAddr::from(([0, 0, 0, 0], 1212)).into()], // the default port for Hyperspeed
+            websocket_addresses: vec![],
+            tls: None,
+            server_name: "default_name".to_string(),
+            max_spectators: None,
+            heartbeat: HeartbeatConfig::default(),
+            rate_limit: Some(RateLimitConfig::default()),
+            send_queue: SendQueueConfig::default()
         }
     }
 }
 
 impl Server {
-    pub(crate) fn new(s: ServerConfig, c_sender: Sender<(Connection, Sender<ClientView>)>, stream_handler: StreamHandler) -> Server {
-        Server {
-            tcp_listener: TcpListener::bind(format!("0.0.0.0:{}", s.port)).unwrap(),
-            input_stream: Arc::new(Mutex::new(PlayerInputBuffer::new())),
-            connection_channel: c_sender,
-            stream_handle: stream_handler
-        }
-    }
-    pub(crate) fn main_loop(&mut self) {
-        for stream in self.tcp_listener.incoming() {
-            let mut stream = stream.unwrap();
-            let data = (self.stream_handle)(&mut stream);
-            match data {
-                StreamData {
-                    login_key,
-                    should_connect
-                } => {
-                    if should_connect {
-                        let mutex_clone = self.input_stream.clone();
-                        let (send, recv) = channel();
-                        let conn = Connection { key: login_key.clone() };
-                        self.connection_channel.send((conn, send));
-                        spawn(move || stream_communicate(stream, recv, mutex_clone, login_key));
+    /// Binds every listener. Nothing is accepted until `start`.
+    pub(crate) fn new(s: ServerConfig, c_sender: Sender<(Connection, QueueSender<Outbound>)>, events: Sender<ConnectionEvent>, stream_handler: StreamHandler) -> Result<Server, BindError> {
+        let bind = |address: &BindAddress| address.bind_first(|address| TcpListener::bind(address));
+        Ok(Server {
+            tcp_listeners: s.addresses.iter().map(bind).collect::<Result<_, _>>()?,
+            websocket_listeners: s.websocket_addresses.iter().map(bind).collect::<Result<_, _>>()?,
+            tls: s.tls,
+            gate: Gate {
+                input_stream: Arc::new(Mutex::new(PlayerInputBuffer::new())),
+                connection_channel: c_sender,
+                stream_handle: stream_handler,
+                max_spectators: s.max_spectators,
+                spectators: Arc::new(AtomicUsize::new(0)),
+                heartbeat: s.heartbeat,
+                rate_limit: s.rate_limit,
+                send_queue: s.send_queue,
+                events,
+                stopping: Arc::new(AtomicBool::new(false)),
+                threads: Threads::default()
+            }
+        })
+    }
+    /// Starts accepting clients, each listener on its own thread.
+    pub(crate) fn start(self) -> ServerControl {
+        let Server { tcp_listeners, websocket_listeners, tls, gate } = self;
+        let bound = |listeners: &[TcpListener]| -> Vec<SocketAddr> { listeners.iter().filter_map(|l| l.local_addr().ok()).collect() };
+        let control = ServerControl {
+            addresses: bound(&tcp_listeners),
+            websocket_addresses: bound(&websocket_listeners),
+            stopping: gate.stopping.clone(),
+            threads: gate.threads.clone()
+        };
+        for listener in websocket_listeners {
+            let gate = gate.clone();
+            let tls = tls.clone();
+            let stopping = gate.stopping.clone();
+            control.threads.spawn(move || listen(listener, &stopping, |stream| {
+                // The upgrade is read on its own thread, so a slow client can't hold up others.
+                let gate = gate.clone();
+                let tls = tls.clone();
+                gate.threads.clone().spawn(move || {
+                    let upgraded = match tls {
+                        Some(tls) => tls.accept(stream).and_then(WebSocketStream::accept).map(|stream| gate.admit(stream)),
+                        None => WebSocketStream::accept(stream).map(|stream| gate.admit(stream))
+                    };
+                    if let Err(e) = upgraded {
+                        println!("WebSocket upgrade failed: {}", e);
                     }
+                });
+            }));
+        }
+        for listener in tcp_listeners {
+            let gate = gate.clone();
+            let tls = tls.clone();
+            let stopping = gate.stopping.clone();
+            control.threads.spawn(move || listen(listener, &stopping, |stream| {
+                // Each client is let in on its own thread, so a slow client or a stream handler
+                // that panics can't stop the listener.
+                let gate = gate.clone();
+                let tls = tls.clone();
+                gate.threads.clone().spawn(move || match tls {
+                    Some(tls) => match tls.accept(stream) {
+                        Ok(stream) => gate.admit(stream),
+                        Err(e) => println!("TLS handshake failed: {}", e)
+                    },
+                    None => gate.admit(stream)
+                });
+            }));
+        }
+        control
+    }
+
+    pub(crate) fn get_input_buffer(&self) -> Arc<Mutex<PlayerInputBuffer>> {
+        self.gate.input_stream.clone()
+    }
+}
+
+impl ServerControl {
+    /// Closes the listeners. Clients that are already connected stay connected.
+    pub(crate) fn stop_accepting(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits until every thread the server started has finished, or `deadline` has passed.
+    /// Returns how many are still running.
+    pub(crate) fn wait(&self, deadline: Instant) -> usize {
+        loop {
+            let running = self.threads.running();
+            if running == 0 || Instant::now() >= deadline {
+                return running;
+            }
+            sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Threads {
+    fn spawn<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        let guard = ThreadGuard(self.running.clone());
+        spawn(move || {
+            let _guard = guard;
+            f()
+        });
+    }
+
+    fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts connections until `stopping` is set. The listener is polled rather than blocked on,
+/// so it notices in time.
+fn listen<F: FnMut(TcpStream)>(listener: TcpListener, stopping: &AtomicBool, mut accepted: F) {
+    const ACCEPT_POLL: Duration = Duration::from_millis(10);
+    if let Err(e) = listener.set_nonblocking(true) {
+        println!("Server could not start listening: {}", e);
+        return;
+    }
+    while !stopping.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // Some platforms hand out sockets that inherit the listener's mode. The read
+                // timeout stops a client that stalls its TLS or WebSocket handshake, or its login,
+                // from holding a thread forever. Client threads read without blocking, so it
+                // doesn't matter to them.
+                if let Err(e) = stream.set_nonblocking(false).and_then(|_| stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))) {
+                    println!("Server dropped a connection: {}", e);
+                    continue;
                 }
+                accepted(stream)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => sleep(ACCEPT_POLL),
+            Err(e) => println!("Server could not accept a connection: {}", e)
+        }
+    }
+}
+
+impl Gate {
+    /// Runs the stream handler on a new client, and starts talking to it if it is let in.
+    fn admit<S: ClientStream + 'static>(&self, mut stream: S) {
+        let StreamData { login_key, should_connect, role, follow } = (self.stream_handle)(&mut stream);
+        if !should_connect {
+            return;
+        }
+        let spectators = if role == ConnectionRole::Spectator {
+            if !self.reserve_spectator_slot() {
+                println!("Spectator limit reached. Refusing connection.");
+                return;
+            }
+            Some(self.spectators.clone())
+        } else {
+            None
+        };
+        let mutex_clone = self.input_stream.clone();
+        let (send, recv) = send_queue(self.send_queue, Outbound::is_view);
+        let link = LinkStats::default();
+        let heartbeat = Heartbeat::new(self.heartbeat, link.clone(), Instant::now());
+        let conn_link = link.clone();
+        let conn = Connection { key: login_key.clone(), role, follow, link };
+        // The engine stops taking connections when it shuts down.
+        if self.connection_channel.send((conn, send)).is_err() {
+            if let Some(spectators) = spectators {
+                spectators.fetch_sub(1, Ordering::SeqCst);
             }
+            return;
         }
+        let stream = Metered { stream, link: conn_link };
+        let limiter = self.rate_limit.map(|config| RateLimiter::new(config, Instant::now()));
+        let events = self.events.clone();
+        self.threads.spawn(move || {
+            stream_communicate(stream, recv, mutex_clone, login_key, role, heartbeat, limiter, events);
+            // Free the spectator slot once the spectator has gone.
+            if let Some(spectators) = spectators {
+                spectators.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
     }
-    pub(crate) fn get_input_buffer(&self) -> Arc<Mutex<PlayerInputBuffer>> {
-        self.input_stream.clone()
+
+    /// Takes a spectator slot, or returns false if they are all taken.
+    fn reserve_spectator_slot(&self) -> bool {
+        let max = match self.max_spectators {
+            Some(max) => max,
+            None => {
+                self.spectators.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
+        };
+        let mut current = self.spectators.load(Ordering::SeqCst);
+        loop {
+            if current >= max {
+                return false;
+            }
+            match self.spectators.compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(actual) => current = actual
+            }
+        }
     }
 }
 
@@ -154,67 +408,158 @@ impl DerefMut for PlayerInputBuffer {
 }
 
 fn put_buffer(input_buffer: &mut InputBufferMutex, player: String, input: Input) {
-    let mut lock = input_buffer.lock().unwrap();
+    // Another client thread panicking while it held the lock doesn't spoil the buffer.
+    let mut lock = input_buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
     lock.push_input(player, input);
     drop(lock);
 }
 
-fn get_new_view(receiver: &mut Receiver<ClientView>) -> Option<ClientView> {
-    match receiver.try_recv() {
-        Ok(V) => Some(V),
-        Err(E) => match E {
-            Empty => None,
-            _ => panic!("View channel was closed!")
-        }
+/// Counts the bytes that go through a client's stream.
+struct Metered<S> {
+    stream: S,
+    link: LinkStats
+}
+
+impl<S: Read> Read for Metered<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        self.link.received(read);
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for Metered<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(buf)?;
+        self.link.sent(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: ClientStream> ClientStream for Metered<S> {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    fn is_framed(&self) -> bool {
+        self.stream.is_framed()
+    }
+
+    fn keep_alive(&mut self) -> io::Result<()> {
+        self.stream.keep_alive()
     }
 }
 
 const BUFFER_SIZE: usize = 512;
-fn stream_communicate(mut stream: TcpStream, mut view_channel: Receiver<ClientView>, mut input_m: InputBufferMutex, key: String) {
+fn stream_communicate<S: ClientStream>(mut stream: Metered<S>, mut view_channel: QueueReceiver<Outbound>, mut input_m: InputBufferMutex, key: String, role: ConnectionRole, mut heartbeat: Heartbeat, mut limiter: Option<RateLimiter>, events: Sender<ConnectionEvent>) {
     println!("Connection made!");
     let mut buffer = BytesMut::with_capacity(BUFFER_SIZE);
     buffer.put(&[0; BUFFER_SIZE][..]);
-    stream.set_nonblocking(true);
+    if let Err(e) = stream.set_nonblocking(true) {
+        println!("The client thread is exiting: {}", e);
+        return;
+    }
     loop {
         // send new data to the client
-        let mut view = get_new_view(&mut view_channel);
-        if view.is_some() {
-            // Get latest view
-            loop {
-                let mut tmp = get_new_view(&mut view_channel);
-                if tmp.is_some() {
-                    view = tmp;
-                } else {
-                    break;
+        let pending = match view_channel.drain() {
+            Ok(pending) => pending,
+            Err(e) => {
+                println!("The client thread is exiting: {}", e);
+                return;
+            }
+        };
+        // Only the latest view is worth sending, but every message goes out, in order.
+        let latest_view = pending.iter().rposition(|o| matches!(o, Outbound::View(_)));
+        for (i, outbound) in pending.into_iter().enumerate() {
+            match outbound {
+                Outbound::View(_) if Some(i) != latest_view => stream.link.dropped(1),
+                outbound => {
+                    if let StreamWriteResult::Ok = send_to_stream(&mut stream, &outbound) {
+                        stream.link.sent_message();
+                    }
                 }
             }
-            // Update the client's view:
-            send_view_to_stream(&mut stream, view.unwrap());
+        }
+        // The engine finishes the queue when it is done with the client, such as after a goodbye.
+        if view_channel.is_finished() {
+            let _ = stream.flush();
+            println!("The client thread is exiting because the server closed the connection.");
+            return;
+        }
+
+        // Clients that stop talking are dropped. The engine then removes them like any other
+        // connection whose thread has gone.
+        let now = Instant::now();
+        if heartbeat.timed_out(now) {
+            println!("The client thread is exiting because {} stopped answering heartbeats.", key);
+            return;
+        }
+        if let Some(ping) = heartbeat.poll(now) {
+            if let Ok(ping) = ClientMessage::from(&ping) {
+                send_to_stream(&mut stream, &Outbound::Message(ping));
+            }
+        }
+
+        if let Err(e) = stream.keep_alive() {
+            println!("The client thread is exiting because the connection went quiet: {}", e);
+            return;
         }
 
         use self::StreamReadResult::*;
-        match read_from_message_from_stream_nonblocking(&mut stream, &mut buffer) {
-            ValidMessage(s) => handle_msg(s, &mut input_m),
-            InvalidMessage => println!("Invalid message from client!"),
+        let message = match read_from_message_from_stream_nonblocking(&mut stream, &mut buffer) {
+            ValidMessage(s) => Some(s),
+            InvalidMessage => None,
             NotReady => continue,
             StreamError(e) => {
                 println!("The stream has been closed and the client thread is exiting due to an error: {}", e);
                 return;
             }
+        };
+        stream.link.received_message();
+        heartbeat.heard(Instant::now());
+
+        // Messages over the rate limit are dropped, and the engine hears about it.
+        let size = message.as_ref().map(|s| s.len()).unwrap_or(0);
+        if let Some(Err(violation)) = limiter.as_mut().map(|l| l.check(size, Instant::now())) {
+            let _ = events.send(ConnectionEvent::RateLimited { key: key.clone(), violation });
+            match violation.action {
+                RateLimitAction::Drop => {},
+                RateLimitAction::Warn => if let Ok(warning) = ClientMessage::from(&violation) {
+                    send_to_stream(&mut stream, &Outbound::Message(warning));
+                },
+                RateLimitAction::Disconnect => {
+                    println!("The client thread is exiting because {} kept going over its rate limit.", key);
+                    return;
+                }
+            }
+            continue;
+        }
+
+        match message {
+            Some(s) => if let Ok(reply) = serde_json::from_str::<HeartbeatReply>(&s) {
+                heartbeat.answered(&reply, Instant::now());
+            } else if role != ConnectionRole::Spectator {
+                // Spectators only watch, so anything else they send is ignored.
+                handle_msg(s, &mut input_m, &key);
+            },
+            None => println!("Invalid message from client!")
         }
     }
 }
 
-fn handle_msg(msg: String, mut input_m: &mut InputBufferMutex) {
+fn handle_msg(msg: String, input_m: &mut InputBufferMutex, key: &str) {
     println!("{}", msg);
     let msg = serde_json::from_str(msg.as_str());
-    match msg {
-        Ok(InputMessage {
-                clicks,
-            keys
-             }) => {
-            println!("{:?}", clicks);
-        },
-        Err(_) => ()
+    if let Ok(InputMessage { clicks, keys }) = msg {
+        for (x, y) in clicks {
+            put_buffer(input_m, key.to_string(), Input::Click { x, y });
+        }
+        for k in keys {
+            put_buffer(input_m, key.to_string(), Input::Key(k.to_string()));
+        }
     }
 }
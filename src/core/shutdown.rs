@@ -0,0 +1,36 @@
+use super::world::OutboundMessage;
+
+/// Sent to every client as a `goodbye` message when the server shuts down.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Goodbye {
+    pub reason: String
+}
+
+/// How a shutdown went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// How many clients were sent a goodbye.
+    pub clients: usize,
+    /// Threads or client tasks that were still running when the timeout ran out. They are left
+    /// to finish on their own.
+    pub unfinished: usize
+}
+
+impl OutboundMessage for Goodbye {
+    const TAG: &'static str = "goodbye";
+}
+
+impl Goodbye {
+    pub fn new(reason: &str) -> Self {
+        Goodbye {
+            reason: reason.to_string()
+        }
+    }
+}
+
+impl ShutdownReport {
+    /// Whether everything finished before the timeout.
+    pub fn is_clean(&self) -> bool {
+        self.unfinished == 0
+    }
+}
@@ -0,0 +1,156 @@
+use super::world::ComponentRegistry;
+use serde_json::{Map, Value};
+use specs::{Builder, Entity, World};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The version of the snapshot file layout itself. Component layouts are versioned separately,
+/// through `NamedComponent::VERSION`.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// A saved copy of every registered component and resource in a world.
+///
+/// Entities are saved in ID order but are given new IDs when a snapshot is loaded, so a component
+/// that stores another entity's ID won't point at the right entity afterwards.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub format: u32,
+    pub tick: u64,
+    /// The version of each component at the time it was saved.
+    pub components: HashMap<String, u32>,
+    pub entities: Vec<Map<String, Value>>,
+    pub resources: Map<String, Value>
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Parse(String),
+    UnsupportedFormat(u32),
+    Component(String)
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "Snapshot I/O failed: {}", e),
+            SnapshotError::Parse(e) => write!(f, "Snapshot is malformed: {}", e),
+            SnapshotError::UnsupportedFormat(v) => write!(f, "Snapshot format {} is newer than this engine supports ({})",
+                                                          v, SNAPSHOT_FORMAT_VERSION),
+            SnapshotError::Component(e) => write!(f, "Snapshot component error: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl Snapshot {
+    /// Copies every registered component and resource out of the world.
+    pub fn capture(world: &World, registry: &ComponentRegistry, tick: u64) -> Result<Snapshot, SnapshotError> {
+        let mut entities: BTreeMap<u32, Entity> = BTreeMap::new();
+        for entry in registry.iter() {
+            for entity in entry.entities(world) {
+                entities.insert(entity.id(), entity);
+            }
+        }
+
+        let mut saved = Vec::with_capacity(entities.len());
+        for entity in entities.values() {
+            saved.push(registry.serialize_entity(world, *entity).map_err(SnapshotError::Component)?);
+        }
+
+        let mut resources = Map::new();
+        for entry in registry.resources() {
+            if let Some(value) = entry.serialize(world) {
+                let value = value.map_err(|e| SnapshotError::Component(format!("'{}': {}", entry.name, e)))?;
+                resources.insert(entry.name.to_string(), value);
+            }
+        }
+
+        Ok(Snapshot {
+            format: SNAPSHOT_FORMAT_VERSION,
+            tick,
+            components: registry.iter().map(|e| (e.name.to_string(), e.version)).collect(),
+            entities: saved,
+            resources
+        })
+    }
+
+    /// Replaces the contents of the world with the snapshot. Every existing entity is deleted,
+    /// including entities that only have unregistered components. Components saved by an older
+    /// version are migrated first. If anything in the snapshot can't be loaded, the world is
+    /// left untouched.
+    pub fn restore(self, world: &mut World, registry: &ComponentRegistry) -> Result<(), SnapshotError> {
+        // Decode everything before touching the world, so a bad value can't leave it half loaded.
+        let mut entities = Vec::with_capacity(self.entities.len());
+        for components in self.entities {
+            let mut decoded = Vec::with_capacity(components.len());
+            for (name, value) in components {
+                let entry = registry.get(&name)
+                    .ok_or_else(|| SnapshotError::Component(format!("Component '{}' isn't registered", name)))?;
+                let version = self.components.get(&name).cloned().unwrap_or(1);
+                let value = entry.migrate(version, value)
+                    .and_then(|value| entry.decode(value))
+                    .map_err(|e| SnapshotError::Component(format!("Could not deserialize '{}': {}", name, e)))?;
+                decoded.push(value);
+            }
+            entities.push(decoded);
+        }
+        let mut resources = Vec::with_capacity(self.resources.len());
+        for (name, value) in self.resources {
+            // Resources that are no longer registered are skipped rather than failing the load.
+            if let Some(entry) = registry.resource(&name) {
+                resources.push(entry.decode(value)
+                    .map_err(|e| SnapshotError::Component(format!("'{}': {}", name, e)))?);
+            }
+        }
+
+        world.delete_all();
+        world.maintain();
+        for components in entities {
+            let entity = world.create_entity().build();
+            for component in components {
+                // The entity was just created, so attaching its components can't fail.
+                let name = component.name();
+                component.insert(world, entity)
+                    .map_err(|e| SnapshotError::Component(format!("Could not deserialize '{}': {}", name, e)))?;
+            }
+        }
+        for resource in resources {
+            resource.insert(world);
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String, SnapshotError> {
+        serde_json::to_string(self).map_err(|e| SnapshotError::Parse(e.to_string()))
+    }
+
+    pub fn from_json(source: &str) -> Result<Snapshot, SnapshotError> {
+        // Read the format first, so a newer file gives a clear error instead of a parse failure.
+        let value: Value = serde_json::from_str(source).map_err(|e| SnapshotError::Parse(e.to_string()))?;
+        let format = value.get("format").and_then(|f| f.as_u64()).unwrap_or(0) as u32;
+        if format > SNAPSHOT_FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedFormat(format));
+        }
+        serde_json::from_value(value).map_err(|e| SnapshotError::Parse(e.to_string()))
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Snapshot, SnapshotError> {
+        Snapshot::from_json(&fs::read_to_string(path)?)
+    }
+}
@@ -1,5 +1,223 @@
-use specs::{Entity, World};
+use super::ComponentRegistry;
+use specs::{Entity, World, Builder};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
 
+/// Something that can add an entity to the world.
 pub trait Blueprint {
-    fn add_to_world(mut self, _w: &mut World);
-}
\ No newline at end of file
+    fn add_to_world(self, w: &mut World) -> Result<Entity, BlueprintError>;
+}
+
+/// A prefab as it is written in a data file.
+///
+/// A prefab can extend another one. It starts with all of the parent's components, and any
+/// component it lists is merged over the parent's, field by field. So with
+///
+/// ```json
+/// {
+///     "enemy": { "components": { "health": { "hp": 100, "armor": 2 }, "visible": { "sprite": 3 } } },
+///     "orc": { "extends": "enemy", "components": { "health": { "hp": 50 } } }
+/// }
+/// ```
+///
+/// an orc has 50 hp, 2 armor and sprite 3.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Prefab {
+    #[serde(default)]
+    pub extends: Option<String>,
+    #[serde(default)]
+    pub components: Map<String, Value>
+}
+
+/// The data formats prefab files can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlueprintFormat {
+    Json,
+    Ron,
+    Toml
+}
+
+#[derive(Clone, Debug)]
+pub enum BlueprintError {
+    Io(String),
+    Parse { source: String, message: String },
+    UnknownPrefab(String),
+    UnknownComponent { prefab: String, component: String },
+    InvalidComponent { prefab: String, component: String, message: String },
+    InheritanceCycle(Vec<String>)
+}
+
+/// Holds every prefab by name. Prefabs refer to components by the names they were given in the
+/// `ComponentRegistry`.
+#[derive(Default)]
+pub struct BlueprintRegistry {
+    prefabs: HashMap<String, Prefab>
+}
+
+/// A prefab with its inheritance resolved, ready to be added to a world.
+pub struct PrefabInstance<'r> {
+    registry: &'r ComponentRegistry,
+    prefab: String,
+    components: Map<String, Value>
+}
+
+/// Merges `over` into `base`. Objects are merged key by key, anything else is replaced.
+fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Object(base), Value::Object(over)) => {
+            for (key, value) in over {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => { base.insert(key, value); }
+                }
+            }
+        },
+        (base, over) => *base = over
+    }
+}
+
+impl BlueprintFormat {
+    /// Picks the format from a file's extension.
+    pub fn from_path(path: &Path) -> Option<BlueprintFormat> {
+        match path.extension()?.to_str()? {
+            "json" => Some(BlueprintFormat::Json),
+            "ron" => Some(BlueprintFormat::Ron),
+            "toml" => Some(BlueprintFormat::Toml),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for BlueprintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BlueprintError::*;
+        match self {
+            Io(e) => write!(f, "Could not read blueprint file: {}", e),
+            Parse { source, message } => write!(f, "Could not parse blueprints in '{}': {}", source, message),
+            UnknownPrefab(name) => write!(f, "No prefab is called '{}'", name),
+            UnknownComponent { prefab, component } =>
+                write!(f, "Prefab '{}' uses component '{}', which isn't registered", prefab, component),
+            InvalidComponent { prefab, component, message } =>
+                write!(f, "Prefab '{}' has an invalid '{}' component: {}", prefab, component, message),
+            InheritanceCycle(chain) => write!(f, "Prefabs extend each other in a cycle: {}", chain.join(" -> "))
+        }
+    }
+}
+
+impl std::error::Error for BlueprintError {}
+
+impl BlueprintRegistry {
+    pub fn new() -> Self {
+        BlueprintRegistry {
+            prefabs: HashMap::new()
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, prefab: Prefab) {
+        self.prefabs.insert(name.to_string(), prefab);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.prefabs.contains_key(name)
+    }
+
+    /// Adds every prefab in a data file. The format is picked from the file's extension
+    /// (`.json`, `.ron` or `.toml`). Returns the names of the prefabs that were added.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<String>, BlueprintError> {
+        let path = path.as_ref();
+        let format = BlueprintFormat::from_path(path).ok_or_else(|| BlueprintError::Parse {
+            source: path.display().to_string(),
+            message: "unknown file extension, expected .json, .ron or .toml".to_string()
+        })?;
+        let source = fs::read_to_string(path)
+            .map_err(|e| BlueprintError::Io(format!("{}: {}", path.display(), e)))?;
+        self.load_str(&source, format, &path.display().to_string())
+    }
+
+    /// Adds every prefab in a string. `source_name` is only used in error messages.
+    pub fn load_str(&mut self, source: &str, format: BlueprintFormat, source_name: &str) -> Result<Vec<String>, BlueprintError> {
+        let parsed: Result<HashMap<String, Prefab>, String> = match format {
+            BlueprintFormat::Json => serde_json::from_str(source).map_err(|e| e.to_string()),
+            BlueprintFormat::Ron => ron::de::from_str(source).map_err(|e| e.to_string()),
+            BlueprintFormat::Toml => toml::from_str(source).map_err(|e| e.to_string())
+        };
+        let prefabs = parsed.map_err(|message| BlueprintError::Parse {
+            source: source_name.to_string(),
+            message
+        })?;
+        let names = prefabs.keys().cloned().collect();
+        self.prefabs.extend(prefabs);
+        Ok(names)
+    }
+
+    /// Works out the full component list of a prefab, including everything it inherits.
+    pub fn resolve(&self, name: &str) -> Result<Map<String, Value>, BlueprintError> {
+        // Walk up to the root of the inheritance chain, then apply overrides on the way back down.
+        let mut chain = vec![];
+        let mut next = Some(name.to_string());
+        while let Some(current) = next {
+            if chain.contains(&current) {
+                chain.push(current);
+                return Err(BlueprintError::InheritanceCycle(chain));
+            }
+            let prefab = self.prefabs.get(&current)
+                .ok_or_else(|| BlueprintError::UnknownPrefab(current.clone()))?;
+            next = prefab.extends.clone();
+            chain.push(current);
+        }
+
+        let mut components = Value::Object(Map::new());
+        for prefab in chain.iter().rev() {
+            merge(&mut components, Value::Object(self.prefabs[prefab].components.clone()));
+        }
+        match components {
+            Value::Object(components) => Ok(components),
+            _ => unreachable!()
+        }
+    }
+
+    pub fn instantiate<'r>(&self, name: &str, components: &'r ComponentRegistry) -> Result<PrefabInstance<'r>, BlueprintError> {
+        Ok(PrefabInstance {
+            registry: components,
+            prefab: name.to_string(),
+            components: self.resolve(name)?
+        })
+    }
+
+    /// Creates an entity from the prefab called `name`.
+    pub fn spawn(&self, name: &str, components: &ComponentRegistry, world: &mut World) -> Result<Entity, BlueprintError> {
+        self.instantiate(name, components)?.add_to_world(world)
+    }
+}
+
+impl<'r> Blueprint for PrefabInstance<'r> {
+    fn add_to_world(self, w: &mut World) -> Result<Entity, BlueprintError> {
+        // Check every component before creating anything, so a bad prefab doesn't leave a
+        // half-built entity behind.
+        for component in self.components.keys() {
+            if !self.registry.contains(component) {
+                return Err(BlueprintError::UnknownComponent {
+                    prefab: self.prefab.clone(),
+                    component: component.clone()
+                });
+            }
+        }
+
+        let entity = w.create_entity().build();
+        for (component, value) in self.components {
+            let entry = self.registry.get(&component).unwrap();
+            if let Err(message) = entry.deserialize(w, entity, value) {
+                let _ = w.delete_entity(entity);
+                return Err(BlueprintError::InvalidComponent {
+                    prefab: self.prefab,
+                    component,
+                    message
+                });
+            }
+        }
+        Ok(entity)
+    }
+}
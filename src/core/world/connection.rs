@@ -1,4 +1,7 @@
+use crate::utils::ratelimit::RateLimitViolation;
 use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug, Default)]
 pub struct ConnectionCollection {
@@ -9,9 +12,62 @@ pub struct ConnectionCollection {
 // Sharing connections can be hard, because both the ECS system and the server
 // need to read/write to the client views. In this case, the world's connection collection
 // is passed immutably to the server, which sends data to each respective channel.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Connection {
-    pub key: String
+    pub key: String,
+    pub role: ConnectionRole,
+    /// The key of the player a spectator's camera follows. Spectators without one get a free
+    /// camera that sees everything.
+    pub follow: Option<String>,
+    /// Measured by the server while the connection is open. Recordings and snapshots don't keep it.
+    #[serde(skip)]
+    pub link: LinkStats
+}
+
+/// Something the server noticed about a connection. Each tick, events are put in the
+/// `ConnectionEvents` resource of the world the connection is in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// The client sent more than its rate limit allows.
+    RateLimited { key: String, violation: RateLimitViolation }
+}
+
+/// Numbers the server measures about a connection while it is open. Every clone of a
+/// `Connection` shares them, so systems see them change.
+#[derive(Clone, Debug, Default)]
+pub struct LinkStats {
+    inner: Arc<Mutex<LinkSample>>
+}
+
+/// The numbers in `LinkStats` at one moment. Byte and message counts are totals since the
+/// connection was made.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkSample {
+    /// The smoothed round trip time, once a heartbeat has been answered.
+    pub rtt: Option<Duration>,
+    /// How much round trip times vary, smoothed the same way.
+    pub jitter: Option<Duration>,
+    /// When the client last sent anything.
+    pub last_heard: Option<Instant>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    /// Messages waiting to be sent to the client.
+    pub send_queue: usize,
+    /// Messages that were never sent, such as views replaced by newer ones.
+    pub dropped: u64
+}
+
+/// What a connection is allowed to do. The role is chosen by the stream handler when the
+/// connection is made.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectionRole {
+    #[default]
+    Player,
+    /// Receives views but can't send inputs.
+    Spectator,
+    Admin
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -20,6 +76,80 @@ pub struct ClientView {
     pub loc: Vec<(f32, f32)>
 }
 
+impl Connection {
+    pub fn new(key: &str, role: ConnectionRole) -> Self {
+        Connection {
+            key: key.to_string(),
+            role,
+            follow: None,
+            link: LinkStats::default()
+        }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.link.sample().rtt
+    }
+
+    pub fn is_spectator(&self) -> bool {
+        self.role == ConnectionRole::Spectator
+    }
+
+    /// Whether the connection's inputs are passed on to the game.
+    pub fn can_send_input(&self) -> bool {
+        self.role != ConnectionRole::Spectator
+    }
+}
+
+impl LinkStats {
+    pub fn sample(&self) -> LinkSample {
+        *self.inner.lock().unwrap()
+    }
+
+    pub(crate) fn heard(&self, now: Instant) {
+        self.inner.lock().unwrap().last_heard = Some(now);
+    }
+
+    /// Adds a round trip measurement. Like TCP, each one moves the average an eighth of the way,
+    /// and the jitter a quarter of the way.
+    pub(crate) fn record_rtt(&self, rtt: Duration) {
+        let mut sample = self.inner.lock().unwrap();
+        let (smoothed, jitter) = match (sample.rtt, sample.jitter) {
+            (Some(smoothed), jitter) => {
+                let deviation = rtt.abs_diff(smoothed);
+                let jitter = jitter.unwrap_or_default();
+                (smoothed * 7 / 8 + rtt / 8, jitter * 3 / 4 + deviation / 4)
+            },
+            (None, _) => (rtt, rtt / 2)
+        };
+        sample.rtt = Some(smoothed);
+        sample.jitter = Some(jitter);
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.inner.lock().unwrap().bytes_in += bytes as u64;
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.inner.lock().unwrap().bytes_out += bytes as u64;
+    }
+
+    pub(crate) fn received_message(&self) {
+        self.inner.lock().unwrap().messages_in += 1;
+    }
+
+    pub(crate) fn sent_message(&self) {
+        self.inner.lock().unwrap().messages_out += 1;
+    }
+
+    pub(crate) fn set_send_queue(&self, depth: usize) {
+        self.inner.lock().unwrap().send_queue = depth;
+    }
+
+    pub(crate) fn dropped(&self, count: usize) {
+        self.inner.lock().unwrap().dropped += count as u64;
+    }
+}
+
 impl ConnectionCollection {
     pub fn new() -> Self {
         ConnectionCollection {
@@ -32,26 +162,50 @@ impl ConnectionCollection {
         self.connections.len()
     }
 
+    /// Every connection that isn't a spectator.
+    pub fn players(&self) -> impl Iterator<Item=&Connection> {
+        self.connections.iter().filter(|c| !c.is_spectator())
+    }
+
+    pub fn spectators(&self) -> impl Iterator<Item=&Connection> {
+        self.connections.iter().filter(|c| c.is_spectator())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Connection> {
+        self.connections.iter().find(|c| c.key == key)
+    }
+
     pub fn pop_new_key(&mut self) -> Option<String> {
         self.new_keys.pop_front()
     }
 
     pub fn pop_new_keys(&mut self) -> Vec<String> {
         let mut keys = vec!();
-        loop {
-            let key = self.pop_new_key();
-            if key.is_some() {
-                keys.push(key.unwrap());
-            } else {
-                break;
-            }
+        while let Some(key) = self.pop_new_key() {
+            keys.push(key);
         }
         self.new_keys.clear();
         keys
     }
 
     pub fn remove(&mut self, key: &String) {
-        self.connections.retain(|x| x.key == *key);
+        self.connections.retain(|x| x.key != *key);
+        self.new_keys.retain(|k| k != key);
+    }
+
+    /// Removes a connection and hands it back, for moving it somewhere else.
+    pub fn take(&mut self, key: &str) -> Option<Connection> {
+        let index = self.connections.iter().position(|c| c.key == key)?;
+        self.new_keys.retain(|k| k != key);
+        Some(self.connections.remove(index))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.connections.iter().any(|c| c.key == key)
+    }
+
+    pub(crate) fn clear_new_keys(&mut self) {
+        self.new_keys.clear();
     }
 
     pub fn push(&mut self, c: Connection) {
@@ -60,6 +214,12 @@ impl ConnectionCollection {
     }
 }
 
+impl Default for ClientView {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ClientView {
     pub fn new() -> Self {
         ClientView {
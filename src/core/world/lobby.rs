@@ -0,0 +1,221 @@
+use super::{World, MasterController, EngineInstruction, Input, Outbox, OutboundMessage};
+
+/// Where a lobby is in the flow from gathering players to playing and back.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state")]
+pub enum LobbyState {
+    #[default]
+    WaitingForPlayers,
+    /// Every player has to send the ready key before `remaining` runs out.
+    ReadyCheck { remaining: f64 },
+    Countdown { remaining: f64 },
+    InGame,
+    PostGame { remaining: f64 }
+}
+
+/// What clients are told about the lobby. It is also a resource in the lobby's world, so systems
+/// can read it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LobbyStatus {
+    pub state: LobbyState,
+    pub players: Vec<String>,
+    pub ready: Vec<String>,
+    pub min_players: usize,
+    pub max_players: usize
+}
+
+#[derive(Clone, Debug)]
+pub struct LobbyConfig {
+    pub min_players: usize,
+    pub max_players: usize,
+    /// Seconds players have to ready up before the lobby goes back to waiting.
+    pub ready_timeout: f64,
+    pub countdown: f64,
+    /// Seconds between the game ending and the lobby opening again.
+    pub post_game: f64,
+    /// The key input that marks a player as ready.
+    pub ready_key: String
+}
+
+/// A master controller that runs the usual lobby flow, and hands the world to `game` while a
+/// game is being played.
+///
+/// The lobby holds the first `max_players` player connections; anyone else waits outside it.
+/// Inputs sent before the game starts are used by the lobby and never reach the systems. The
+/// game is started with `game.start` when the countdown ends, and ends when `game.tick` returns
+/// `EngineInstruction::Restart`. The systems don't run on ticks where the lobby changes state.
+pub struct LobbyController<G> {
+    config: LobbyConfig,
+    game: G,
+    status: LobbyStatus
+}
+
+impl OutboundMessage for LobbyStatus {
+    const TAG: &'static str = "lobby";
+}
+
+impl LobbyConfig {
+    pub fn new(min_players: usize, max_players: usize) -> Self {
+        LobbyConfig {
+            min_players: min_players.max(1),
+            max_players: max_players.max(min_players).max(1),
+            ready_timeout: 30.0,
+            countdown: 5.0,
+            post_game: 10.0,
+            ready_key: "r".to_string()
+        }
+    }
+
+    pub fn ready_timeout(mut self, seconds: f64) -> Self {
+        self.ready_timeout = seconds;
+        self
+    }
+
+    pub fn countdown(mut self, seconds: f64) -> Self {
+        self.countdown = seconds;
+        self
+    }
+
+    pub fn post_game(mut self, seconds: f64) -> Self {
+        self.post_game = seconds;
+        self
+    }
+
+    pub fn ready_key(mut self, key: &str) -> Self {
+        self.ready_key = key.to_string();
+        self
+    }
+}
+
+impl<G: MasterController> LobbyController<G> {
+    pub fn new(config: LobbyConfig, game: G) -> Self {
+        let status = LobbyStatus {
+            state: LobbyState::WaitingForPlayers,
+            players: vec![],
+            ready: vec![],
+            min_players: config.min_players,
+            max_players: config.max_players
+        };
+        LobbyController {
+            config,
+            game,
+            status
+        }
+    }
+
+    pub fn status(&self) -> &LobbyStatus {
+        &self.status
+    }
+
+    pub fn game(&mut self) -> &mut G {
+        &mut self.game
+    }
+
+    /// Updates the player list from the world's connections. Returns whether it changed.
+    fn update_players(&mut self, world: &World) -> bool {
+        let players: Vec<String> = world.connections.players()
+            .take(self.config.max_players)
+            .map(|c| c.key.clone())
+            .collect();
+        if players == self.status.players {
+            return false;
+        }
+        self.status.ready.retain(|key| players.contains(key));
+        self.status.players = players;
+        true
+    }
+
+    /// Takes every waiting input out of the world, marking players who sent the ready key.
+    /// Returns whether anyone new is ready.
+    fn take_ready_inputs(&mut self, world: &mut World) -> bool {
+        let keys: Vec<String> = world.pending_inputs().keys().cloned().collect();
+        let mut changed = false;
+        for key in keys {
+            let inputs = world.take_inputs(&key).unwrap_or_default();
+            let sent_ready = inputs.iter().any(|input| match input {
+                Input::Key(k) => *k == self.config.ready_key,
+                _ => false
+            });
+            let can_ready = match self.status.state {
+                LobbyState::ReadyCheck { .. } => self.status.players.contains(&key),
+                _ => false
+            };
+            if sent_ready && can_ready && !self.status.ready.contains(&key) {
+                self.status.ready.push(key);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Works out the next state. `None` means the state only counts down.
+    fn next_state(&mut self, delta_time: f64) -> Option<LobbyState> {
+        let enough = self.status.players.len() >= self.config.min_players;
+        match self.status.state {
+            LobbyState::WaitingForPlayers if enough => Some(LobbyState::ReadyCheck { remaining: self.config.ready_timeout }),
+            LobbyState::WaitingForPlayers => None,
+            LobbyState::ReadyCheck { .. } | LobbyState::Countdown { .. } if !enough => Some(LobbyState::WaitingForPlayers),
+            LobbyState::ReadyCheck { .. } if self.status.ready.len() == self.status.players.len() =>
+                Some(LobbyState::Countdown { remaining: self.config.countdown }),
+            LobbyState::ReadyCheck { ref mut remaining } => {
+                *remaining -= delta_time;
+                if *remaining <= 0.0 { Some(LobbyState::WaitingForPlayers) } else { None }
+            },
+            LobbyState::Countdown { ref mut remaining } => {
+                *remaining -= delta_time;
+                if *remaining <= 0.0 { Some(LobbyState::InGame) } else { None }
+            },
+            LobbyState::PostGame { ref mut remaining } => {
+                *remaining -= delta_time;
+                if *remaining <= 0.0 { Some(LobbyState::WaitingForPlayers) } else { None }
+            },
+            LobbyState::InGame => None
+        }
+    }
+
+    /// Stores the status in the world and sends it to every connection in the world.
+    fn broadcast(&self, world: &mut World) {
+        world.ecs_world.add_resource(self.status.clone());
+        if let Err(e) = world.ecs_world.write_resource::<Outbox>().broadcast(&self.status) {
+            println!("Lobby could not send its status: {}", e);
+        }
+    }
+}
+
+impl<G: MasterController> MasterController for LobbyController<G> {
+    type ObserverEvent = G::ObserverEvent;
+
+    fn start(&mut self, world: &mut World, _delta_time: f64) {
+        self.broadcast(world);
+    }
+
+    fn tick(&mut self, world: &mut World, delta_time: f64) -> EngineInstruction {
+        if self.status.state == LobbyState::InGame {
+            match self.game.tick(world, delta_time) {
+                EngineInstruction::Restart => {
+                    self.status.state = LobbyState::PostGame { remaining: self.config.post_game };
+                    self.status.ready.clear();
+                    self.broadcast(world);
+                    return EngineInstruction::Run { run_dispatcher: false };
+                },
+                instruction => return instruction
+            }
+        }
+
+        let mut changed = self.update_players(world);
+        changed |= self.take_ready_inputs(world);
+        if let Some(state) = self.next_state(delta_time) {
+            match state {
+                LobbyState::WaitingForPlayers | LobbyState::ReadyCheck { .. } => self.status.ready.clear(),
+                LobbyState::InGame => self.game.start(world, delta_time),
+                _ => {}
+            }
+            self.status.state = state;
+            changed = true;
+        }
+        if changed {
+            self.broadcast(world);
+        }
+        EngineInstruction::Run { run_dispatcher: false }
+    }
+}
@@ -6,6 +6,9 @@ pub trait MasterController {
     fn tick(&mut self, _world: &mut World, _delta_time: f64) -> EngineInstruction { EngineInstruction::Run {
         run_dispatcher: true
     } }
+    /// Called once when the engine shuts down, before clients are sent their goodbye. Messages
+    /// sent from here still reach them, and this is the place for a final save.
+    fn shutdown(&mut self, _world: &mut World) {}
 }
 
 pub enum EngineInstruction {
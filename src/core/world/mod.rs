@@ -1,16 +1,99 @@
 mod connection;
 mod input;
 mod mc;
+mod lobby;
+mod outbox;
 mod system;
 mod blueprint;
+mod registry;
+mod stats;
 
-pub use connection::{ConnectionCollection, Connection, ClientView};
+use crate::utils::{DeltaTime, InputMap, ViewMap};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+pub use connection::{ConnectionCollection, Connection, ConnectionRole, ClientView, ConnectionEvent, LinkStats, LinkSample};
 pub use input::Input;
 pub use mc::{MasterController, EngineInstruction};
+pub use lobby::{LobbyController, LobbyConfig, LobbyState, LobbyStatus};
+pub use outbox::{Outbox, Outbound, OutboundMessage, ClientMessage};
 pub use system::{SystemExecutor, SystemExecutorBuilder};
+pub use blueprint::{Blueprint, BlueprintRegistry, BlueprintError, BlueprintFormat, Prefab, PrefabInstance};
+pub use stats::{ConnectionStats, NetworkStats};
+pub use registry::{ComponentRegistry, ComponentEntry, ResourceEntry, DecodedComponent, DecodedResource, NamedComponent, NamedResource};
 
 pub struct World<'a, 'b> {
     pub(crate) system_executor: SystemExecutor<'a, 'b>,
     pub ecs_world: specs::prelude::World,
     pub connections: ConnectionCollection,
-}
\ No newline at end of file
+    /// Inputs waiting for the world's next dispatcher run.
+    pending_inputs: InputMap,
+}
+
+impl<'a, 'b> World<'a, 'b> {
+    pub(crate) fn new(system_executor: SystemExecutor<'a, 'b>) -> Self {
+        World {
+            system_executor,
+            ecs_world: specs::prelude::World::new(),
+            connections: ConnectionCollection::new(),
+            pending_inputs: InputMap::new(),
+        }
+    }
+
+    /// The inputs that will be handed to the systems the next time the dispatcher runs. Master
+    /// controllers can look at these before the systems do.
+    pub fn pending_inputs(&self) -> &InputMap {
+        &self.pending_inputs
+    }
+
+    /// Takes a connection's waiting inputs, so the systems never see them.
+    pub fn take_inputs(&mut self, key: &str) -> Option<VecDeque<Input>> {
+        self.pending_inputs.remove(key)
+    }
+
+    pub(crate) fn queue_inputs(&mut self, inputs: InputMap) {
+        for (key, queue) in inputs {
+            self.pending_inputs.entry(key).or_default().extend(queue);
+        }
+    }
+
+    /// Hands the waiting inputs and the tick's time to the systems and runs them.
+    pub(crate) fn run_systems(&mut self, delta_time: f64) {
+        let inputs = std::mem::take(&mut self.pending_inputs);
+        self.ecs_world.add_resource(inputs);
+        self.ecs_world.add_resource(DeltaTime(delta_time));
+        self.system_executor.run(&mut self.ecs_world);
+    }
+
+    /// Copies the connection list into the world's `ConnectionCollection` resource, where systems
+    /// read it. The keys of new connections are handed over once, on the tick they joined.
+    pub(crate) fn publish_connections(&mut self) {
+        *self.ecs_world.write_resource::<ConnectionCollection>() = self.connections.clone();
+        self.connections.clear_new_keys();
+    }
+
+    /// Refreshes the `ConnectionStats` resource from the world's connections.
+    pub(crate) fn update_connection_stats(&mut self) {
+        self.ecs_world.write_resource::<ConnectionStats>().update(&self.connections, Instant::now());
+    }
+
+    /// Takes the messages and views the world produced this tick, in the order they are sent.
+    /// Broadcast messages go to every connection in the world, and anything addressed to a
+    /// connection that isn't in it is dropped.
+    pub(crate) fn take_outgoing(&mut self) -> Vec<(String, Outbound)> {
+        let keys = self.connections.connections.iter().map(|c| &c.key);
+        let messages = self.ecs_world.write_resource::<Outbox>().drain_for(keys.clone());
+        let mut views = std::mem::take(&mut *self.ecs_world.write_resource::<ViewMap>());
+        views.retain(|key, _| {
+            let connected = keys.clone().any(|k| k == key);
+            if !connected {
+                println!("World dropped a view for '{}', who isn't connected to it.", key);
+            }
+            connected
+        });
+        messages.into_iter()
+            .map(|(key, message)| (key, Outbound::Message(message)))
+            .chain(views.into_iter().map(|(key, view)| (key, Outbound::View(view))))
+            .collect()
+    }
+}
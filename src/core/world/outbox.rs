@@ -0,0 +1,106 @@
+use super::ClientView;
+use serde::Serialize;
+use serde_json::Value;
+
+/// A message with a fixed tag that systems can send to clients, such as a chat line or a
+/// sound cue.
+pub trait OutboundMessage: Serialize {
+    const TAG: &'static str;
+}
+
+/// A message as it is sent to a client: `{"tag": ..., "data": ...}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientMessage {
+    pub tag: String,
+    pub data: Value
+}
+
+/// Everything the engine sends down a client's stream. Views are written exactly as before, so
+/// clients can tell messages apart by their `tag` field.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Outbound {
+    View(ClientView),
+    Message(ClientMessage)
+}
+
+/// Messages waiting to be sent at the end of the tick. This is a resource, like `ViewMap`.
+/// Messages are delivered in the order they were queued, before the tick's view.
+#[derive(Clone, Debug, Default)]
+pub struct Outbox {
+    // Each message is queued with its target, or `None` for every connection in the world.
+    messages: Vec<(Option<String>, ClientMessage)>
+}
+
+impl ClientMessage {
+    pub fn new<T: Serialize>(tag: &str, data: &T) -> serde_json::Result<Self> {
+        Ok(ClientMessage {
+            tag: tag.to_string(),
+            data: serde_json::to_value(data)?
+        })
+    }
+
+    pub fn from<M: OutboundMessage>(message: &M) -> serde_json::Result<Self> {
+        ClientMessage::new(M::TAG, message)
+    }
+}
+
+impl Outbound {
+    /// Views replace each other, so only the latest one needs sending.
+    pub fn is_view(&self) -> bool {
+        match self {
+            Outbound::View(_) => true,
+            Outbound::Message(_) => false
+        }
+    }
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Outbox {
+            messages: vec![]
+        }
+    }
+
+    /// Queues a message for one connection.
+    pub fn send<M: OutboundMessage>(&mut self, key: &str, message: &M) -> serde_json::Result<()> {
+        self.send_message(key, ClientMessage::from(message)?);
+        Ok(())
+    }
+
+    /// Queues a message for every connection in the world.
+    pub fn broadcast<M: OutboundMessage>(&mut self, message: &M) -> serde_json::Result<()> {
+        self.broadcast_message(ClientMessage::from(message)?);
+        Ok(())
+    }
+
+    pub fn send_message(&mut self, key: &str, message: ClientMessage) {
+        self.messages.push((Some(key.to_string()), message));
+    }
+
+    pub fn broadcast_message(&mut self, message: ClientMessage) {
+        self.messages.push((None, message));
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Empties the outbox, giving each connection in `keys` its messages in order. Messages for
+    /// anyone else are dropped, so a world can't reach players in other rooms.
+    pub(crate) fn drain_for<'k, I: Iterator<Item=&'k String> + Clone>(&mut self, keys: I) -> Vec<(String, ClientMessage)> {
+        let mut out = vec![];
+        for (target, message) in self.messages.drain(..) {
+            match target {
+                Some(key) if keys.clone().any(|k| *k == key) => out.push((key, message)),
+                Some(key) => println!("Outbox dropped a '{}' message for '{}', who isn't connected to this world.", message.tag, key),
+                None => out.extend(keys.clone().map(|key| (key.clone(), message.clone())))
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,305 @@
+use specs::{Component, Entity, World, Join};
+use shred::Resource;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::any::Any;
+use std::collections::HashMap;
+
+/// A component with a stable name and a serialized form. The name is what identifies the
+/// component in blueprints and snapshots, so it must not change once data using it exists.
+///
+/// Network replication and script bindings don't go through the registry yet. Views are still
+/// built from `Position` and `Visible` by `ViewSystem`, and scripts are handed entities as ID
+/// numbers, so registering a component doesn't make it visible to clients or scripts.
+///
+/// Use `define_component!(Type, "name")` to implement this together with `Component`.
+pub trait NamedComponent: Component + Serialize + DeserializeOwned + Send + Sync {
+    const NAME: &'static str;
+
+    /// The version of the component's serialized form. Bump it when the form changes in a way
+    /// `#[serde(default)]` can't cover, and handle the old form in `migrate`.
+    const VERSION: u32 = 1;
+
+    /// Upgrades a value that was serialized by an older version of the component.
+    fn migrate(_from_version: u32, value: Value) -> Result<Value, String> {
+        Ok(value)
+    }
+}
+
+/// A resource with a stable name and a serialized form, so it can be saved in snapshots.
+pub trait NamedResource: Resource + Serialize + DeserializeOwned {
+    const NAME: &'static str;
+}
+
+/// The type-erased operations the registry keeps for each component.
+#[derive(Clone, Copy)]
+pub struct ComponentEntry {
+    pub name: &'static str,
+    pub version: u32,
+    migrate: fn(u32, Value) -> Result<Value, String>,
+    register: fn(&mut World),
+    serialize: fn(&World, Entity) -> Option<Result<Value, String>>,
+    decode: fn(Value) -> Result<Box<dyn Any + Send>, String>,
+    insert: fn(&mut World, Entity, Box<dyn Any + Send>) -> Result<(), String>,
+    remove: fn(&mut World, Entity),
+    entities: fn(&World) -> Vec<Entity>
+}
+
+/// The type-erased operations the registry keeps for each resource.
+#[derive(Clone, Copy)]
+pub struct ResourceEntry {
+    pub name: &'static str,
+    serialize: fn(&World) -> Option<Result<Value, String>>,
+    decode: fn(Value) -> Result<Box<dyn Any + Send>, String>,
+    insert: fn(&mut World, Box<dyn Any + Send>)
+}
+
+/// A component read from its serialized form, but not attached to an entity yet.
+pub struct DecodedComponent {
+    entry: ComponentEntry,
+    value: Box<dyn Any + Send>
+}
+
+/// A resource read from its serialized form, but not added to a world yet.
+pub struct DecodedResource {
+    entry: ResourceEntry,
+    value: Box<dyn Any + Send>
+}
+
+/// Every component and resource that can be serialized, by name.
+#[derive(Clone, Default)]
+pub struct ComponentRegistry {
+    entries: Vec<ComponentEntry>,
+    by_name: HashMap<&'static str, usize>,
+    resources: Vec<ResourceEntry>
+}
+
+fn register_component<T: NamedComponent>(world: &mut World)
+where <T as Component>::Storage: Default {
+    world.register::<T>();
+}
+
+fn serialize_component<T: NamedComponent>(world: &World, entity: Entity) -> Option<Result<Value, String>> {
+    world.read_storage::<T>()
+        .get(entity)
+        .map(|component| serde_json::to_value(component).map_err(|e| e.to_string()))
+}
+
+fn decode_component<T: NamedComponent>(value: Value) -> Result<Box<dyn Any + Send>, String> {
+    let component: T = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    Ok(Box::new(component))
+}
+
+fn insert_component<T: NamedComponent>(world: &mut World, entity: Entity, component: Box<dyn Any + Send>) -> Result<(), String> {
+    let component = component.downcast::<T>()
+        .map_err(|_| format!("Decoded value isn't a '{}' component", T::NAME))?;
+    world.write_storage::<T>()
+        .insert(entity, *component)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn remove_component<T: NamedComponent>(world: &mut World, entity: Entity) {
+    world.write_storage::<T>().remove(entity);
+}
+
+fn component_entities<T: NamedComponent>(world: &World) -> Vec<Entity> {
+    (&world.entities(), &world.read_storage::<T>()).join()
+        .map(|(entity, _)| entity)
+        .collect()
+}
+
+fn serialize_resource<R: NamedResource>(world: &World) -> Option<Result<Value, String>> {
+    if !world.res.has_value::<R>() {
+        return None;
+    }
+    Some(serde_json::to_value(&*world.read_resource::<R>()).map_err(|e| e.to_string()))
+}
+
+fn decode_resource<R: NamedResource>(value: Value) -> Result<Box<dyn Any + Send>, String> {
+    let resource: R = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    Ok(Box::new(resource))
+}
+
+fn insert_resource<R: NamedResource>(world: &mut World, resource: Box<dyn Any + Send>) {
+    // `decode_resource::<R>` is the only thing that makes these values.
+    if let Ok(resource) = resource.downcast::<R>() {
+        world.add_resource(*resource);
+    }
+}
+
+impl ComponentEntry {
+    /// Upgrades a serialized value written by an older version of the component.
+    pub fn migrate(&self, from_version: u32, value: Value) -> Result<Value, String> {
+        if from_version > self.version {
+            return Err(format!("'{}' was saved by a newer version ({}) than this one ({})",
+                               self.name, from_version, self.version));
+        }
+        if from_version == self.version {
+            return Ok(value);
+        }
+        (self.migrate)(from_version, value)
+    }
+
+    /// Registers the component's storage with a world.
+    pub fn register_with(&self, world: &mut World) {
+        (self.register)(world)
+    }
+
+    /// Serializes the component of an entity, or returns `None` if the entity doesn't have one.
+    pub fn serialize(&self, world: &World, entity: Entity) -> Option<Result<Value, String>> {
+        (self.serialize)(world, entity)
+    }
+
+    /// Attaches a component to an entity from its serialized form, replacing any it already has.
+    pub fn deserialize(&self, world: &mut World, entity: Entity, value: Value) -> Result<(), String> {
+        self.decode(value)?.insert(world, entity)
+    }
+
+    /// Reads a component from its serialized form without touching any world, so a whole set of
+    /// components can be checked before any of them is attached.
+    pub fn decode(&self, value: Value) -> Result<DecodedComponent, String> {
+        Ok(DecodedComponent {
+            entry: *self,
+            value: (self.decode)(value)?
+        })
+    }
+
+    pub fn remove(&self, world: &mut World, entity: Entity) {
+        (self.remove)(world, entity)
+    }
+
+    /// Every entity in the world that has this component.
+    pub fn entities(&self, world: &World) -> Vec<Entity> {
+        (self.entities)(world)
+    }
+}
+
+impl ResourceEntry {
+    /// Serializes the resource, or returns `None` if the world doesn't have it.
+    pub fn serialize(&self, world: &World) -> Option<Result<Value, String>> {
+        (self.serialize)(world)
+    }
+
+    /// Replaces the resource in the world with one read from its serialized form.
+    pub fn deserialize(&self, world: &mut World, value: Value) -> Result<(), String> {
+        self.decode(value)?.insert(world);
+        Ok(())
+    }
+
+    /// Reads the resource from its serialized form without touching any world.
+    pub fn decode(&self, value: Value) -> Result<DecodedResource, String> {
+        Ok(DecodedResource {
+            entry: *self,
+            value: (self.decode)(value)?
+        })
+    }
+}
+
+impl DecodedComponent {
+    pub fn name(&self) -> &'static str {
+        self.entry.name
+    }
+
+    /// Attaches the component to an entity, replacing any it already has.
+    pub fn insert(self, world: &mut World, entity: Entity) -> Result<(), String> {
+        (self.entry.insert)(world, entity, self.value)
+    }
+}
+
+impl DecodedResource {
+    /// Adds the resource to a world, replacing any it already has.
+    pub fn insert(self, world: &mut World) {
+        (self.entry.insert)(world, self.value)
+    }
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        ComponentRegistry {
+            entries: vec![],
+            by_name: HashMap::new(),
+            resources: vec![]
+        }
+    }
+
+    /// Records a resource so it is saved in snapshots.
+    pub fn register_resource<R: NamedResource>(&mut self) {
+        let entry = ResourceEntry {
+            name: R::NAME,
+            serialize: serialize_resource::<R>,
+            decode: decode_resource::<R>,
+            insert: insert_resource::<R>
+        };
+        self.resources.retain(|r| r.name != R::NAME);
+        self.resources.push(entry);
+    }
+
+    pub fn resource(&self, name: &str) -> Option<&ResourceEntry> {
+        self.resources.iter().find(|r| r.name == name)
+    }
+
+    pub fn resources(&self) -> impl Iterator<Item=&ResourceEntry> {
+        self.resources.iter()
+    }
+
+    /// Records a component. Registering the same name twice replaces the earlier entry.
+    pub fn register<T: NamedComponent>(&mut self)
+    where <T as Component>::Storage: Default {
+        let entry = ComponentEntry {
+            name: T::NAME,
+            version: T::VERSION,
+            migrate: T::migrate,
+            register: register_component::<T>,
+            serialize: serialize_component::<T>,
+            decode: decode_component::<T>,
+            insert: insert_component::<T>,
+            remove: remove_component::<T>,
+            entities: component_entities::<T>
+        };
+        match self.by_name.get(T::NAME) {
+            Some(&index) => self.entries[index] = entry,
+            None => {
+                self.by_name.insert(T::NAME, self.entries.len());
+                self.entries.push(entry);
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ComponentEntry> {
+        self.by_name.get(name).map(|&index| &self.entries[index])
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.by_name.contains_key(name)
+    }
+
+    /// Iterates over the components in the order they were registered.
+    pub fn iter(&self) -> impl Iterator<Item=&ComponentEntry> {
+        self.entries.iter()
+    }
+
+    /// Serializes every registered component an entity has, keyed by component name.
+    pub fn serialize_entity(&self, world: &World, entity: Entity) -> Result<Map<String, Value>, String> {
+        let mut components = Map::new();
+        for entry in &self.entries {
+            if let Some(value) = entry.serialize(world, entity) {
+                let value = value.map_err(|e| format!("Could not serialize '{}': {}", entry.name, e))?;
+                components.insert(entry.name.to_string(), value);
+            }
+        }
+        Ok(components)
+    }
+
+    /// Attaches serialized components to an entity. Fails on the first component that isn't
+    /// registered or doesn't match its type.
+    pub fn deserialize_entity(&self, world: &mut World, entity: Entity, components: Map<String, Value>) -> Result<(), String> {
+        for (name, value) in components {
+            let entry = self.get(&name).ok_or_else(|| format!("Component '{}' isn't registered", name))?;
+            entry.deserialize(world, entity, value)
+                .map_err(|e| format!("Could not deserialize '{}': {}", name, e))?;
+        }
+        Ok(())
+    }
+}
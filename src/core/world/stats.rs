@@ -0,0 +1,87 @@
+use super::{ConnectionCollection, LinkSample};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Rates are worked out over at least this long, so they don't jump around from tick to tick.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// How one connection is doing.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct NetworkStats {
+    pub rtt: Option<Duration>,
+    pub jitter: Option<Duration>,
+    pub bytes_in_per_sec: f64,
+    pub bytes_out_per_sec: f64,
+    pub messages_in_per_sec: f64,
+    pub messages_out_per_sec: f64,
+    /// Messages waiting to be sent.
+    pub send_queue: usize,
+    /// Messages that were never sent, since the connection was made.
+    pub dropped: u64
+}
+
+/// Network stats for every connection in the world, updated at the start of each tick. This is a
+/// resource, so systems can adapt to bad connections, for example by sending them fewer updates.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    stats: HashMap<String, NetworkStats>,
+    // The totals each connection's rates were last worked out from, and when.
+    baselines: HashMap<String, (Instant, LinkSample)>
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        ConnectionStats {
+            stats: HashMap::new(),
+            baselines: HashMap::new()
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&NetworkStats> {
+        self.stats.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&String, &NetworkStats)> {
+        self.stats.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.is_empty()
+    }
+
+    /// Adds another world's stats, for reporting every connection at once.
+    pub(crate) fn merge(&mut self, other: &ConnectionStats) {
+        self.stats.extend(other.stats.iter().map(|(key, stats)| (key.clone(), stats.clone())));
+    }
+
+    /// Reads the latest numbers from every connection. Connections that have gone are forgotten.
+    pub(crate) fn update(&mut self, connections: &ConnectionCollection, now: Instant) {
+        self.stats.retain(|key, _| connections.contains(key));
+        self.baselines.retain(|key, _| connections.contains(key));
+        for connection in &connections.connections {
+            let sample = connection.link.sample();
+            let stats = self.stats.entry(connection.key.clone()).or_default();
+            stats.rtt = sample.rtt;
+            stats.jitter = sample.jitter;
+            stats.send_queue = sample.send_queue;
+            stats.dropped = sample.dropped;
+
+            let (since, baseline) = *self.baselines.entry(connection.key.clone()).or_insert((now, sample));
+            let elapsed = now - since;
+            if elapsed < RATE_WINDOW {
+                continue;
+            }
+            let seconds = elapsed.as_secs_f64();
+            let rate = |total: u64, before: u64| total.saturating_sub(before) as f64 / seconds;
+            stats.bytes_in_per_sec = rate(sample.bytes_in, baseline.bytes_in);
+            stats.bytes_out_per_sec = rate(sample.bytes_out, baseline.bytes_out);
+            stats.messages_in_per_sec = rate(sample.messages_in, baseline.messages_in);
+            stats.messages_out_per_sec = rate(sample.messages_out, baseline.messages_out);
+            self.baselines.insert(connection.key.clone(), (now, sample));
+        }
+    }
+}
@@ -27,7 +27,7 @@ impl<'a, 'b> SystemExecutorBuilder<'a, 'b> {
         S: for<'c> System<'c> + Send + 'a {
         self.dispatcher_builder.add(system, name, dep);
     }
-    pub fn build(mut self) -> SystemExecutor<'a, 'b> {
+    pub fn build(self) -> SystemExecutor<'a, 'b> {
         SystemExecutor {
             dispatcher: self.dispatcher_builder.build()
         }
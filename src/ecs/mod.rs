@@ -1,8 +1,4 @@
 mod world;
-use specs::prelude::*;
-use world::*;
-use tokio::prelude::*;
-use tokio::sync::mpsc::Sender;
 
 pub struct GameUpdate();
 /*
@@ -0,0 +1,86 @@
+//! The crate's error type. Every error the engine returns converts into it, so code driving the
+//! engine can use `?` on all of them.
+
+use crate::core::{ReplayError, RoomError, SnapshotError, BlueprintError};
+use crate::script::ScriptError;
+use crate::utils::bind::BindError;
+use std::fmt;
+use std::io;
+
+pub type HyperspeedResult<T> = Result<T, HyperspeedError>;
+
+#[derive(Debug)]
+pub enum HyperspeedError {
+    /// `EngineBuilder::build` was called without `with_mc`.
+    MissingMasterController,
+    /// A listener couldn't be bound.
+    Bind(BindError),
+    /// The server stopped handing over new connections, because its threads have gone.
+    ServerStopped,
+    Replay(ReplayError),
+    Snapshot(SnapshotError),
+    Room(RoomError),
+    Blueprint(BlueprintError),
+    Script(ScriptError),
+    Io(io::Error)
+}
+
+impl fmt::Display for HyperspeedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HyperspeedError::MissingMasterController => write!(f, "The engine needs a master controller"),
+            HyperspeedError::Bind(e) => write!(f, "{}", e),
+            HyperspeedError::ServerStopped => write!(f, "The server stopped accepting connections"),
+            HyperspeedError::Replay(e) => write!(f, "{}", e),
+            HyperspeedError::Snapshot(e) => write!(f, "{}", e),
+            HyperspeedError::Room(e) => write!(f, "{}", e),
+            HyperspeedError::Blueprint(e) => write!(f, "{}", e),
+            HyperspeedError::Script(e) => write!(f, "{}", e),
+            HyperspeedError::Io(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for HyperspeedError {}
+
+impl From<BindError> for HyperspeedError {
+    fn from(e: BindError) -> Self {
+        HyperspeedError::Bind(e)
+    }
+}
+
+impl From<ReplayError> for HyperspeedError {
+    fn from(e: ReplayError) -> Self {
+        HyperspeedError::Replay(e)
+    }
+}
+
+impl From<SnapshotError> for HyperspeedError {
+    fn from(e: SnapshotError) -> Self {
+        HyperspeedError::Snapshot(e)
+    }
+}
+
+impl From<RoomError> for HyperspeedError {
+    fn from(e: RoomError) -> Self {
+        HyperspeedError::Room(e)
+    }
+}
+
+impl From<BlueprintError> for HyperspeedError {
+    fn from(e: BlueprintError) -> Self {
+        HyperspeedError::Blueprint(e)
+    }
+}
+
+impl From<ScriptError> for HyperspeedError {
+    fn from(e: ScriptError) -> Self {
+        HyperspeedError::Script(e)
+    }
+}
+
+impl From<io::Error> for HyperspeedError {
+    fn from(e: io::Error) -> Self {
+        HyperspeedError::Io(e)
+    }
+}
@@ -1,11 +1,9 @@
 #![allow(dead_code)]
-#![feature(try_from)]
+#![allow(clippy::new_ret_no_self, clippy::result_large_err, clippy::large_enum_variant, clippy::type_complexity, clippy::too_many_arguments)]
 #![feature(trait_alias)]
 
 extern crate specs;
-#[macro_use]
 extern crate shred_derive;
-#[macro_use]
 extern crate specs_derive;
 #[macro_use]
 extern crate cascade;
@@ -13,11 +11,31 @@ extern crate futures;
 extern crate tokio;
 extern crate bytes;
 #[macro_use]
+extern crate serde;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+extern crate ron;
+extern crate toml;
+extern crate sha1;
+extern crate base64;
+extern crate rustls;
+extern crate tokio_rustls;
+#[cfg(test)]
+extern crate rcgen;
+#[cfg(test)]
+extern crate webpki;
+#[macro_use]
 pub extern crate cpython;
 
+#[macro_use]
+pub mod utils;
+pub mod core;
+pub mod components;
+pub mod systems;
 pub mod network;
 pub mod ecs;
 pub mod script;
+pub mod error;
 
 #[cfg(test)]
 mod tests;
\ No newline at end of file
@@ -1,17 +1,33 @@
+mod udp;
+
+pub use self::udp::{Channel, Packet, UdpConnection, SendError, MAX_PACKET_SIZE, MAX_MESSAGE_SIZE};
+
 use std::marker::PhantomData;
-use tokio::net::{TcpListener, TcpStream};
-use std::net::{SocketAddr, Shutdown};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use std::net::{IpAddr, SocketAddr};
 use std::collections::HashMap;
 use tokio::prelude::*;
-use tokio::prelude::stream::ForEach;
-use bytes::{Bytes, BytesMut};
-use futures::sync::mpsc::{channel, Receiver, Sender, unbounded, UnboundedReceiver, UnboundedSender};
+use bytes::BytesMut;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
 use std::fmt::Debug;
 use std::thread;
 use std::thread::JoinHandle;
 use crate::ecs::GameUpdate;
 use std::convert::TryFrom;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::timer::{Interval, Timeout};
+use crate::utils::websocket::{self, FrameAssembler, FrameEvent, Handshake, Opcode};
+use crate::utils::bind::{BindAddress, BindError};
+use std::ops::RangeInclusive;
+use crate::utils::tls::TlsConfig;
+use crate::utils::heartbeat::{Heartbeat, HeartbeatConfig, HeartbeatReply, HANDSHAKE_TIMEOUT};
+use crate::utils::ratelimit::{RateLimitAction, RateLimitConfig, RateLimitViolation, RateLimiter};
+use crate::utils::queue::{send_queue, QueueReceiver, QueueSender, SendQueueConfig};
+use crate::core::{LinkStats, OutboundMessage, ShutdownReport};
 
 /// A wrapper for a binary packet sent to or from the server socket.
 pub struct ClientMessage {
@@ -20,7 +36,7 @@ pub struct ClientMessage {
 
 /// A wrapper type that maps clients to their address and the channel
 /// to communicate with them.
-pub type ClientMap<M> = HashMap<ClientID, (SocketAddr, UnboundedSender<M>)>;
+pub type ClientMap<M> = HashMap<ClientID, (SocketAddr, QueueSender<M>)>;
 
 /// A wrapper type that puts a client map in an arc mutex pointer so
 /// it can be accessed and modified by multiple threads.
@@ -32,11 +48,35 @@ pub type ClientID = u32;
 /// A set of traits required for any message type.
 pub trait Message = 'static + Send + TryFrom<ClientMessage, Error=()> + Into<ClientMessage> + Debug;
 
+/// A client's TCP connection, with or without TLS.
+pub enum ClientSocket {
+    Plain(TcpStream),
+    Tls(tokio_rustls::server::TlsStream<TcpStream>)
+}
+
 /// A TCP socket that serializes and deserializes messages automatically
 pub struct MessageSocket<M: Message> {
     _pd: PhantomData<M>,
-    socket: TcpStream,
-    buffer: BytesMut
+    socket: ClientSocket,
+    buffer: BytesMut,
+    /// Set for WebSocket clients, where each message is one frame.
+    websocket: Option<WebSocketState>,
+    // Bytes waiting for the socket to take them: messages and pings, and for WebSockets also
+    // replies and the upgrade response.
+    outgoing: Vec<u8>,
+    // Set for plain and TLS clients once `Client::with_heartbeat` is called. WebSockets use
+    // ping frames instead.
+    heartbeat: Option<Heartbeat>,
+    opened: Instant,
+    last_heard: Instant,
+    bytes_read: u64
+}
+
+struct WebSocketState {
+    upgraded: bool,
+    incoming: Vec<u8>,
+    assembler: FrameAssembler,
+    ping: Interval
 }
 
 /// A client future that processes a client connection and
@@ -45,8 +85,23 @@ pub struct Client<M: Message> {
     socket: MessageSocket<M>,
     id: ClientID,
     server_tx: UnboundedSender<M>,
-    server_rx: UnboundedReceiver<M>,
+    server_rx: QueueReceiver<M>,
     shared_client_map: SharedClientMap<M>,
+    idle_timeout: Option<Duration>,
+    idle_check: Interval,
+    limiter: Option<RateLimiter>,
+    // How many of the socket's bytes have been charged to the rate limiter.
+    bytes_counted: u64,
+    // Set once the server has finished the send queue. The client leaves after a last flush.
+    closing: bool,
+    events: Option<mpsc::Sender<ClientEvent>>
+}
+
+/// Something the server noticed about a client. See `ServerHandle::events`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientEvent {
+    /// The client sent more than its rate limit allows.
+    RateLimited { id: ClientID, violation: RateLimitViolation }
 }
 
 /// A map of clients to messages from that client.
@@ -56,35 +111,140 @@ pub struct ClientInput<M: Message> {
 }
 
 /// A communication channel with the server.
-pub struct ServerHandle<M: Message>(pub UnboundedReceiver<ClientInput<M>>, pub UnboundedSender<GameUpdate>, pub JoinHandle<()>);
+pub struct ServerHandle<M: Message>(pub UnboundedReceiver<ClientInput<M>>, pub UnboundedSender<GameUpdate>, pub JoinHandle<()>, ServerControl<M>);
+
+/// What `ServerHandle::shutdown` needs to stop the server.
+struct ServerControl<M: Message> {
+    clients: SharedClientMap<M>,
+    stop_accepting: oneshot::Sender<()>,
+    stopping: Arc<AtomicBool>,
+    // Hears from the server thread once the runtime has nothing left to run.
+    finished: mpsc::Receiver<()>,
+    events: mpsc::Receiver<ClientEvent>,
+    addresses: Vec<SocketAddr>,
+    websocket_addresses: Vec<SocketAddr>
+}
+
+/// The protocol a server speaks to its clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    /// UDP with a handshake, acks and per-message delivery channels. See `Channel`.
+    Udp
+}
 
 /// A helper class for server generation
 pub struct ServerBuilder<M: Message> {
     _pd: PhantomData<M>,
     thread_cap: u16,
-    addr: &'static str,
-    port: u16
+    addr: String,
+    ports: RangeInclusive<u16>,
+    listeners: Vec<BindAddress>,
+    websocket_port: Option<u16>,
+    websocket_listeners: Vec<BindAddress>,
+    settings: ClientSettings<M>,
+    transport: Transport,
+    channel_of: fn(&M) -> Channel
+}
+
+/// How every client of a server is set up.
+pub(crate) struct ClientSettings<M> {
+    tls: Option<TlsConfig>,
+    heartbeat: HeartbeatConfig,
+    rate_limit: Option<RateLimitConfig>,
+    send_queue: SendQueueConfig,
+    supersedes: fn(&M) -> bool,
+    // Set when the server shuts down, so no more clients are started.
+    stopping: Arc<AtomicBool>,
+    // Set once the server runs, since the handle gets the other end.
+    events: Option<mpsc::Sender<ClientEvent>>
+}
+
+enum Listener {
+    Tcp(Vec<TcpListener>),
+    Udp(Vec<UdpSocket>)
 }
 
 /// A struct that handles multi-client networking.
 pub struct Server<M: Message> {
     thread_cap: u16,
-    address: SocketAddr,
+    addresses: Vec<SocketAddr>,
+    websocket_addresses: Vec<SocketAddr>,
 
-    listener: TcpListener,
+    listener: Listener,
+    websocket_listeners: Vec<TcpListener>,
+    settings: ClientSettings<M>,
+    channel_of: fn(&M) -> Channel,
     clients: SharedClientMap<M>,
     messages: UnboundedReceiver<M>
 }
 
-static mut CLIENT_ID_COUNTER: ClientID = 0;
+// Several servers can hand out ids at once, each from its own runtime.
+static CLIENT_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
 
-fn get_id(socket: &mut TcpStream) -> ClientID {
-    // This only gets called from one thread, so there won't be any data racing.
-    // In other words this (shouldn't) ever panic.
-    unsafe {
-        CLIENT_ID_COUNTER += 1;
-        CLIENT_ID_COUNTER - 1
-    }
+fn get_id() -> ClientID {
+    CLIENT_ID_COUNTER.fetch_add(1, Ordering::SeqCst)
+}
+
+/// The JSON of a message from the server itself, such as a heartbeat ping or a rate limit warning.
+fn encode_notice<T: OutboundMessage>(notice: &T) -> Option<Vec<u8>> {
+    crate::core::ClientMessage::from(notice).and_then(|notice| serde_json::to_vec(&notice)).ok()
+}
+
+/// Starts a `Client` for every socket that connects. The flag says whether it is a WebSocket.
+/// With TLS, the client is only started once its handshake is done.
+fn accept_clients<M, S>(sockets: S, settings: ClientSettings<M>, shared_client_map: SharedClientMap<M>, server_tx: UnboundedSender<M>) -> impl Future<Item=(), Error=()>
+where
+    M: Message,
+    S: Stream<Item=(TcpStream, bool), Error=io::Error> {
+    // A connection that fails is dropped on its own; it never stops the others being accepted.
+    let sockets = sockets
+        .then(|accepted| Ok::<_, ()>(accepted.map_err(|e| println!("Server could not accept a connection: {}", e)).ok()))
+        .filter_map(|socket| socket);
+    sockets.for_each(move |(socket, websocket)| {
+        let id = get_id();
+        let address = match socket.local_addr() {
+            Ok(address) => address,
+            Err(e) => {
+                println!("Server dropped a connection: {}", e);
+                return Ok(());
+            }
+        };
+        let shared_client_map = shared_client_map.clone();
+        let server_tx = server_tx.clone();
+        let (heartbeat, rate_limit, events) = (settings.heartbeat, settings.rate_limit, settings.events.clone());
+        let (tx, rx) = settings.queue();
+        let stopping = settings.stopping.clone();
+        let start = move |socket: ClientSocket| {
+            {
+                // Checked under the lock, so a shutdown either sees this client or stops it here.
+                let mut shared_client_map = shared_client_map.lock().unwrap();
+                if stopping.load(Ordering::SeqCst) {
+                    return;
+                }
+                shared_client_map.insert(id, (address, tx));
+            }
+            let socket = if websocket { MessageSocket::websocket(socket) } else { MessageSocket::new(socket) };
+            tokio::spawn(Client::from_socket(socket, id, shared_client_map, rx, server_tx)
+                .with_heartbeat(heartbeat)
+                .with_rate_limit(rate_limit)
+                .with_events(events));
+        };
+        match settings.tls {
+            Some(ref tls) => {
+                // A client that stalls its handshake would otherwise hold the socket forever.
+                tokio::spawn(Timeout::new(tls.accept_async(socket), HANDSHAKE_TIMEOUT)
+                    .map(|socket| start(ClientSocket::Tls(socket)))
+                    .map_err(|e| if e.is_elapsed() {
+                        println!("TLS handshake timed out.")
+                    } else {
+                        println!("TLS handshake failed: {}", e)
+                    }));
+            },
+            None => start(ClientSocket::Plain(socket))
+        }
+        Ok(())
+    })
 }
 
 impl<M: Message> ServerBuilder<M> {
@@ -92,23 +252,115 @@ impl<M: Message> ServerBuilder<M> {
         self.thread_cap = thread_max;
         self
     }
-    pub fn address(mut self, address: &'static str) -> ServerBuilder<M> {
-        self.addr = address;
+    /// The IP address to listen on, IPv4 or IPv6, such as `"127.0.0.1"` or `"::"`.
+    pub fn address<S: Into<String>>(mut self, address: S) -> ServerBuilder<M> {
+        self.addr = address.into();
+        self
+    }
+    pub fn port(self, port: u16) -> ServerBuilder<M> {
+        self.port_range(port..=port)
+    }
+    /// Listens on the first free port in `ports`, such as `4000..=4010`, which `local_addrs`
+    /// reports.
+    pub fn port_range(mut self, ports: RangeInclusive<u16>) -> ServerBuilder<M> {
+        self.ports = ports;
+        self
+    }
+    /// Listens on `address` instead of `address` and `port`. Call it again to listen on several
+    /// addresses, such as an IPv4 and an IPv6 one. Port 0 picks a free port, which
+    /// `local_addrs` reports.
+    pub fn listen_on(mut self, address: SocketAddr) -> ServerBuilder<M> {
+        self.listeners.push(address.into());
+        self
+    }
+    /// Like `listen_on`, but takes the first free port in `ports`.
+    pub fn listen_on_range(mut self, ip: IpAddr, ports: RangeInclusive<u16>) -> ServerBuilder<M> {
+        self.listeners.push(BindAddress::new(ip, ports));
+        self
+    }
+    /// Also accepts WebSocket clients on `port`. Their messages are the payloads of binary or
+    /// text frames, and are handled just like messages from other clients.
+    pub fn websocket_port(mut self, port: u16) -> ServerBuilder<M> {
+        self.websocket_port = Some(port);
+        self
+    }
+    /// Also accepts WebSocket clients on `address`. This can be called more than once.
+    pub fn websocket_on(mut self, address: SocketAddr) -> ServerBuilder<M> {
+        self.websocket_listeners.push(address.into());
+        self
+    }
+    /// Only accepts TLS connections on the TCP and WebSocket listeners. UDP isn't encrypted.
+    /// Keep a clone of `tls` to reload the certificate while the server runs.
+    pub fn tls(mut self, tls: TlsConfig) -> ServerBuilder<M> {
+        self.settings.tls = Some(tls);
+        self
+    }
+    /// How often clients are pinged, and how long they can stay quiet before they are
+    /// disconnected. See `Client::with_heartbeat`. No round trip times are reported on this
+    /// server. UDP connections have their own keepalive.
+    pub fn heartbeat(mut self, heartbeat: HeartbeatConfig) -> ServerBuilder<M> {
+        self.settings.heartbeat = heartbeat;
+        self
+    }
+    /// Changes the limits on how much each client can send. Messages over the limit are dropped;
+    /// clients that keep going over it are disconnected.
+    pub fn rate_limit(mut self, config: RateLimitConfig) -> ServerBuilder<M> {
+        self.settings.rate_limit = Some(config);
         self
     }
-    pub fn port(mut self, port: u16) -> ServerBuilder<M> {
-        self.port = port;
+    pub fn without_rate_limit(mut self) -> ServerBuilder<M> {
+        self.settings.rate_limit = None;
         self
     }
-    pub fn build(self) -> Server<M> {
-        let socket_addr = format!("{}:{}", self.addr, self.port).parse().unwrap();
-        Server {
+    /// Caps how many messages can wait to be sent to each client, and picks what happens to
+    /// clients that fall behind.
+    pub fn send_queue(mut self, config: SendQueueConfig) -> ServerBuilder<M> {
+        self.settings.send_queue = config;
+        self
+    }
+    /// Picks out messages that newer ones of the same kind replace, such as state updates. Full
+    /// send queues drop these first.
+    pub fn supersedes(mut self, supersedes: fn(&M) -> bool) -> ServerBuilder<M> {
+        self.settings.supersedes = supersedes;
+        self
+    }
+    pub fn transport(mut self, transport: Transport) -> ServerBuilder<M> {
+        self.transport = transport;
+        self
+    }
+    /// Picks the UDP channel each message is sent on. By default every message is reliable and
+    /// ordered. TCP ignores this, since everything it sends is reliable and ordered anyway.
+    pub fn channel_of(mut self, channel_of: fn(&M) -> Channel) -> ServerBuilder<M> {
+        self.channel_of = channel_of;
+        self
+    }
+    /// Binds every listener. Fails if the address isn't an IP address, or if any listener can't
+    /// be bound.
+    pub fn build(self) -> Result<Server<M>, BindError> {
+        let ip: IpAddr = self.addr.parse().map_err(|_| BindError::InvalidAddress(self.addr.clone()))?;
+        let addresses = if self.listeners.is_empty() { vec![BindAddress::new(ip, self.ports)] } else { self.listeners };
+        let mut websocket_addresses = self.websocket_listeners;
+        websocket_addresses.extend(self.websocket_port.map(|port| SocketAddr::new(ip, port).into()));
+
+        let bind_tcp = |address: &BindAddress| address.bind_first(TcpListener::bind);
+        let listener = match self.transport {
+            Transport::Tcp => Listener::Tcp(addresses.iter().map(bind_tcp).collect::<Result<_, _>>()?),
+            Transport::Udp => Listener::Udp(addresses.iter()
+                .map(|address| address.bind_first(UdpSocket::bind))
+                .collect::<Result<_, _>>()?)
+        };
+        let websocket_listeners: Vec<TcpListener> = websocket_addresses.iter().map(bind_tcp).collect::<Result<_, _>>()?;
+        Ok(Server {
             thread_cap: self.thread_cap,
-            address: socket_addr,
-            listener: TcpListener::bind(&socket_addr).unwrap(),
+            addresses: listener.local_addrs(),
+            websocket_addresses: websocket_listeners.iter().filter_map(|l| l.local_addr().ok()).collect(),
+            listener,
+            websocket_listeners,
+            settings: self.settings,
+            channel_of: self.channel_of,
             clients: Arc::new(Mutex::new(HashMap::new())),
             messages: unbounded().1,
-        }
+        })
     }
 }
 
@@ -118,50 +370,369 @@ impl<M: Message> Server<M> {
         ServerBuilder {
             _pd: PhantomData,
             thread_cap: 5,
-            addr: "0.0.0.0",
-            port: 4343,
+            addr: "0.0.0.0".to_string(),
+            ports: 4343..=4343,
+            listeners: vec![],
+            websocket_port: None,
+            websocket_listeners: vec![],
+            settings: ClientSettings {
+                tls: None,
+                heartbeat: HeartbeatConfig::default(),
+                rate_limit: Some(RateLimitConfig::default()),
+                send_queue: SendQueueConfig::default(),
+                supersedes: |_| false,
+                stopping: Arc::new(AtomicBool::new(false)),
+                events: None
+            },
+            transport: Transport::Tcp,
+            channel_of: |_| Channel::ReliableOrdered
         }
     }
 
     /// Starts a new thread with Tokio running the server processes. Returns a
     /// communication interface with the server, `ServerHandle`
-    pub fn run(mut self) -> ServerHandle<M> {
-        let (client_input_tx, client_input_rx) = unbounded::<ClientInput<M>>();
+    pub fn run(self) -> ServerHandle<M> {
+        let Server { listener, websocket_listeners, mut settings, channel_of, clients, addresses, websocket_addresses, .. } = self;
+        let (events_tx, events_rx) = mpsc::channel();
+        settings.events = Some(events_tx);
+        let (_client_input_tx, client_input_rx) = unbounded::<ClientInput<M>>();
         let (server_tx, server_rx) = unbounded::<M>();
-        let (game_tx, game_rx) = unbounded::<GameUpdate>();
-        let shared_client_map = self.clients.clone();
+        let (game_tx, _game_rx) = unbounded::<GameUpdate>();
+        let shared_client_map = clients.clone();
         let hc_client_map = shared_client_map.clone();
         thread::spawn(|| Server::handle_channels(server_rx, hc_client_map));
-        let server_process = self.listener.incoming().for_each(move |mut socket| {
-            let id = get_id(&mut socket);
-            let (tx, rx) = unbounded();
-            {
-                let mut shared_client_map = shared_client_map.lock().unwrap();
-                shared_client_map.insert(id, (socket.local_addr().unwrap(), tx));
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        // Dropping the handle without shutting down leaves the server running.
+        let stop_accepting = stop_rx.or_else(|_| future::empty::<(), ()>());
+        let stopping = settings.stopping.clone();
+        // Every listener's sockets are merged into one stream.
+        let mut websockets: Box<dyn Stream<Item=(TcpStream, bool), Error=io::Error> + Send> = Box::new(stream::empty());
+        for listener in websocket_listeners {
+            websockets = Box::new(websockets.select(listener.incoming().map(|socket| (socket, true))));
+        }
+        let server_process: Box<dyn Future<Item=(), Error=()> + Send> = match listener {
+            Listener::Tcp(listeners) => {
+                let mut sockets = websockets;
+                for listener in listeners {
+                    sockets = Box::new(sockets.select(listener.incoming().map(|socket| (socket, false))));
+                }
+                Box::new(accept_clients(sockets, settings, shared_client_map.clone(), server_tx)
+                    .select(stop_accepting)
+                    .map(|_| ())
+                    .map_err(|_| ()))
+            },
+            Listener::Udp(sockets) => {
+                let udp: Vec<_> = sockets.into_iter()
+                    .map(|socket| udp::UdpServer::new(socket, shared_client_map.clone(), server_tx.clone(), channel_of, settings.clone()))
+                    .collect();
+                let websockets = accept_clients(websockets, settings, shared_client_map.clone(), server_tx)
+                    .select(stop_accepting)
+                    .map(|_| ())
+                    .map_err(|_| ());
+                Box::new(future::join_all(udp).join(websockets).map(|_| ()))
             }
-            tokio::spawn(Client::new(socket, id, shared_client_map.clone(),rx, server_tx.clone()));
-             Ok(())
-        }).map_err(|e| ()); //TODO: Do something with error
-        let handle = thread::spawn(move || tokio::run(server_process));
-        ServerHandle(client_input_rx, game_tx, handle)
+        };
+        let (finished_tx, finished_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            tokio::run(server_process);
+            let _ = finished_tx.send(());
+        });
+        ServerHandle(client_input_rx, game_tx, handle, ServerControl {
+            clients: shared_client_map,
+            stop_accepting: stop_tx,
+            stopping,
+            finished: finished_rx,
+            events: events_rx,
+            addresses,
+            websocket_addresses
+        })
+    }
+
+    /// Where the server is listening. When port 0 was asked for, this has the port that was
+    /// picked.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    /// Where the server is listening for WebSocket clients.
+    pub fn websocket_addrs(&self) -> &[SocketAddr] {
+        &self.websocket_addresses
     }
 
-    fn handle_channels(server_rx: UnboundedReceiver<M>, client_map: SharedClientMap<M>) {
+    fn handle_channels(_server_rx: UnboundedReceiver<M>, _client_map: SharedClientMap<M>) {
         loop {
             thread::park()
         }
     }
 }
 
+impl Listener {
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        match self {
+            Listener::Tcp(listeners) => listeners.iter().filter_map(|l| l.local_addr().ok()).collect(),
+            Listener::Udp(sockets) => sockets.iter().filter_map(|s| s.local_addr().ok()).collect()
+        }
+    }
+}
+
+impl<M> ClientSettings<M> {
+    /// Makes a send queue for a new client.
+    pub(crate) fn queue(&self) -> (QueueSender<M>, QueueReceiver<M>) {
+        send_queue(self.send_queue, self.supersedes)
+    }
+}
+
+impl<M> Clone for ClientSettings<M> {
+    fn clone(&self) -> Self {
+        ClientSettings {
+            tls: self.tls.clone(),
+            heartbeat: self.heartbeat,
+            rate_limit: self.rate_limit,
+            send_queue: self.send_queue,
+            supersedes: self.supersedes,
+            stopping: self.stopping.clone(),
+            events: self.events.clone()
+        }
+    }
+}
+
+impl<M: Message> ServerHandle<M> {
+    /// Where the server is listening, as `Server::local_addrs` reported.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.3.addresses
+    }
+
+    pub fn websocket_addrs(&self) -> &[SocketAddr] {
+        &self.3.websocket_addresses
+    }
+
+    /// What the server has noticed about its clients since this was last called.
+    pub fn events(&self) -> Vec<ClientEvent> {
+        self.3.events.try_iter().collect()
+    }
+
+    /// Stops the server. No more clients are accepted, and every client is sent `goodbye()`
+    /// after what is already queued for it. Clients are disconnected once that has gone out.
+    /// Waits up to `timeout` for the server thread to finish.
+    pub fn shutdown<G: Fn() -> M>(self, goodbye: G, timeout: Duration) -> ShutdownReport {
+        let ServerHandle(_, _, handle, control) = self;
+        control.stopping.store(true, Ordering::SeqCst);
+        let _ = control.stop_accepting.send(());
+        let clients = {
+            let clients = control.clients.lock().unwrap();
+            for (_, queue) in clients.values() {
+                let _ = queue.send(goodbye());
+                queue.finish();
+            }
+            clients.len()
+        };
+        match control.finished.recv_timeout(timeout) {
+            Ok(()) => {
+                let _ = handle.join();
+                ShutdownReport { clients, unfinished: 0 }
+            },
+            // Whatever is left keeps running on the server thread, which is detached. Even with
+            // no clients left, the runtime itself hasn't finished.
+            Err(_) => ShutdownReport { clients, unfinished: control.clients.lock().unwrap().len().max(1) }
+        }
+    }
+}
+
+impl From<TcpStream> for ClientSocket {
+    fn from(socket: TcpStream) -> ClientSocket {
+        ClientSocket::Plain(socket)
+    }
+}
+
+impl Read for ClientSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientSocket::Plain(socket) => socket.read(buf),
+            ClientSocket::Tls(socket) => socket.read(buf)
+        }
+    }
+}
+
+impl Write for ClientSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientSocket::Plain(socket) => socket.write(buf),
+            ClientSocket::Tls(socket) => socket.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientSocket::Plain(socket) => socket.flush(),
+            ClientSocket::Tls(socket) => socket.flush()
+        }
+    }
+}
+
+impl AsyncRead for ClientSocket {}
+
+impl AsyncWrite for ClientSocket {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            ClientSocket::Plain(socket) => AsyncWrite::shutdown(socket),
+            ClientSocket::Tls(socket) => socket.shutdown()
+        }
+    }
+}
+
 impl<M: Message> MessageSocket<M> {
-    pub fn new(socket: TcpStream) -> MessageSocket<M> {
+    pub fn new<S: Into<ClientSocket>>(socket: S) -> MessageSocket<M> {
         const MSG_SOCKET_BUF_CAP: usize = 4096;
         MessageSocket {
             _pd: PhantomData,
-            socket: socket,
-            buffer: BytesMut::with_capacity(MSG_SOCKET_BUF_CAP)
+            socket: socket.into(),
+            buffer: BytesMut::with_capacity(MSG_SOCKET_BUF_CAP),
+            websocket: None,
+            outgoing: vec![],
+            heartbeat: None,
+            opened: Instant::now(),
+            last_heard: Instant::now(),
+            bytes_read: 0
+        }
+    }
+
+    /// A socket for a WebSocket client. The HTTP upgrade is answered before any messages are read.
+    pub fn websocket<S: Into<ClientSocket>>(socket: S) -> MessageSocket<M> {
+        const PING_INTERVAL: Duration = Duration::from_secs(20);
+        MessageSocket {
+            websocket: Some(WebSocketState {
+                upgraded: false,
+                incoming: vec![],
+                assembler: FrameAssembler::new(),
+                ping: Interval::new(Instant::now() + PING_INTERVAL, PING_INTERVAL)
+            }),
+            ..MessageSocket::new(socket)
+        }
+    }
+
+    fn poll_websocket(&mut self) -> Poll<Option<M>, io::Error> {
+        let ws = self.websocket.as_mut().unwrap();
+        while let Ok(Async::Ready(Some(_))) = ws.ping.poll() {
+            if ws.upgraded {
+                self.outgoing.extend(websocket::encode_frame(Opcode::Ping, &[]));
+            }
+        }
+        let mut chunk = [0; 1024];
+        loop {
+            if !ws.upgraded {
+                match websocket::parse_handshake(&ws.incoming) {
+                    Handshake::Accept { response, consumed } => {
+                        self.outgoing.extend(response);
+                        ws.incoming.drain(..consumed);
+                        ws.upgraded = true;
+                    },
+                    Handshake::Reject(reason) => return Err(io::Error::new(io::ErrorKind::InvalidData, reason)),
+                    Handshake::Incomplete => {}
+                }
+            }
+            while ws.upgraded {
+                let (frame, used) = match websocket::decode_frame(&ws.incoming)? {
+                    Some(decoded) => decoded,
+                    None => break
+                };
+                ws.incoming.drain(..used);
+                match ws.assembler.handle(frame)? {
+                    FrameEvent::Message(payload) => {
+                        if let Ok(msg) = M::try_from(ClientMessage { bytes: BytesMut::from(payload) }) {
+                            return Ok(Async::Ready(Some(msg)));
+                        }
+                    },
+                    FrameEvent::Reply(reply) => self.outgoing.extend(reply),
+                    FrameEvent::Close => return Ok(Async::Ready(None)),
+                    FrameEvent::Pong | FrameEvent::Nothing => {}
+                }
+            }
+            match self.socket.poll_read(&mut chunk)? {
+                Async::Ready(0) => return Ok(Async::Ready(None)),
+                Async::Ready(read) => {
+                    ws.incoming.extend_from_slice(&chunk[..read]);
+                    self.last_heard = Instant::now();
+                    self.bytes_read += read as u64;
+                },
+                Async::NotReady => return Ok(Async::NotReady)
+            }
+        }
+    }
+
+    /// Whether the client is still in its WebSocket upgrade.
+    pub fn is_handshaking(&self) -> bool {
+        self.websocket.as_ref().is_some_and(|ws| !ws.upgraded)
+    }
+
+    /// Queues a heartbeat ping for a plain or TLS client if one is due.
+    fn poll_heartbeat(&mut self, now: Instant) {
+        if let Some(ping) = self.heartbeat.as_mut().and_then(|heartbeat| heartbeat.poll(now)) {
+            self.queue_notice(&ping);
+        }
+    }
+
+    /// Queues a message from the server itself, as `{"tag": ..., "data": ...}` like the legacy
+    /// server sends it. WebSocket clients get it as a text frame, and others as a line of its
+    /// own. Messages are only ever queued whole, so it always goes out between two of them.
+    fn queue_notice<T: OutboundMessage>(&mut self, notice: &T) {
+        let mut bytes = match encode_notice(notice) {
+            Some(bytes) => bytes,
+            None => return
+        };
+        match self.websocket {
+            Some(ref ws) if ws.upgraded => self.outgoing.extend(websocket::encode_frame(Opcode::Text, &bytes)),
+            Some(_) => {},
+            None => {
+                bytes.push(b'\n');
+                self.outgoing.extend(bytes);
+            }
         }
     }
+
+    /// Takes a whole `{"heartbeat": id}` line off the front of the buffer, if that is what it
+    /// holds.
+    fn take_heartbeat_reply(&mut self) -> bool {
+        const REPLY_PREFIX: &[u8] = b"{\"heartbeat\"";
+        let heartbeat = match self.heartbeat.as_mut() {
+            Some(heartbeat) if self.buffer.starts_with(REPLY_PREFIX) => heartbeat,
+            _ => return false
+        };
+        let end = match self.buffer.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None => return false
+        };
+        let line = self.buffer.split_to(end + 1);
+        if let Ok(reply) = serde_json::from_slice::<HeartbeatReply>(&line[..end]) {
+            heartbeat.answered(&reply, Instant::now());
+        }
+        true
+    }
+
+    /// When the client last sent anything, including WebSocket pongs and heartbeat replies.
+    pub fn last_heard(&self) -> Instant {
+        self.last_heard
+    }
+
+    /// How many bytes have been read from the client so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Whether there is anything still to be written to the client.
+    pub fn has_pending_output(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Writes as much of the pending output as the socket takes.
+    fn flush_outgoing(&mut self) -> Poll<(), io::Error> {
+        while !self.outgoing.is_empty() {
+            match self.socket.poll_write(&self.outgoing)? {
+                Async::Ready(written) => { self.outgoing.drain(..written); },
+                Async::NotReady => return Ok(Async::NotReady)
+            }
+        }
+        self.socket.poll_flush()
+    }
 }
 
 impl<M: Message> Stream for MessageSocket<M> {
@@ -169,25 +740,31 @@ impl<M: Message> Stream for MessageSocket<M> {
     type Error = std::io::Error;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        // Attempt to read from the socket
-        match self.socket.poll_read(&mut self.buffer) {
-            Ok(Async::NotReady) => Ok(Async::NotReady),
-            Ok(Async::Ready(_)) => {
-                // Attempt to convert to message
-                let buffer = self.buffer.clone();
-                let client_m = ClientMessage { bytes: buffer };
-                match M::try_from(client_m) {
-                    Ok(msg) => {
-                        // Flush buffer
-                        self.buffer.clear();
-                        Ok(Async::Ready(Some(msg)))
-                    },
-                    Err(_) => {
-                        Ok(Async::Ready(None))
-                    }
+        if self.websocket.is_some() {
+            return self.poll_websocket();
+        }
+        // A message can arrive over several reads, so the buffer is kept until it converts.
+        const MAX_BUFFERED: usize = 64 * 1024;
+        loop {
+            while self.take_heartbeat_reply() {}
+            if !self.buffer.is_empty() {
+                if let Ok(msg) = M::try_from(ClientMessage { bytes: self.buffer.clone() }) {
+                    self.buffer.clear();
+                    return Ok(Async::Ready(Some(msg)));
                 }
-            },
-            Err(e) => Err(e)
+                if self.buffer.len() >= MAX_BUFFERED {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+                }
+            }
+            self.buffer.reserve(1024);
+            match AsyncRead::read_buf(&mut self.socket, &mut self.buffer)? {
+                Async::Ready(0) => return Ok(Async::Ready(None)),
+                Async::Ready(read) => {
+                    self.last_heard = Instant::now();
+                    self.bytes_read += read as u64;
+                },
+                Async::NotReady => return Ok(Async::NotReady)
+            }
         }
     }
 }
@@ -198,32 +775,70 @@ impl<M: Message> Sink for MessageSocket<M> {
 
     fn start_send(&mut self, item: Self::SinkItem) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
         // Extract bytes from ClientMessage
-        let ClientMessage { bytes: bytes } = M::into(item);
-        // Begin sending over self.socket
-        self.socket.write(bytes.as_ref());
+        let ClientMessage { bytes } = M::into(item);
+        if self.websocket.is_some() {
+            self.outgoing.extend(websocket::encode_frame(Opcode::Binary, &bytes));
+        } else {
+            // The socket may not take it all at once, so it is written by `poll_complete`.
+            self.outgoing.extend_from_slice(&bytes);
+        }
         Ok(AsyncSink::Ready)
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.socket.flush()?;
-        Ok(Async::Ready(()))
+        self.flush_outgoing()
     }
 
     fn close(&mut self) -> Poll<(), Self::SinkError> {
-        Ok(Async::Ready(self.socket.shutdown(Shutdown::Write)?))
+        self.socket.shutdown()
     }
 }
 
 impl<M: Message> Client<M> {
-    pub fn new(socket: TcpStream, id: ClientID, shared_client_map: SharedClientMap<M>, server_rx: UnboundedReceiver<M>, server_tx: UnboundedSender<M>) -> Client<M> {
+    pub fn new(socket: TcpStream, id: ClientID, shared_client_map: SharedClientMap<M>, server_rx: QueueReceiver<M>, server_tx: UnboundedSender<M>) -> Client<M> {
+        Client::from_socket(MessageSocket::new(socket), id, shared_client_map, server_rx, server_tx)
+    }
+
+    pub fn from_socket(socket: MessageSocket<M>, id: ClientID, shared_client_map: SharedClientMap<M>, server_rx: QueueReceiver<M>, server_tx: UnboundedSender<M>) -> Client<M> {
         Client {
-            socket: MessageSocket::new(socket),
+            socket,
             id,
             server_rx,
             server_tx,
-            shared_client_map
+            shared_client_map,
+            idle_timeout: None,
+            idle_check: Interval::new_interval(Duration::from_secs(1)),
+            limiter: None,
+            bytes_counted: 0,
+            closing: false,
+            events: None
         }
     }
+
+    /// Limits what the client can send. Without one, it can send as much as it likes.
+    pub fn with_rate_limit(mut self, config: Option<RateLimitConfig>) -> Self {
+        self.limiter = config.map(|config| RateLimiter::new(config, Instant::now()));
+        self
+    }
+
+    /// Reports what happens to the client, such as going over its rate limit, on `events`.
+    pub fn with_events(mut self, events: Option<mpsc::Sender<ClientEvent>>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Pings the client every `heartbeat.interval`, and disconnects it once it has been quiet for
+    /// `heartbeat.idle_timeout`. WebSocket clients get ping frames. Plain and TLS clients get the
+    /// legacy server's `heartbeat` line, and answer with `{"heartbeat": id}` on a line of its own.
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatConfig) -> Self {
+        match self.socket.websocket {
+            Some(ref mut ws) => ws.ping = Interval::new(Instant::now() + heartbeat.interval, heartbeat.interval),
+            None => self.socket.heartbeat = Some(Heartbeat::new(heartbeat, LinkStats::default(), Instant::now()))
+        }
+        self.idle_check = Interval::new_interval(heartbeat.interval.min(Duration::from_secs(1)));
+        self.idle_timeout = Some(heartbeat.idle_timeout);
+        self
+    }
 }
 
 impl<M: Message> Future for Client<M> {
@@ -231,34 +846,95 @@ impl<M: Message> Future for Client<M> {
     type Error = ();
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // A client that has gone quiet, or never finishes its upgrade, is finished, which
+        // removes it like any other disconnect.
+        while let Ok(Async::Ready(Some(_))) = self.idle_check.poll() {
+            let now = Instant::now();
+            if self.socket.is_handshaking() && now - self.socket.opened >= HANDSHAKE_TIMEOUT {
+                println!("Client {} didn't finish its WebSocket upgrade in time.", self.id);
+                return Ok(Async::Ready(()));
+            }
+            if let Some(idle_timeout) = self.idle_timeout {
+                if now - self.socket.last_heard() >= idle_timeout {
+                    println!("Client {} timed out.", self.id);
+                    return Ok(Async::Ready(()));
+                }
+            }
+            self.socket.poll_heartbeat(now);
+        }
         // Each client sends, at most, 10 messages per tick.
         const MESSAGE_LIMIT: u32 = 10;
         // First, get messages from the server
         for i in 0..MESSAGE_LIMIT {
             match self.server_rx.poll() {
                 Ok(Async::Ready(Some(msg))) => {
-                    self.socket.start_send(msg);
+                    if self.socket.start_send(msg).is_err() {
+                        return Err(());
+                    }
 
                     if i + 1 == MESSAGE_LIMIT {
                         task::current().notify();
                     }
                 },
+                // The queue ends when the client falls too far behind, or when the server is
+                // done with it.
+                Ok(Async::Ready(None)) => {
+                    if self.server_rx.overflowed() {
+                        println!("Client {} fell too far behind and is being disconnected.", self.id);
+                        return Ok(Async::Ready(()));
+                    }
+                    self.closing = true;
+                    break;
+                },
                 _ => break
             }
         }
 
         match self.socket.poll_complete() {
+            Ok(Async::Ready(())) if self.closing => return Ok(Async::Ready(())),
             Ok(_) => (),
             Err(_) => return Err(()) //TODO: Fix error coercion
         };
+        if self.closing {
+            return Ok(Async::NotReady);
+        }
 
-        while let Ok(Async::Ready(msg)) = self.socket.poll() {
-            if let Some(msg) = msg {
-                match self.server_tx.unbounded_send(msg) {
-                    Ok(_) => (),
-                    Err(e) => println!("{}", e)
+        loop {
+            let msg = match self.socket.poll() {
+                Ok(Async::Ready(Some(msg))) => msg,
+                // The client closed the connection, or it failed.
+                Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break
+            };
+            // Messages can't be told apart from their bytes here, so each one is charged
+            // whatever was read since the last.
+            let bytes = self.socket.bytes_read() - self.bytes_counted;
+            self.bytes_counted = self.socket.bytes_read();
+            if let Some(Err(violation)) = self.limiter.as_mut().map(|l| l.check(bytes as usize, Instant::now())) {
+                if let Some(ref events) = self.events {
+                    let _ = events.send(ClientEvent::RateLimited { id: self.id, violation });
+                }
+                match violation.action {
+                    RateLimitAction::Disconnect => {
+                        println!("Client {} kept going over its rate limit and is being disconnected.", self.id);
+                        // Earlier warnings get one chance to go out before the socket is closed.
+                        let _ = self.socket.poll_complete();
+                        return Ok(Async::Ready(()));
+                    },
+                    RateLimitAction::Warn => self.socket.queue_notice(&violation),
+                    RateLimitAction::Drop => {}
                 }
+                continue;
             }
+            match self.server_tx.unbounded_send(msg) {
+                Ok(_) => (),
+                Err(e) => println!("{}", e)
+            }
+        }
+        // Reading can queue the upgrade response, pongs and rate limit warnings, and heartbeat
+        // pings were queued above, which nothing else would flush.
+        if self.socket.has_pending_output() && self.socket.poll_complete().is_err() {
+            return Err(());
         }
         Ok(Async::NotReady)
     }
@@ -266,7 +942,8 @@ impl<M: Message> Future for Client<M> {
 
 impl<M: Message> Drop for Client<M> {
     fn drop(&mut self) {
-        self.socket.close().unwrap();
+        // The client is gone either way, so a failed close changes nothing.
+        let _ = self.socket.close();
         self.shared_client_map.lock().unwrap().remove(&self.id);
     }
 }
\ No newline at end of file
@@ -0,0 +1,593 @@
+use super::{ClientEvent, ClientID, ClientMessage, ClientSettings, Message, SharedClientMap, encode_notice, get_id};
+use crate::utils::queue::QueueReceiver;
+use crate::utils::ratelimit::{RateLimitAction, RateLimiter};
+use bytes::{Buf, BufMut, Bytes};
+use futures::sync::mpsc::UnboundedSender;
+use std::collections::{HashMap, VecDeque};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+/// Identifies our packets, so stray datagrams sent to the port are ignored.
+const PROTOCOL_ID: u32 = 0x4853_5044;
+/// Packets are kept under the usual internet MTU, so they are never fragmented.
+pub const MAX_PACKET_SIZE: usize = 1200;
+/// The largest message that fits in a packet on its own.
+pub const MAX_MESSAGE_SIZE: usize = MAX_PACKET_SIZE - DATA_HEADER_SIZE - MESSAGE_HEADER_SIZE;
+const DATA_HEADER_SIZE: usize = 4 + 1 + 2 + 2 + 4 + 1;
+const MESSAGE_HEADER_SIZE: usize = 1 + 2 + 2;
+/// Packets older than this many sequence numbers are forgotten, and their messages resent.
+const SENT_PACKET_WINDOW: u16 = 512;
+/// How far ahead of the next message to deliver a reliable message may be. Anything further is
+/// refused rather than buffered, so a peer can't make the receiver hold on to unlimited messages.
+const RECEIVE_WINDOW: u16 = SENT_PACKET_WINDOW;
+
+const KEEP_ALIVE: Duration = Duration::from_millis(1000);
+const RESEND_AFTER: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the server wakes up to resend, keep alive and time out connections.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How a message is delivered over UDP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    /// Sent once. It may be lost, duplicated or arrive out of order.
+    Unreliable,
+    /// Sent once, and dropped if a newer message on the channel has already arrived. Good for
+    /// views, where only the latest one matters.
+    UnreliableSequenced,
+    /// Resent until it is acknowledged, and delivered in the order it was sent. Good for chat
+    /// and RPC.
+    ReliableOrdered
+}
+
+/// The first bytes of a datagram. Clients send `Connect` with a random salt until the server
+/// answers with `Accept` and the same salt; after that both sides send `Data`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Packet {
+    Connect { salt: u64 },
+    Accept { salt: u64 },
+    Data {
+        seq: u16,
+        /// The newest packet received from the other side.
+        ack: u16,
+        /// Bit `n` is set if packet `ack - n - 1` was received too.
+        ack_bits: u32,
+        messages: Vec<(Channel, u16, Bytes)>
+    },
+    Disconnect
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError {
+    TooLarge(usize)
+}
+
+struct PendingReliable {
+    id: u16,
+    payload: Bytes,
+    sent_at: Option<Instant>
+}
+
+/// The reliability layer of one UDP connection. It doesn't touch a socket: feed it the packets
+/// that arrive with `receive`, and send the packets `poll_outgoing` returns.
+pub struct UdpConnection {
+    local_seq: u16,
+    remote_seq: u16,
+    received_bits: u32,
+    has_received: bool,
+    ack_pending: bool,
+
+    queued: Vec<(Channel, u16, Bytes)>,
+    next_sequenced_id: u16,
+    latest_sequenced: Option<u16>,
+
+    next_reliable_id: u16,
+    unacked: VecDeque<PendingReliable>,
+    sent_packets: HashMap<u16, Vec<u16>>,
+    next_delivery: u16,
+    out_of_order: HashMap<u16, Bytes>,
+
+    last_sent: Instant,
+    last_received: Instant
+}
+
+/// Whether sequence number `a` is newer than `b`, allowing for wrap-around.
+fn seq_greater(a: u16, b: u16) -> bool {
+    (a > b && a - b <= 32768) || (a < b && b - a > 32768)
+}
+
+impl Channel {
+    fn to_u8(self) -> u8 {
+        match self {
+            Channel::Unreliable => 0,
+            Channel::UnreliableSequenced => 1,
+            Channel::ReliableOrdered => 2
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Channel> {
+        match value {
+            0 => Some(Channel::Unreliable),
+            1 => Some(Channel::UnreliableSequenced),
+            2 => Some(Channel::ReliableOrdered),
+            _ => None
+        }
+    }
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MAX_PACKET_SIZE);
+        buf.put_u32_be(PROTOCOL_ID);
+        match self {
+            Packet::Connect { salt } => {
+                buf.put_u8(0);
+                buf.put_u64_be(*salt);
+            },
+            Packet::Accept { salt } => {
+                buf.put_u8(1);
+                buf.put_u64_be(*salt);
+            },
+            Packet::Data { seq, ack, ack_bits, messages } => {
+                buf.put_u8(2);
+                buf.put_u16_be(*seq);
+                buf.put_u16_be(*ack);
+                buf.put_u32_be(*ack_bits);
+                buf.put_u8(messages.len() as u8);
+                for (channel, id, payload) in messages {
+                    buf.put_u8(channel.to_u8());
+                    buf.put_u16_be(*id);
+                    buf.put_u16_be(payload.len() as u16);
+                    buf.put_slice(payload);
+                }
+            },
+            Packet::Disconnect => buf.put_u8(3)
+        }
+        buf
+    }
+
+    /// Reads a datagram. Returns `None` for anything that isn't a well-formed packet.
+    pub fn decode(data: &[u8]) -> Option<Packet> {
+        let mut buf = Cursor::new(data);
+        if buf.remaining() < 5 || buf.get_u32_be() != PROTOCOL_ID {
+            return None;
+        }
+        let packet = match buf.get_u8() {
+            0 | 1 if buf.remaining() < 8 => return None,
+            0 => Packet::Connect { salt: buf.get_u64_be() },
+            1 => Packet::Accept { salt: buf.get_u64_be() },
+            2 => {
+                if buf.remaining() < DATA_HEADER_SIZE - 5 {
+                    return None;
+                }
+                let seq = buf.get_u16_be();
+                let ack = buf.get_u16_be();
+                let ack_bits = buf.get_u32_be();
+                let count = buf.get_u8();
+                let mut messages = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    if buf.remaining() < MESSAGE_HEADER_SIZE {
+                        return None;
+                    }
+                    let channel = Channel::from_u8(buf.get_u8())?;
+                    let id = buf.get_u16_be();
+                    let len = buf.get_u16_be() as usize;
+                    if buf.remaining() < len {
+                        return None;
+                    }
+                    let start = buf.position() as usize;
+                    messages.push((channel, id, Bytes::from(&data[start..start + len])));
+                    buf.advance(len);
+                }
+                Packet::Data { seq, ack, ack_bits, messages }
+            },
+            3 => Packet::Disconnect,
+            _ => return None
+        };
+        Some(packet)
+    }
+}
+
+impl UdpConnection {
+    pub fn new(now: Instant) -> Self {
+        UdpConnection {
+            local_seq: 0,
+            remote_seq: 0,
+            received_bits: 0,
+            has_received: false,
+            ack_pending: false,
+            queued: vec![],
+            next_sequenced_id: 0,
+            latest_sequenced: None,
+            next_reliable_id: 0,
+            unacked: VecDeque::new(),
+            sent_packets: HashMap::new(),
+            next_delivery: 0,
+            out_of_order: HashMap::new(),
+            last_sent: now,
+            last_received: now
+        }
+    }
+
+    /// Queues a message to go out with the next `poll_outgoing`.
+    pub fn send(&mut self, channel: Channel, payload: Bytes) -> Result<(), SendError> {
+        if payload.len() > MAX_MESSAGE_SIZE {
+            return Err(SendError::TooLarge(payload.len()));
+        }
+        match channel {
+            Channel::Unreliable => self.queued.push((channel, 0, payload)),
+            Channel::UnreliableSequenced => {
+                self.queued.push((channel, self.next_sequenced_id, payload));
+                self.next_sequenced_id = self.next_sequenced_id.wrapping_add(1);
+            },
+            Channel::ReliableOrdered => {
+                self.unacked.push_back(PendingReliable { id: self.next_reliable_id, payload, sent_at: None });
+                self.next_reliable_id = self.next_reliable_id.wrapping_add(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a `Data` packet from the other side, and returns the messages that are ready to be
+    /// delivered, in order. Other packets are ignored.
+    pub fn receive(&mut self, packet: Packet, now: Instant) -> Vec<Bytes> {
+        let (seq, ack, ack_bits, messages) = match packet {
+            Packet::Data { seq, ack, ack_bits, messages } => (seq, ack, ack_bits, messages),
+            _ => return vec![]
+        };
+        self.last_received = now;
+
+        // The whole packet is dropped without acknowledging it, so the other side resends it
+        // once the messages before it have been delivered.
+        let too_far_ahead = messages.iter().any(|&(channel, id, _)| {
+            channel == Channel::ReliableOrdered
+                && seq_greater(id, self.next_delivery)
+                && id.wrapping_sub(self.next_delivery) >= RECEIVE_WINDOW
+        });
+        if too_far_ahead {
+            return vec![];
+        }
+        self.ack_pending = true;
+
+        // Remember which packets arrived, so the other side learns what to resend.
+        if !self.has_received {
+            self.has_received = true;
+            self.remote_seq = seq;
+        } else if seq_greater(seq, self.remote_seq) {
+            let shift = seq.wrapping_sub(self.remote_seq) as u32;
+            self.received_bits = if shift > 32 { 0 } else { ((self.received_bits as u64) << shift) as u32 | (1 << (shift - 1)) };
+            self.remote_seq = seq;
+        } else {
+            let behind = self.remote_seq.wrapping_sub(seq) as u32;
+            if (1..=32).contains(&behind) {
+                self.received_bits |= 1 << (behind - 1);
+            }
+        }
+
+        // Forget reliable messages the other side has acknowledged.
+        self.acknowledge(ack);
+        for bit in 0..32 {
+            if ack_bits & (1 << bit) != 0 {
+                self.acknowledge(ack.wrapping_sub(bit + 1));
+            }
+        }
+
+        let mut delivered = vec![];
+        for (channel, id, payload) in messages {
+            match channel {
+                Channel::Unreliable => delivered.push(payload),
+                Channel::UnreliableSequenced => {
+                    if self.latest_sequenced.map(|latest| seq_greater(id, latest)).unwrap_or(true) {
+                        self.latest_sequenced = Some(id);
+                        delivered.push(payload);
+                    }
+                },
+                Channel::ReliableOrdered => {
+                    if id == self.next_delivery {
+                        delivered.push(payload);
+                        self.next_delivery = self.next_delivery.wrapping_add(1);
+                        while let Some(next) = self.out_of_order.remove(&self.next_delivery) {
+                            delivered.push(next);
+                            self.next_delivery = self.next_delivery.wrapping_add(1);
+                        }
+                    } else if seq_greater(id, self.next_delivery) {
+                        self.out_of_order.insert(id, payload);
+                    }
+                    // Anything older is a resend of a message that was already delivered.
+                }
+            }
+        }
+        delivered
+    }
+
+    fn acknowledge(&mut self, seq: u16) {
+        if let Some(ids) = self.sent_packets.remove(&seq) {
+            self.unacked.retain(|pending| !ids.contains(&pending.id));
+        }
+    }
+
+    /// Builds the packets that should be sent now: queued messages, reliable messages that
+    /// haven't been acknowledged in time, and acks or keep-alives if there is nothing else.
+    pub fn poll_outgoing(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut messages: Vec<(Channel, u16, Bytes)> = self.queued.drain(..).collect();
+        let mut reliable_sent = vec![];
+        for pending in self.unacked.iter_mut() {
+            let due = pending.sent_at.map(|at| now.duration_since(at) >= RESEND_AFTER).unwrap_or(true);
+            if due {
+                pending.sent_at = Some(now);
+                reliable_sent.push(pending.id);
+                messages.push((Channel::ReliableOrdered, pending.id, pending.payload.clone()));
+            }
+        }
+
+        let mut packets = vec![];
+        let mut batch = vec![];
+        let mut size = DATA_HEADER_SIZE;
+        for message in messages {
+            let message_size = MESSAGE_HEADER_SIZE + message.2.len();
+            if !batch.is_empty() && (size + message_size > MAX_PACKET_SIZE || batch.len() == u8::MAX as usize) {
+                packets.push(self.build_packet(std::mem::take(&mut batch)));
+                size = DATA_HEADER_SIZE;
+            }
+            size += message_size;
+            batch.push(message);
+        }
+        let idle = now.duration_since(self.last_sent) >= KEEP_ALIVE;
+        if !batch.is_empty() || (packets.is_empty() && (self.ack_pending || idle)) {
+            packets.push(self.build_packet(batch));
+        }
+        if !packets.is_empty() {
+            self.ack_pending = false;
+            self.last_sent = now;
+        }
+        packets
+    }
+
+    fn build_packet(&mut self, messages: Vec<(Channel, u16, Bytes)>) -> Vec<u8> {
+        let seq = self.local_seq;
+        self.local_seq = self.local_seq.wrapping_add(1);
+        let reliable: Vec<u16> = messages.iter()
+            .filter(|m| m.0 == Channel::ReliableOrdered)
+            .map(|m| m.1)
+            .collect();
+        if !reliable.is_empty() {
+            self.sent_packets.insert(seq, reliable);
+        }
+        self.sent_packets.remove(&seq.wrapping_sub(SENT_PACKET_WINDOW));
+        Packet::Data {
+            seq,
+            ack: self.remote_seq,
+            ack_bits: self.received_bits,
+            messages
+        }.encode()
+    }
+
+    /// The number of reliable messages still waiting to be acknowledged.
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// The number of reliable messages that arrived early and are waiting for the ones before them.
+    pub fn buffered(&self) -> usize {
+        self.out_of_order.len()
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        now.duration_since(self.last_received) >= TIMEOUT
+    }
+}
+
+struct UdpClient<M: Message> {
+    id: ClientID,
+    salt: u64,
+    connection: UdpConnection,
+    rx: QueueReceiver<M>,
+    limiter: Option<RateLimiter>
+}
+
+/// Runs the UDP side of a `network::Server`. Every client shares the one socket, so this is a
+/// single future instead of one per client.
+pub(crate) struct UdpServer<M: Message> {
+    socket: UdpSocket,
+    clients: HashMap<SocketAddr, UdpClient<M>>,
+    shared_client_map: SharedClientMap<M>,
+    server_tx: UnboundedSender<M>,
+    channel_of: fn(&M) -> Channel,
+    settings: ClientSettings<M>,
+    interval: Interval,
+    buffer: Vec<u8>
+}
+
+impl<M: Message> UdpServer<M> {
+    pub(crate) fn new(socket: UdpSocket, shared_client_map: SharedClientMap<M>, server_tx: UnboundedSender<M>, channel_of: fn(&M) -> Channel, settings: ClientSettings<M>) -> Self {
+        UdpServer {
+            socket,
+            clients: HashMap::new(),
+            shared_client_map,
+            server_tx,
+            channel_of,
+            settings,
+            interval: Interval::new(Instant::now(), UPDATE_INTERVAL),
+            buffer: vec![0; MAX_PACKET_SIZE]
+        }
+    }
+
+    fn handle_packet(&mut self, addr: SocketAddr, packet: Packet, now: Instant) {
+        match packet {
+            Packet::Connect { salt } => {
+                let known = match self.clients.get(&addr) {
+                    Some(client) => client.salt == salt,
+                    None => false
+                };
+                if !known {
+                    self.disconnect(&addr);
+                    let (tx, rx) = self.settings.queue();
+                    let id = get_id();
+                    {
+                        // New clients are turned away once the server is shutting down.
+                        let mut shared_client_map = self.shared_client_map.lock().unwrap();
+                        if self.settings.stopping.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        shared_client_map.insert(id, (addr, tx));
+                    }
+                    let limiter = self.settings.rate_limit.map(|config| RateLimiter::new(config, now));
+                    self.clients.insert(addr, UdpClient { id, salt, connection: UdpConnection::new(now), rx, limiter });
+                }
+                // A repeated connect means our accept was lost, so it is sent again.
+                self.send_to(&Packet::Accept { salt }.encode(), &addr);
+            },
+            Packet::Disconnect => self.disconnect(&addr),
+            packet @ Packet::Data { .. } => {
+                let client = match self.clients.get_mut(&addr) {
+                    Some(client) => client,
+                    None => return
+                };
+                let delivered = client.connection.receive(packet, now);
+                // Each message is charged on its own. Like on TCP, warnings go out reliably as
+                // `rate_limited` messages.
+                let mut allowed = vec![];
+                let mut over_limit = false;
+                for bytes in delivered {
+                    let violation = match client.limiter.as_mut().map(|l| l.check(bytes.len(), now)) {
+                        Some(Err(violation)) => violation,
+                        _ => {
+                            allowed.push(bytes);
+                            continue;
+                        }
+                    };
+                    if let Some(ref events) = self.settings.events {
+                        let _ = events.send(ClientEvent::RateLimited { id: client.id, violation });
+                    }
+                    match violation.action {
+                        RateLimitAction::Disconnect => {
+                            over_limit = true;
+                            break;
+                        },
+                        RateLimitAction::Warn => if let Some(notice) = encode_notice(&violation) {
+                            let _ = client.connection.send(Channel::ReliableOrdered, notice.into());
+                        },
+                        RateLimitAction::Drop => {}
+                    }
+                }
+                for bytes in allowed {
+                    match M::try_from(ClientMessage { bytes: bytes.into() }) {
+                        Ok(msg) => if let Err(e) = self.server_tx.unbounded_send(msg) {
+                            println!("{}", e);
+                        },
+                        Err(_) => println!("Dropping a UDP message that isn't a valid message")
+                    }
+                }
+                if over_limit {
+                    println!("UDP client at {} kept going over its rate limit and is being disconnected.", addr);
+                    // Warnings from the same packet get one chance to arrive before the goodbye.
+                    let packets = self.clients.get_mut(&addr).map(|client| client.connection.poll_outgoing(now)).unwrap_or_default();
+                    for packet in packets {
+                        self.send_to(&packet, &addr);
+                    }
+                    self.send_to(&Packet::Disconnect.encode(), &addr);
+                    self.disconnect(&addr);
+                }
+            },
+            Packet::Accept { .. } => {}
+        }
+    }
+
+    fn disconnect(&mut self, addr: &SocketAddr) {
+        if let Some(client) = self.clients.remove(addr) {
+            self.shared_client_map.lock().unwrap().remove(&client.id);
+        }
+    }
+
+    fn send_to(&mut self, packet: &[u8], addr: &SocketAddr) {
+        // A full send buffer loses the packet, which UDP has to cope with anyway.
+        if let Err(e) = self.socket.poll_send_to(packet, addr) {
+            println!("UDP send to {} failed: {}", addr, e);
+        }
+    }
+}
+
+impl<M: Message> Future for UdpServer<M> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // The interval makes sure resends and timeouts happen even when nothing arrives.
+        while let Ok(Async::Ready(Some(_))) = self.interval.poll() {}
+        let now = Instant::now();
+
+        loop {
+            match self.socket.poll_recv_from(&mut self.buffer) {
+                Ok(Async::Ready((len, addr))) => {
+                    if let Some(packet) = Packet::decode(&self.buffer[..len]) {
+                        self.handle_packet(addr, packet, now);
+                    }
+                },
+                Ok(Async::NotReady) => break,
+                // Errors on a UDP socket are about a single datagram, so keep going.
+                Err(e) => println!("UDP receive failed: {}", e)
+            }
+        }
+
+        let mut outgoing = vec![];
+        let mut timed_out = vec![];
+        let mut fell_behind = vec![];
+        let mut finished = vec![];
+        for (addr, client) in self.clients.iter_mut() {
+            loop {
+                let msg = match client.rx.poll() {
+                    Ok(Async::Ready(Some(msg))) => msg,
+                    // The send queue ends when the client falls too far behind, or when the
+                    // server is done with it.
+                    Ok(Async::Ready(None)) => {
+                        if client.rx.overflowed() {
+                            fell_behind.push(*addr);
+                        }
+                        break;
+                    },
+                    _ => break
+                };
+                let channel = (self.channel_of)(&msg);
+                let ClientMessage { bytes } = msg.into();
+                if let Err(SendError::TooLarge(len)) = client.connection.send(channel, bytes.freeze()) {
+                    println!("Dropping a {} byte message to client {}; UDP messages are limited to {} bytes",
+                             len, client.id, MAX_MESSAGE_SIZE);
+                }
+            }
+            for packet in client.connection.poll_outgoing(now) {
+                outgoing.push((*addr, packet));
+            }
+            if client.connection.timed_out(now) {
+                timed_out.push(*addr);
+            } else if client.rx.is_finished() && client.connection.unacked() == 0 {
+                // Everything reliable has arrived, goodbyes included.
+                finished.push(*addr);
+            }
+        }
+        for (addr, packet) in outgoing {
+            self.send_to(&packet, &addr);
+        }
+        for addr in timed_out {
+            println!("UDP client at {} timed out", addr);
+            self.disconnect(&addr);
+        }
+        for addr in fell_behind {
+            println!("UDP client at {} fell too far behind", addr);
+            self.send_to(&Packet::Disconnect.encode(), &addr);
+            self.disconnect(&addr);
+        }
+        for addr in finished {
+            self.send_to(&Packet::Disconnect.encode(), &addr);
+            self.disconnect(&addr);
+        }
+        if self.settings.stopping.load(Ordering::SeqCst) && self.clients.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
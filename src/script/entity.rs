@@ -0,0 +1,178 @@
+use super::{PythonInterpreter, ScriptID, ScriptError, InterpreterResult};
+use crate::core::Input;
+use crate::utils::{InputMap, ReadInputMap, ReadDeltaTime};
+use cpython::{Python, PyObject, PyTuple, ToPyObject, PythonObject, NoArgs, ObjectProtocol, PyClone, exc};
+use specs::prelude::*;
+
+/// Attaches a script class to an entity.
+///
+/// Each entity gets its own instance of the class, created the first time the entity's script
+/// runs. The engine then calls these methods on the instance, if the class defines them:
+///
+/// - `update(self, entity, dt)` once per tick
+/// - `on_input(self, entity, input)` for each input sent by the connection that owns the entity
+/// - `on_collision(self, entity, other)` when a system reports a collision through `ScriptEvents`
+///
+/// Entities are passed to Python as their ID number. Inputs are passed as `("click", x, y)` or
+/// `("key", name)` tuples.
+pub struct Script {
+    pub script: ScriptID,
+    pub class: String,
+    pub owner: Option<String>,
+    instance: Option<PyObject>
+}
+
+/// Something that happened in the world that scripted entities may want to react to.
+#[derive(Clone, Debug)]
+pub enum ScriptEvent {
+    Collision(Entity, Entity)
+}
+
+/// A resource that systems push `ScriptEvent`s into. It is drained once per tick, after the
+/// systems have run.
+pub type ScriptEvents = Vec<ScriptEvent>;
+
+define_component!(Script);
+
+impl Script {
+    pub fn new(script: ScriptID, class: &str) -> Self {
+        Script {
+            script,
+            class: class.to_string(),
+            owner: None,
+            instance: None
+        }
+    }
+
+    /// Forwards the inputs of a connection to this entity's `on_input` callback.
+    pub fn owned_by(mut self, key: &str) -> Self {
+        self.owner = Some(key.to_string());
+        self
+    }
+
+    /// Drops the entity's instance, so a fresh one is created the next time the script runs.
+    /// This is useful after the script has been reloaded.
+    pub fn reset(&mut self) {
+        self.instance = None;
+    }
+
+    fn instance(&mut self, interpreter: &mut PythonInterpreter) -> InterpreterResult<PyObject> {
+        if self.instance.is_none() {
+            let class = interpreter.get_value(self.script, &self.class)?;
+            self.instance = Some(interpreter.call_object(self.script, &class, NoArgs)?);
+        }
+        let gil = Python::acquire_gil();
+        Ok(self.instance.as_ref().unwrap().clone_ref(gil.python()))
+    }
+
+    /// Calls a callback on the entity's instance. Classes don't have to define every callback, so
+    /// a missing method is not an error. Any other error raised while looking the method up (by a
+    /// `__getattr__` or a property, say) is returned.
+    fn invoke<A>(&mut self, interpreter: &mut PythonInterpreter, method: &str, args: A) -> InterpreterResult<()>
+    where A: ToPyObject<ObjectType=PyTuple> {
+        let instance = self.instance(interpreter)?;
+        let callback = {
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            match instance.getattr(py, method) {
+                Ok(callback) => callback,
+                Err(ref e) if e.matches(py, py.get_type::<exc::AttributeError>()) => return Ok(()),
+                Err(e) => return Err(ScriptError::from_py_err(py, e, Some(self.script), None))
+            }
+        };
+        interpreter.call_object(self.script, &callback, args).map(|_| ())
+    }
+}
+
+fn input_to_py(py: Python, input: &Input) -> PyObject {
+    match input {
+        Input::Click { x, y } => ("click", *x, *y).to_py_object(py).into_object(),
+        Input::Key(key) => ("key", key.as_str()).to_py_object(py).into_object()
+    }
+}
+
+impl PythonInterpreter {
+    /// Runs the scripts attached to entities for one tick: inputs first, then collisions, then
+    /// `update`. A failing script doesn't stop the others from running; every error is returned.
+    pub fn run_entity_scripts(&mut self, world: &mut World, delta_time: f64) -> Vec<ScriptError> {
+        let entities = world.entities();
+        let mut scripts = world.write_storage::<Script>();
+        let inputs = world.read_resource::<InputMap>();
+        let events = std::mem::take(&mut *world.write_resource::<ScriptEvents>());
+        self.run_scripts(&entities, &mut scripts, &inputs, events, delta_time)
+    }
+
+    fn run_scripts(&mut self, entities: &Entities, scripts: &mut WriteStorage<Script>, inputs: &InputMap,
+                   events: ScriptEvents, delta_time: f64) -> Vec<ScriptError> {
+        let mut errors = vec![];
+
+        for (entity, script) in (entities, &mut *scripts).join() {
+            let owned_inputs = script.owner.as_ref().and_then(|key| inputs.get(key));
+            if let Some(owned_inputs) = owned_inputs {
+                for input in owned_inputs {
+                    let input = {
+                        let gil = Python::acquire_gil();
+                        input_to_py(gil.python(), input)
+                    };
+                    if let Err(e) = script.invoke(self, "on_input", (entity.id(), input)) {
+                        errors.push(e);
+                    }
+                }
+            }
+        }
+
+        for event in events {
+            match event {
+                ScriptEvent::Collision(a, b) => {
+                    for &(entity, other) in &[(a, b), (b, a)] {
+                        if let Some(script) = scripts.get_mut(entity) {
+                            if let Err(e) = script.invoke(self, "on_collision", (entity.id(), other.id())) {
+                                errors.push(e);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for (entity, script) in (entities, &mut *scripts).join() {
+            if let Err(e) = script.invoke(self, "update", (entity.id(), delta_time)) {
+                errors.push(e);
+            }
+        }
+        errors
+    }
+}
+
+/// A system that runs entity scripts on the dispatcher, instead of on the engine's thread.
+/// Add it with `EngineBuilder::with_system` rather than passing the interpreter to `with_scripts`.
+pub struct PythonScriptSystem {
+    backend: PythonInterpreter
+}
+
+impl PythonScriptSystem {
+    pub fn new(backend: PythonInterpreter) -> Self {
+        PythonScriptSystem {
+            backend
+        }
+    }
+
+    pub fn interpreter(&mut self) -> &mut PythonInterpreter {
+        &mut self.backend
+    }
+}
+
+impl<'a> System<'a> for PythonScriptSystem {
+    type SystemData = (Entities<'a>,
+    WriteStorage<'a, Script>,
+    ReadInputMap<'a>,
+    Write<'a, ScriptEvents>,
+    ReadDeltaTime<'a>);
+
+    fn run(&mut self, (entities, mut scripts, inputs, mut events, delta_time): Self::SystemData) {
+        let events = std::mem::take(&mut *events);
+        for error in self.backend.run_scripts(&entities, &mut scripts, &inputs, events, delta_time.0) {
+            println!("Script system caught a script error: {}", error);
+        }
+    }
+}
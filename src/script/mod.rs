@@ -1,13 +1,40 @@
+mod entity;
+mod sandbox;
+mod thread;
+mod watch;
+
 pub use cpython;
-use cpython::{Python, PyObject, GILGuard, PyModule, FromPyObject};
-use std::path::Path;
-use std::marker::PhantomData;
+pub use entity::{Script, ScriptEvent, ScriptEvents, PythonScriptSystem};
+pub use sandbox::SandboxConfig;
+pub use thread::ScriptThread;
+pub use watch::ScriptWatcher;
+use cpython::{Python, PyObject, PyModule, FromPyObject, PyErr, PyString, PyDict, PyTuple,
+              ObjectProtocol, PythonObject, ToPyObject, PyClone, NoArgs};
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
-use std::hint::unreachable_unchecked;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-pub type InterpreterResult<T> = Result<T, String>;
+pub type InterpreterResult<T> = Result<T, ScriptError>;
 
 pub type ScriptID = u64;
+
+static INTERPRETER_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The `hyperspeed` module that scripts can import to talk to the engine.
+const HOST_MODULE: &str = r#"
+import threading
+
+# Every interpreter shares this module, so requests are kept apart by the interpreter whose
+# script made them. The interpreter running on each thread is set before it calls into scripts.
+_spawn_requests = {}
+_context = threading.local()
+
+def spawn(name):
+    """Spawns an entity from the blueprint called `name` once the current tick's scripts have run."""
+    interpreter = getattr(_context, 'interpreter', None)
+    _spawn_requests.setdefault(interpreter, []).append(str(name))
+"#;
 /*
 macro_rules! setup_python_hook {
     ($ss:expr, $sid:expr, $f:ident [ $( { $pname:ident : $ptype:ty = $detail:tt } )* ]) => {
@@ -16,111 +43,620 @@ macro_rules! setup_python_hook {
 }
 */
 
+/// The place in a script's source where an error was raised.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32
+}
+
+/// Everything known about a failed script operation. Fields that don't apply to a particular
+/// failure (a missing script ID has no traceback, for example) are left as `None`.
+#[derive(Clone, Debug)]
+pub struct ScriptErrorInfo {
+    pub script: Option<ScriptID>,
+    pub module: Option<String>,
+    pub location: Option<SourceLocation>,
+    pub message: String,
+    pub traceback: Option<String>
+}
+
+/// An error raised while loading or running a script.
+#[derive(Clone, Debug)]
+pub enum ScriptError {
+    /// The module (or one of its imports) could not be found.
+    ModuleNotFound(ScriptErrorInfo),
+    /// The script's source could not be parsed.
+    Syntax(ScriptErrorInfo),
+    /// An exception was raised while the script was running.
+    Runtime(ScriptErrorInfo),
+    /// A value from the script could not be converted into the requested Rust type.
+    Conversion(ScriptErrorInfo),
+    /// The script has no variable with the requested name.
+    MissingVariable(ScriptErrorInfo),
+    /// A sandboxed script tried to import a module that isn't on its allowlist.
+    ImportDenied(ScriptErrorInfo),
+    /// A sandboxed script went over its instruction or time budget and was aborted.
+    BudgetExceeded(ScriptErrorInfo),
+    /// A sandboxed script used a construct the sandbox forbids, such as private attribute access.
+    SandboxViolation(ScriptErrorInfo),
+    /// The script ID does not refer to a loaded script.
+    InvalidScript(ScriptID)
+}
+
+/// Loads and runs Python scripts.
+///
+/// The interpreter only holds the GIL while it is calling into Python, so it can be sent to
+/// other threads, including the specs dispatcher's thread pool (see `PythonScriptSystem`) or a
+/// dedicated `ScriptThread`.
 pub struct PythonInterpreter {
-    pub gil_guard: GILGuard,
+    // Tells this interpreter's spawn requests apart from those of others in the process.
+    id: u64,
     modules: HashMap<ScriptID, PyModule>,
-    script_id_counter: ScriptID
+    script_id_counter: ScriptID,
+    watcher: Option<ScriptWatcher>,
+    sandboxes: HashMap<ScriptID, SandboxedScript>,
+    sandbox_runtime: Option<PyModule>
+}
+
+struct SandboxedScript {
+    path: PathBuf,
+    config: SandboxConfig
+}
+
+impl SourceLocation {
+    pub fn new(file: &str, line: u32) -> Self {
+        SourceLocation {
+            file: file.to_string(),
+            line
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+impl ScriptErrorInfo {
+    pub fn new(message: String) -> Self {
+        ScriptErrorInfo {
+            script: None,
+            module: None,
+            location: None,
+            message,
+            traceback: None
+        }
+    }
+
+    /// Builds the error information from a Python exception. The exception is normalized so that
+    /// the source location and traceback can be read from it.
+    pub fn from_py_err(py: Python, mut err: PyErr, script: Option<ScriptID>, module: Option<&str>) -> Self {
+        err.normalize(py);
+        let message = match err.pvalue {
+            Some(ref value) => value.str(py)
+                .map(|s| s.to_string_lossy(py).into_owned())
+                .unwrap_or_default(),
+            None => String::new()
+        };
+        ScriptErrorInfo {
+            script,
+            module: module.map(|m| m.to_string()),
+            location: error_location(py, &err),
+            message: format!("{}: {}", err.get_type(py).name(py), message),
+            traceback: format_traceback(py, &err)
+        }
+    }
+}
+
+impl ScriptError {
+    /// Sorts a Python exception into the matching error kind.
+    pub fn from_py_err(py: Python, err: PyErr, script: Option<ScriptID>, module: Option<&str>) -> Self {
+        let type_name = err.get_type(py).name(py).into_owned();
+        let info = ScriptErrorInfo::from_py_err(py, err, script, module);
+        match type_name.as_str() {
+            "ImportDenied" => ScriptError::ImportDenied(info),
+            "BudgetExceeded" => ScriptError::BudgetExceeded(info),
+            "SandboxViolation" => ScriptError::SandboxViolation(info),
+            "ModuleNotFoundError" | "ImportError" => ScriptError::ModuleNotFound(info),
+            "SyntaxError" | "IndentationError" | "TabError" => ScriptError::Syntax(info),
+            _ => ScriptError::Runtime(info)
+        }
+    }
+
+    /// Returns the details of the error, if there are any.
+    pub fn info(&self) -> Option<&ScriptErrorInfo> {
+        use self::ScriptError::*;
+        match self {
+            ModuleNotFound(info)
+            | Syntax(info)
+            | Runtime(info)
+            | Conversion(info)
+            | MissingVariable(info)
+            | ImportDenied(info)
+            | BudgetExceeded(info)
+            | SandboxViolation(info) => Some(info),
+            InvalidScript(_) => None
+        }
+    }
+
+    pub fn script(&self) -> Option<ScriptID> {
+        match self {
+            ScriptError::InvalidScript(id) => Some(*id),
+            _ => self.info().and_then(|info| info.script)
+        }
+    }
+
+    pub fn traceback(&self) -> Option<&str> {
+        self.info().and_then(|info| info.traceback.as_deref())
+    }
+
+    fn kind_name(&self) -> &'static str {
+        use self::ScriptError::*;
+        match self {
+            ModuleNotFound(_) => "module not found",
+            Syntax(_) => "syntax error",
+            Runtime(_) => "runtime error",
+            Conversion(_) => "conversion error",
+            MissingVariable(_) => "missing variable",
+            ImportDenied(_) => "import denied",
+            BudgetExceeded(_) => "budget exceeded",
+            SandboxViolation(_) => "sandbox violation",
+            InvalidScript(_) => "invalid script"
+        }
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.info() {
+            Some(info) => {
+                write!(f, "Script {}", self.kind_name())?;
+                if let Some(ref module) = info.module {
+                    write!(f, " in '{}'", module)?;
+                }
+                if let Some(script) = info.script {
+                    write!(f, " (script ID {})", script)?;
+                }
+                if let Some(ref location) = info.location {
+                    write!(f, " at {}", location)?;
+                }
+                write!(f, ": {}", info.message)?;
+                if let Some(ref traceback) = info.traceback {
+                    write!(f, "\n{}", traceback.trim_end())?;
+                }
+                Ok(())
+            },
+            None => match self {
+                ScriptError::InvalidScript(id) => write!(f, "Attempted to use non-existent script ID ({})", id),
+                _ => unreachable!()
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Finds where an exception was raised. Syntax errors carry their own location, everything else
+/// is located by the innermost frame of the traceback.
+fn error_location(py: Python, err: &PyErr) -> Option<SourceLocation> {
+    if let Some(ref value) = err.pvalue {
+        let file = value.getattr(py, "filename").and_then(|f| f.extract::<String>(py));
+        let line = value.getattr(py, "lineno").and_then(|l| l.extract::<u32>(py));
+        if let (Ok(file), Ok(line)) = (file, line) {
+            return Some(SourceLocation { file, line });
+        }
+    }
+
+    let mut tb = err.ptraceback.as_ref()?.clone_ref(py);
+    loop {
+        let next = tb.getattr(py, "tb_next").ok()?;
+        if next == py.None() {
+            break;
+        }
+        tb = next;
+    }
+    let line = tb.getattr(py, "tb_lineno").ok()?.extract::<u32>(py).ok()?;
+    let file = tb.getattr(py, "tb_frame").ok()?
+        .getattr(py, "f_code").ok()?
+        .getattr(py, "co_filename").ok()?
+        .extract::<String>(py).ok()?;
+    Some(SourceLocation { file, line })
+}
+
+/// Formats an exception the same way Python prints uncaught exceptions.
+fn format_traceback(py: Python, err: &PyErr) -> Option<String> {
+    let traceback = py.import("traceback").ok()?;
+    let lines = traceback.call(py, "format_exception", (
+        err.ptype.clone_ref(py),
+        err.pvalue.as_ref().map(|v| v.clone_ref(py)),
+        err.ptraceback.as_ref().map(|t| t.clone_ref(py))
+    ), None).ok()?;
+    PyString::new(py, "").as_object()
+        .call_method(py, "join", (lines,), None).ok()?
+        .extract::<String>(py).ok()
+}
+
+impl Default for PythonInterpreter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PythonInterpreter {
     pub fn new() -> PythonInterpreter {
+        if let Err(e) = PythonInterpreter::install_host_module() {
+            println!("Could not install the hyperspeed module for scripts: {}", e);
+        }
         PythonInterpreter {
-            gil_guard: Python::acquire_gil(),
+            id: INTERPRETER_ID_COUNTER.fetch_add(1, Ordering::SeqCst),
             modules: HashMap::new(),
-            script_id_counter: 0
+            script_id_counter: 0,
+            watcher: None,
+            sandboxes: HashMap::new(),
+            sandbox_runtime: None
+        }
+    }
+
+    fn install_host_module() -> InterpreterResult<()> {
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        let sys_modules = python.import("sys")
+            .and_then(|sys| sys.get(python, "modules"))
+            .map_err(|e| ScriptError::from_py_err(python, e, None, Some("hyperspeed")))?;
+        // Every interpreter shares the one Python runtime, so the module only needs installing once.
+        if sys_modules.get_item(python, "hyperspeed").is_ok() {
+            return Ok(());
         }
+        PyModule::new(python, "hyperspeed")
+            .and_then(|m| python.run(HOST_MODULE, Some(&m.dict(python)), None).map(|_| m))
+            .and_then(|m| sys_modules.set_item(python, "hyperspeed", m))
+            .map_err(|e| ScriptError::from_py_err(python, e, None, Some("hyperspeed")))
+    }
+
+    /// Takes the names of the blueprints that this interpreter's scripts have asked to spawn with
+    /// `hyperspeed.spawn`. Requests made by other interpreters' scripts are left for them.
+    pub fn take_spawn_requests(&mut self) -> InterpreterResult<Vec<String>> {
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        let to_error = |e| ScriptError::from_py_err(python, e, None, Some("hyperspeed"));
+        let requests = python.import("hyperspeed")
+            .and_then(|m| m.get(python, "_spawn_requests"))
+            .and_then(|requests| requests.call_method(python, "pop", (self.id, Vec::<String>::new()), None))
+            .map_err(&to_error)?;
+        requests.extract::<Vec<String>>(python).map_err(to_error)
+    }
+
+    /// Marks the current thread as running this interpreter's scripts, so their spawn requests
+    /// are kept for it. Called before anything that can run script code.
+    fn enter(&self, python: Python) -> InterpreterResult<()> {
+        python.import("hyperspeed")
+            .and_then(|m| m.get(python, "_context"))
+            .and_then(|context| context.setattr(python, "interpreter", self.id))
+            .map_err(|e| ScriptError::from_py_err(python, e, None, Some("hyperspeed")))
+    }
+
+    /// Turns watch mode on or off. While it is on, the source file of every loaded script is
+    /// tracked, and `reload_changed` reloads the scripts whose files were modified.
+    pub fn set_watch_mode(&mut self, enabled: bool) {
+        if !enabled {
+            self.watcher = None;
+            return;
+        }
+        if self.watcher.is_none() {
+            self.watcher = Some(ScriptWatcher::new());
+            let scripts: Vec<ScriptID> = self.modules.keys().cloned().collect();
+            for script in scripts {
+                self.watch_script(script);
+            }
+        }
+    }
+
+    pub fn is_watching(&self) -> bool {
+        self.watcher.is_some()
+    }
+
+    fn watch_script(&mut self, script: ScriptID) {
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        let file = self.modules.get(&script)
+            .and_then(|m| m.dict(python).get_item(python, "__file__"))
+            .and_then(|f| f.extract::<String>(python).ok());
+        // Built-in modules have no source file, so there is nothing to watch.
+        if let (Some(watcher), Some(file)) = (self.watcher.as_mut(), file) {
+            watcher.watch(script, file);
+        }
+    }
+
+    /// Reloads every watched script whose source has changed on disk. This is meant to be called
+    /// between ticks. A script that fails to reload keeps running its previous version, and the
+    /// failure is returned alongside its ID.
+    pub fn reload_changed(&mut self) -> Vec<(ScriptID, InterpreterResult<()>)> {
+        let changed = match self.watcher.as_mut() {
+            Some(watcher) => watcher.changed(),
+            None => return vec![]
+        };
+        changed.into_iter()
+            .map(|script| (script, self.reload(script)))
+            .collect()
     }
 
     /// This is a helper function that appends a path to `sys.path` to allow imports from other locations.
     /// This doesn't happen by default, for safety reasons.
-    pub fn include(&mut self, path: &'static str) -> InterpreterResult<()> {
-        let python = self.gil_guard.python();
-        //Note: Potential injection vulnerability. With a &'static str it shouldn't be a problem though.
-        let command = format!("import sys\nsys.path.append(\"{}\")", path);
-        match python.run(command.as_str(), None, None) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("Error adding include path: {:?}", e))
+    pub fn include(&mut self, path: &str) -> InterpreterResult<()> {
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        // The path is passed as an object rather than formatted into source, so it can't inject code.
+        python.import("sys")
+            .and_then(|sys| sys.get(python, "path"))
+            .and_then(|sys_path| sys_path.call_method(python, "append", (path,), None))
+            .map(|_| ())
+            .map_err(|e| ScriptError::from_py_err(python, e, None, None))
+    }
+
+    fn sandbox_runtime(&mut self) -> InterpreterResult<PyModule> {
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        if let Some(ref runtime) = self.sandbox_runtime {
+            return Ok(runtime.clone_ref(python));
+        }
+        let runtime = sandbox::new_runtime(python)
+            .map_err(|e| ScriptError::from_py_err(python, e, None, Some("_hyperspeed_sandbox")))?;
+        self.sandbox_runtime = Some(runtime.clone_ref(python));
+        Ok(runtime)
+    }
+
+    /// Runs a script's source in a fresh module with the sandbox's restrictions applied.
+    fn exec_sandboxed(&mut self, name: &str, path: &Path, config: &SandboxConfig) -> InterpreterResult<PyModule> {
+        let runtime = self.sandbox_runtime()?;
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        self.enter(python)?;
+        let to_error = |e| ScriptError::from_py_err(python, e, None, Some(name));
+        let source = std::fs::read_to_string(path).map_err(|e| ScriptError::ModuleNotFound(ScriptErrorInfo {
+            module: Some(name.to_string()),
+            ..ScriptErrorInfo::new(format!("Could not read '{}': {}", path.display(), e))
+        }))?;
+
+        let module = PyModule::new(python, name).map_err(&to_error)?;
+        sandbox::call_guarded(python, &runtime, config, "load", (
+            module.dict(python),
+            source,
+            path.to_string_lossy().into_owned(),
+            config.allowed_imports.clone(),
+            config.instruction_budget,
+            config.time_budget_secs()
+        )).map_err(&to_error)?;
+        Ok(module)
+    }
+
+    /// Loads an untrusted script from a file, with the restrictions described by `sandbox`.
+    /// Sandboxed scripts aren't added to `sys.modules`, so other scripts can't import them.
+    pub fn load_sandboxed<P: AsRef<Path>>(&mut self, name: &str, path: P, sandbox: SandboxConfig) -> InterpreterResult<ScriptID> {
+        let path = path.as_ref().to_path_buf();
+        let module = self.exec_sandboxed(name, &path, &sandbox)?;
+        let script = self.script_id_counter;
+        self.modules.insert(script, module);
+        self.sandboxes.insert(script, SandboxedScript { path, config: sandbox });
+        self.script_id_counter += 1;
+        self.watch_script(script);
+        Ok(script)
+    }
+
+    pub fn is_sandboxed(&self, script: ScriptID) -> bool {
+        self.sandboxes.contains_key(&script)
+    }
+
+    /// Calls a function defined by a script. Calls into sandboxed scripts are held to the
+    /// sandbox's budget.
+    pub fn call<A>(&mut self, script: ScriptID, function: &str, args: A) -> InterpreterResult<PyObject>
+    where A: ToPyObject<ObjectType=PyTuple> {
+        let func = match self.get_value(script, function) {
+            Ok(func) => func,
+            Err(ScriptError::MissingVariable(info)) => return Err(ScriptError::MissingVariable(ScriptErrorInfo {
+                message: format!("Script contains no function called '{}'", function),
+                ..info
+            })),
+            Err(e) => return Err(e)
+        };
+        self.call_object(script, &func, args)
+    }
+
+    /// Calls a Python object (a function, class or bound method) that belongs to a script. If
+    /// the script is sandboxed, the call is held to the sandbox's budget.
+    pub fn call_object<A>(&mut self, script: ScriptID, callable: &PyObject, args: A) -> InterpreterResult<PyObject>
+    where A: ToPyObject<ObjectType=PyTuple> {
+        let runtime = if self.is_sandboxed(script) { Some(self.sandbox_runtime()?) } else { None };
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        self.enter(python)?;
+        let module = self.modules.get(&script).ok_or(ScriptError::InvalidScript(script))?;
+        let name = module.name(python).ok().map(|n| n.to_string());
+        let to_error = |e| ScriptError::from_py_err(python, e, Some(script), name.as_deref());
+
+        match runtime {
+            Some(runtime) => {
+                let sandbox = &self.sandboxes[&script].config;
+                sandbox::call_guarded(python, &runtime, sandbox, "run_guarded", (
+                    callable.clone_ref(python),
+                    args.to_py_object(python),
+                    sandbox.instruction_budget,
+                    sandbox.time_budget_secs()
+                )).map_err(&to_error)
+            },
+            None => callable.call(python, args, None).map_err(&to_error)
         }
     }
 
     pub fn load_module(&mut self, name: &str) -> InterpreterResult<ScriptID> {
-        let python = self.gil_guard.python();
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        self.enter(python)?;
         match python.import(name) {
             Ok(module) => {
-                self.modules.insert(self.script_id_counter, module);
+                let script = self.script_id_counter;
+                self.modules.insert(script, module);
                 self.script_id_counter += 1;
-                Ok(self.script_id_counter - 1) // It's -1 because we just incremented it
+                self.watch_script(script);
+                Ok(script)
             },
             Err(error) => {
-                Err(format!("Error loading Python script: {:?}", error))
+                Err(ScriptError::from_py_err(python, error, None, Some(name)))
             }
         }
     }
+    /// Re-imports a script from its source, even if Python has the module cached.
+    ///
+    /// If the new version defines `on_reload(old_state)`, it is called with the namespace of the
+    /// previous version so that script-level state can be carried over. If the new version fails
+    /// to import, or its `on_reload` hook raises, the previous module is put back and keeps running.
     pub fn reload(&mut self, script: ScriptID) -> InterpreterResult<()> {
-        let python = self.gil_guard.python();
-        match self.modules.get(&script) {
-            Some(m) => {
-                let name = m.name(python).unwrap();
-                match python.import(name) {
-                    Ok(module) => {
-                        self.modules.insert(script, module);
-                        Ok(())
-                    },
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        self.enter(python)?;
+        let old = match self.modules.get(&script) {
+            Some(m) => m.clone_ref(python),
+            None => return Err(ScriptError::InvalidScript(script))
+        };
+        let name = old.name(python)
+            .map_err(|e| ScriptError::from_py_err(python, e, Some(script), None))?
+            .to_string();
+        let to_error = |e| ScriptError::from_py_err(python, e, Some(script), Some(&name));
+
+        let new = match self.sandboxes.get(&script).map(|s| (s.path.clone(), s.config.clone())) {
+            // Sandboxed scripts never enter `sys.modules`, so a failed load has nothing to undo.
+            Some((path, config)) => self.exec_sandboxed(&name, &path, &config)?,
+            None => {
+                let sys_modules = python.import("sys")
+                    .and_then(|sys| sys.get(python, "modules"))
+                    .map_err(&to_error)?;
+                python.import("importlib")
+                    .and_then(|importlib| importlib.call(python, "invalidate_caches", NoArgs, None))
+                    .map_err(&to_error)?;
+
+                // Removing the cached module forces a fresh import from source, which leaves the
+                // old module object untouched in case we have to roll back.
+                sys_modules.del_item(python, name.as_str()).map_err(&to_error)?;
+                match python.import(&name) {
+                    Ok(new) => new,
                     Err(error) => {
-                        Err(format!("Error re-loading Python script: {:?}", error))
+                        let _ = sys_modules.set_item(python, name.as_str(), old.as_object());
+                        return Err(to_error(error));
                     }
                 }
-            },
-            None => {
-                Err(format!("Attempted to reload non-existent script ID ({})", script))
+            }
+        };
+
+        let sandbox = self.sandboxes.get(&script).map(|s| s.config.clone());
+        let runtime = if sandbox.is_some() { Some(self.sandbox_runtime()?) } else { None };
+        if let Some(hook) = new.dict(python).get_item(python, "on_reload") {
+            let hook_result = old.dict(python).copy(python)
+                .and_then(|old_state: PyDict| match (&runtime, &sandbox) {
+                    (Some(runtime), Some(sandbox)) => sandbox::call_guarded(python, runtime, sandbox, "run_guarded", (
+                        hook,
+                        (old_state,).to_py_object(python),
+                        sandbox.instruction_budget,
+                        sandbox.time_budget_secs()
+                    )),
+                    _ => hook.call(python, (old_state,), None)
+                });
+            if let Err(error) = hook_result {
+                if sandbox.is_none() {
+                    // If this fails too, the old module is still held in `self.modules`.
+                    let _ = python.import("sys")
+                        .and_then(|sys| sys.get(python, "modules"))
+                        .and_then(|sys_modules| sys_modules.set_item(python, name.as_str(), old.as_object()));
+                }
+                return Err(to_error(error));
             }
         }
+
+        self.modules.insert(script, new);
+        self.watch_script(script);
+        Ok(())
     }
 
     pub fn get_value(&mut self, script: ScriptID, variable_name: &str) -> InterpreterResult<PyObject> {
-        let python = self.gil_guard.python();
+        let gil = Python::acquire_gil();
+        let python = gil.python();
         match self.modules.get(&script) {
             Some(m) => {
                 match m.dict(python).get_item(python, variable_name) {
                     Some(v) => Ok(v),
-                    None => Err(format!("Script with id ({}) contains no variable called '{}'", script, variable_name))
+                    None => Err(ScriptError::MissingVariable(ScriptErrorInfo {
+                        script: Some(script),
+                        module: m.name(python).ok().map(|n| n.to_string()),
+                        ..ScriptErrorInfo::new(format!("Script contains no variable called '{}'", variable_name))
+                    }))
                 }
             },
             None => {
-                Err(format!("Atempted to use non-existent script ID ({})", script))
+                Err(ScriptError::InvalidScript(script))
             }
         }
     }
 
     pub fn convert<'a, V>(&mut self, py_obj: &'a PyObject) -> InterpreterResult<Box<V>>
     where V: FromPyObject<'a> {
-        let python = self.gil_guard.python();
+        let gil = Python::acquire_gil();
+        let python = gil.python();
         match py_obj.extract::<V>(python) {
             Ok(v) => Ok(Box::new(v)),
-            Err(e) => Err(format!("Could not convert object to desired type: {:?}", e))
+            Err(e) => {
+                let info = ScriptErrorInfo::from_py_err(python, e, None, None);
+                Err(ScriptError::Conversion(info))
+            }
         }
     }
 
+    /// Converts a serialized component (see `ComponentRegistry`) into plain Python dicts, lists,
+    /// numbers and strings.
+    pub fn to_python(&mut self, value: &serde_json::Value) -> InterpreterResult<PyObject> {
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        python.import("json")
+            .and_then(|json| json.call(python, "loads", (value.to_string(),), None))
+            .map_err(|e| ScriptError::Conversion(ScriptErrorInfo::from_py_err(python, e, None, None)))
+    }
+
+    /// Converts a Python object back into the serialized form of a component.
+    pub fn from_python(&mut self, py_obj: &PyObject) -> InterpreterResult<serde_json::Value> {
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        let json = python.import("json")
+            .and_then(|json| json.call(python, "dumps", (py_obj.clone_ref(python),), None))
+            .and_then(|json| json.extract::<String>(python))
+            .map_err(|e| ScriptError::Conversion(ScriptErrorInfo::from_py_err(python, e, None, None)))?;
+        serde_json::from_str(&json)
+            .map_err(|e| ScriptError::Conversion(ScriptErrorInfo::new(e.to_string())))
+    }
+
     pub fn clear(&mut self) -> InterpreterResult<()> {
         self.modules.clear();
+        self.sandboxes.clear();
+        if let Some(watcher) = self.watcher.as_mut() {
+            watcher.clear();
+        }
         Ok(())
     }
 
     pub fn exec(&mut self, script: ScriptID, statement: &str) -> InterpreterResult<()> {
-        let python = self.gil_guard.python();
-        let module = self.modules.get_mut(&script).unwrap();
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        self.enter(python)?;
+        let module = self.modules.get_mut(&script)
+            .ok_or(ScriptError::InvalidScript(script))?;
         match python.run(statement, Some(&module.dict(python)), None) {
             Ok(()) => Ok(()),
-            Err(e) => Err(format!("Error when running '{}': {:?}", statement, e))
+            Err(e) => {
+                let name = module.name(python).ok().map(|n| n.to_string());
+                Err(ScriptError::from_py_err(python, e, Some(script), name.as_deref()))
+            }
         }
     }
 
 }
 
-/// A subsystem that sends ECS data to Python scripts.
-pub struct PythonScriptSystem {
-    backend: PythonInterpreter
-}
-
-/// This is currently unimplemented
-pub struct LuaScriptSystem {}
\ No newline at end of file
+/// This is currently unimplemented. Until it is, `Script` components can only be written in Python.
+pub struct LuaScriptSystem {}
@@ -0,0 +1,315 @@
+use cpython::{Python, PyObject, PyModule, PyResult, PyTuple, ToPyObject, NoArgs};
+use cpython::_detail::ffi;
+use std::collections::HashMap;
+use std::os::raw::c_ulong;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Restrictions applied to a script loaded with `PythonInterpreter::load_sandboxed`.
+///
+/// Sandboxed scripts get a reduced set of builtins (no `open`, `exec`, `eval`, `compile` or
+/// `input`), can't use `str.format` (f-strings work instead), can only import the modules listed
+/// here, and are aborted once they go over their budget. The time budget is enforced by a
+/// watchdog thread, which can only interrupt the script between bytecodes, so a single
+/// long-running call into native code (a huge `sorted`, for example) can overshoot it.
+#[derive(Clone, Debug)]
+pub struct SandboxConfig {
+    pub allowed_imports: Vec<String>,
+    pub instruction_budget: Option<u64>,
+    pub time_budget: Option<Duration>
+}
+
+impl SandboxConfig {
+    /// A sandbox that allows the `math` module, with a budget of one million lines and 50
+    /// milliseconds per call.
+    ///
+    /// The `hyperspeed` module isn't allowed by default, because `hyperspeed.spawn` has no limit
+    /// on how many entities a script can ask for. Use `allow_import("hyperspeed")` for scripts
+    /// that are trusted to spawn.
+    pub fn new() -> Self {
+        SandboxConfig {
+            allowed_imports: vec!["math".to_string()],
+            instruction_budget: Some(1_000_000),
+            time_budget: Some(Duration::from_millis(50))
+        }
+    }
+
+    /// Allows the script to import a module. Only allow modules that can't reach the filesystem,
+    /// the network or the interpreter itself: `random` is fine, `os` and `sys` are not.
+    pub fn allow_import(mut self, module: &str) -> Self {
+        self.allowed_imports.push(module.to_string());
+        self
+    }
+
+    /// Sets how many lines of Python a single call may execute. `None` removes the limit.
+    pub fn instruction_budget(mut self, lines: Option<u64>) -> Self {
+        self.instruction_budget = lines;
+        self
+    }
+
+    /// Sets how much wall-clock time a single call may take. `None` removes the limit.
+    pub fn time_budget(mut self, time: Option<Duration>) -> Self {
+        self.time_budget = time;
+        self
+    }
+
+    pub(crate) fn time_budget_secs(&self) -> Option<f64> {
+        self.time_budget.map(|t| t.as_secs_f64())
+    }
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig::new()
+    }
+}
+
+// How often the watchdog raises `BudgetExceeded` again while an aborted call keeps running.
+const RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A guarded call the watchdog is timing.
+struct Guarded {
+    // `None` until the line budget runs out, for calls without a time budget.
+    deadline: Option<Instant>,
+    // The sandbox runtime's `BudgetExceeded` class. Every interpreter has its own.
+    exception: PyObject
+}
+
+/// Aborts guarded calls from outside the interpreter, by raising `BudgetExceeded` in the thread
+/// that made them. A script can't outlast it by looping where no line events are traced, and
+/// it keeps raising until the call returns, since the script's `finally` blocks run after the
+/// first one with tracing already turned off.
+struct Watchdog {
+    // Keyed by the ident of the thread making the call.
+    calls: Mutex<HashMap<c_ulong, Guarded>>,
+    wake: Condvar
+}
+
+static WATCHDOG: OnceLock<Watchdog> = OnceLock::new();
+
+fn watchdog() -> &'static Watchdog {
+    WATCHDOG.get_or_init(|| {
+        thread::spawn(|| watchdog().run());
+        Watchdog {
+            calls: Mutex::new(HashMap::new()),
+            wake: Condvar::new()
+        }
+    })
+}
+
+impl Watchdog {
+    fn run(&self) {
+        let mut calls = self.calls.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            match calls.values().filter_map(|call| call.deadline).min() {
+                None => calls = self.wake.wait(calls).unwrap(),
+                Some(deadline) if deadline > now => calls = self.wake.wait_timeout(calls, deadline - now).unwrap().0,
+                Some(_) => {
+                    // The GIL is taken before the calls are locked again, the same order the
+                    // threads making them use.
+                    drop(calls);
+                    self.interrupt_expired();
+                    thread::sleep(RETRY_INTERVAL);
+                    calls = self.calls.lock().unwrap();
+                }
+            }
+        }
+    }
+
+    fn interrupt_expired(&self) {
+        // Holding the GIL means a call can't finish and be disarmed while its exception is set.
+        let _gil = Python::acquire_gil();
+        let now = Instant::now();
+        for (thread, call) in self.calls.lock().unwrap().iter() {
+            if call.deadline.is_some_and(|deadline| deadline <= now) {
+                unsafe { ffi::PyThreadState_SetAsyncExc(*thread, call.exception.as_ptr()); }
+            }
+        }
+    }
+}
+
+fn current_thread(py: Python) -> PyResult<c_ulong> {
+    py.import("threading")?.call(py, "get_ident", NoArgs, None)?.extract::<u64>(py).map(|ident| ident as c_ulong)
+}
+
+/// Called by the tracer once a call has gone over its line budget, so the watchdog aborts it
+/// straight away.
+fn expire(py: Python) -> PyResult<PyObject> {
+    let thread = current_thread(py)?;
+    let watchdog = watchdog();
+    if let Some(call) = watchdog.calls.lock().unwrap().get_mut(&thread) {
+        call.deadline = Some(Instant::now());
+    }
+    watchdog.wake.notify_one();
+    Ok(py.None())
+}
+
+/// Creates the sandbox runtime module. See `SANDBOX_RUNTIME`.
+pub(crate) fn new_runtime(py: Python) -> PyResult<PyModule> {
+    let runtime = PyModule::new(py, "_hyperspeed_sandbox")?;
+    runtime.add(py, "_expire", py_fn!(py, expire()))?;
+    py.run(SANDBOX_RUNTIME, Some(&runtime.dict(py)), None)?;
+    Ok(runtime)
+}
+
+/// Calls `function` from the sandbox runtime with the watchdog timing it.
+pub(crate) fn call_guarded<A>(py: Python, runtime: &PyModule, config: &SandboxConfig, function: &str, args: A) -> PyResult<PyObject>
+where A: ToPyObject<ObjectType=PyTuple> {
+    let exception = runtime.get(py, "BudgetExceeded")?;
+    let sys = py.import("sys")?;
+    let trace = sys.call(py, "gettrace", NoArgs, None)?;
+    let thread = current_thread(py)?;
+    let watchdog = watchdog();
+    let outer = watchdog.calls.lock().unwrap().insert(thread, Guarded {
+        deadline: config.time_budget.map(|budget| Instant::now() + budget),
+        exception
+    });
+    watchdog.wake.notify_one();
+
+    let result = runtime.call(py, function, args, None);
+
+    // This all happens outside Python, so the exception that aborted the call can't skip it.
+    // A call from a script into another guarded call gets the outer one's budget back.
+    {
+        let mut calls = watchdog.calls.lock().unwrap();
+        match outer {
+            Some(outer) => calls.insert(thread, outer),
+            None => calls.remove(&thread)
+        };
+    }
+    // An exception raised just as the call returned would go off in whatever Python runs next,
+    // so it is set off here instead. Clearing it with `PyThreadState_SetAsyncExc` leaves the
+    // interpreter checking for one that never comes, which stalls the next call.
+    let _ = py.run("pass", None, None);
+    sys.call(py, "settrace", (trace,), None)?;
+    result
+}
+
+/// The Python half of the sandbox. It is run once into a private module, and the interpreter
+/// calls `load` and `run_guarded` from it.
+pub(crate) const SANDBOX_RUNTIME: &str = r#"
+import ast
+import builtins
+import sys
+
+
+class ImportDenied(ImportError):
+    pass
+
+
+# Why the current call is being aborted. The watchdog raises the class itself, so the message
+# can't be passed in.
+_overrun = ["script went over its budget"]
+
+
+class BudgetExceeded(BaseException):
+    def __init__(self, *args):
+        super().__init__(*(args or _overrun))
+
+
+class SandboxViolation(Exception):
+    pass
+
+
+_SAFE_BUILTINS = [
+    'abs', 'all', 'any', 'ascii', 'bin', 'bool', 'bytearray', 'bytes', 'callable', 'chr',
+    'classmethod', 'complex', 'dict', 'divmod', 'enumerate', 'filter', 'float', 'format',
+    'frozenset', 'hasattr', 'hash', 'hex', 'int', 'isinstance', 'issubclass', 'iter', 'len',
+    'list', 'map', 'max', 'min', 'next', 'object', 'oct', 'ord', 'pow', 'print', 'property',
+    'range', 'repr', 'reversed', 'round', 'set', 'slice', 'sorted', 'staticmethod', 'str', 'sum',
+    'super', 'tuple', 'zip', 'None', 'True', 'False', 'NotImplemented', 'Ellipsis',
+]
+
+# Attribute names that start with an underscore are how sandboxed code usually climbs back out
+# (`().__class__.__base__.__subclasses__()`), so they are rejected apart from these few.
+_ALLOWED_PRIVATE = frozenset(['__init__', '__name__', '__doc__'])
+
+# Public attributes that still lead out of the sandbox. Frames and tracebacks reach the globals
+# and builtins of unsandboxed code, `mro` reaches `BaseException`, and `format`/`format_map`
+# evaluate attribute lookups written inside the format string, where the AST check can't see them.
+_DENIED = frozenset([
+    'gi_frame', 'gi_code', 'cr_frame', 'ag_frame', 'tb_frame', 'tb_next',
+    'f_back', 'f_globals', 'f_locals', 'f_builtins', 'f_code',
+    'mro', 'format', 'format_map',
+])
+
+
+def _check_name(name):
+    if (name.startswith('_') and name not in _ALLOWED_PRIVATE) or name in _DENIED:
+        raise SandboxViolation("access to '%s' is not allowed in sandboxed scripts" % name)
+
+
+def _safe_getattr(obj, name, *default):
+    _check_name(name)
+    return getattr(obj, name, *default)
+
+
+def make_builtins(allowed_imports):
+    safe = {name: getattr(builtins, name) for name in _SAFE_BUILTINS}
+    # Only `Exception` subclasses are handed out: with `BaseException` in reach, a script could
+    # alias it (`E = BaseException; except E:`) and swallow `BudgetExceeded`.
+    for name, value in vars(builtins).items():
+        if isinstance(value, type) and issubclass(value, Exception):
+            safe[name] = value
+    allowed = frozenset(allowed_imports)
+
+    def guarded_import(name, globals=None, locals=None, fromlist=(), level=0):
+        if level != 0 or name not in allowed:
+            raise ImportDenied("import of '%s' is not allowed in sandboxed scripts" % name)
+        return builtins.__import__(name, globals, locals, fromlist, level)
+
+    safe['__import__'] = guarded_import
+    safe['__build_class__'] = builtins.__build_class__
+    safe['getattr'] = _safe_getattr
+    return safe
+
+
+def check_source(tree, filename):
+    for node in ast.walk(tree):
+        if isinstance(node, ast.Attribute):
+            _check_name(node.attr)
+        elif isinstance(node, ast.Name) and node.id.startswith('__'):
+            _check_name(node.id)
+        elif isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef)) and node.name.startswith('__'):
+            # Special methods like `__del__` and `__exit__` are called by the interpreter, outside
+            # of any guarded call.
+            _check_name(node.name)
+        elif isinstance(node, ast.ExceptHandler):
+            # A bare `except:` would swallow the budget check and let the script keep running.
+            caught = node.type
+            names = caught.elts if isinstance(caught, ast.Tuple) else [caught]
+            for name in names:
+                if name is None or (isinstance(name, ast.Name) and name.id == 'BaseException'):
+                    raise SandboxViolation(
+                        "%s:%d: sandboxed scripts can't catch BaseException" % (filename, node.lineno))
+
+
+def run_guarded(func, args, max_lines, max_seconds):
+    # The watchdog aborts the call, and the previous trace function is put back by the caller
+    # once it returns. Raising from the tracer would turn tracing off for the rest of the call.
+    if max_seconds is not None:
+        _overrun[0] = "script ran for longer than %.3f seconds" % max_seconds
+    lines = [0]
+
+    def tracer(frame, event, arg):
+        if event in ('call', 'line'):
+            lines[0] += 1
+            if max_lines is not None and lines[0] == max_lines + 1:
+                _overrun[0] = "script executed more than %d lines" % max_lines
+                _expire()
+        return tracer
+
+    sys.settrace(tracer)
+    return func(*args)
+
+
+def load(namespace, source, filename, allowed_imports, max_lines, max_seconds):
+    tree = ast.parse(source, filename)
+    check_source(tree, filename)
+    code = compile(tree, filename, 'exec')
+    namespace['__builtins__'] = make_builtins(allowed_imports)
+    namespace['__file__'] = filename
+    run_guarded(exec, (code, namespace), max_lines, max_seconds)
+"#;
@@ -0,0 +1,60 @@
+use super::PythonInterpreter;
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::{spawn, JoinHandle};
+
+type ScriptJob = Box<dyn FnOnce(&mut PythonInterpreter) + Send>;
+
+/// Runs an interpreter on its own thread. Work is sent to it through a queue and runs in the
+/// order it was sent, so scripts never block the engine's tick.
+pub struct ScriptThread {
+    jobs: Option<Sender<ScriptJob>>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl ScriptThread {
+    pub fn spawn(mut interpreter: PythonInterpreter) -> Self {
+        let (jobs, queue) = channel::<ScriptJob>();
+        let handle = spawn(move || {
+            for job in queue {
+                job(&mut interpreter);
+            }
+        });
+        ScriptThread {
+            jobs: Some(jobs),
+            handle: Some(handle)
+        }
+    }
+
+    /// Queues work for the script thread without waiting for it.
+    pub fn send<F>(&self, job: F)
+    where F: FnOnce(&mut PythonInterpreter) + Send + 'static {
+        // The thread only stops once this handle is dropped, so the queue can't be closed here
+        // unless a previous job panicked.
+        if let Some(ref jobs) = self.jobs {
+            if jobs.send(Box::new(job)).is_err() {
+                println!("Script thread has exited. The job was dropped.");
+            }
+        }
+    }
+
+    /// Queues work for the script thread. The result can be collected from the returned channel
+    /// once the job has run, for example on the next tick.
+    pub fn call<F, R>(&self, job: F) -> Receiver<R>
+    where F: FnOnce(&mut PythonInterpreter) -> R + Send + 'static, R: Send + 'static {
+        let (result_tx, result_rx) = channel();
+        self.send(move |interpreter| {
+            let _ = result_tx.send(job(interpreter));
+        });
+        result_rx
+    }
+}
+
+impl Drop for ScriptThread {
+    fn drop(&mut self) {
+        // Closing the queue lets the thread finish the remaining jobs and exit.
+        self.jobs = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
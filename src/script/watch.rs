@@ -0,0 +1,65 @@
+use super::ScriptID;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Keeps track of the source file behind each loaded script, and notices when one of them has
+/// been modified on disk.
+#[derive(Debug, Default)]
+pub struct ScriptWatcher {
+    files: HashMap<ScriptID, WatchedFile>
+}
+
+#[derive(Debug)]
+struct WatchedFile {
+    path: PathBuf,
+    modified: Option<SystemTime>
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl ScriptWatcher {
+    pub fn new() -> Self {
+        ScriptWatcher {
+            files: HashMap::new()
+        }
+    }
+
+    /// Starts (or restarts) watching the source file of a script. The file's current modification
+    /// time is taken as the baseline, so the script isn't reported as changed straight away.
+    pub fn watch<P: Into<PathBuf>>(&mut self, script: ScriptID, path: P) {
+        let path = path.into();
+        let modified = modified_time(&path);
+        self.files.insert(script, WatchedFile { path, modified });
+    }
+
+    pub fn unwatch(&mut self, script: ScriptID) {
+        self.files.remove(&script);
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+
+    pub fn path(&self, script: ScriptID) -> Option<&Path> {
+        self.files.get(&script).map(|f| f.path.as_path())
+    }
+
+    /// Returns every script whose source file has changed since the last call. A file that
+    /// can't be read (it is probably being written) is skipped until it can be.
+    pub fn changed(&mut self) -> Vec<ScriptID> {
+        let mut changed = vec![];
+        for (script, file) in self.files.iter_mut() {
+            let modified = modified_time(&file.path);
+            if modified.is_some() && modified != file.modified {
+                file.modified = modified;
+                changed.push(*script);
+            }
+        }
+        changed.sort();
+        changed
+    }
+}
@@ -13,7 +13,7 @@ impl ViewSystem {
     pub fn new(use_cameras: bool, filter: Option<Vec<String>>) -> Self {
         ViewSystem {
             use_cameras,
-            filter: filter.unwrap_or(Vec::new())
+            filter: filter.unwrap_or_default()
         }
     }
 }
@@ -25,7 +25,7 @@ impl<'a> System<'a> for ViewSystem {
     ReadStorage<'a, Visible>,
     WriteViewMap<'a>);
 
-    fn run(&mut self, (connections, cameras, positions, visible, mut views): Self::SystemData) {
+    fn run(&mut self, (connections, _cameras, positions, visible, mut views): Self::SystemData) {
         if self.use_cameras {
             unimplemented!()
         } else {
@@ -39,16 +39,30 @@ impl<'a> System<'a> for ViewSystem {
                 view
             };
 
-            let should_filter = self.filter.len() > 0;
+            let should_filter = !self.filter.is_empty();
 
-            for conn in &connections.connections {
-                if should_filter {
-                    if !self.filter.contains(&conn.key) {
-                        continue;
-                    }
+            for conn in connections.players() {
+                if should_filter && !self.filter.contains(&conn.key) {
+                    continue;
                 }
                 views.insert(conn.key.clone(), view.clone());
             }
+
+            // Spectators following a player see exactly what that player sees, and nothing when
+            // the player gets no view. The rest get a free camera, which sees everything. Either
+            // way, the filter applies to spectators too.
+            for conn in connections.spectators() {
+                if should_filter && !self.filter.contains(&conn.key) {
+                    continue;
+                }
+                let spectated = match conn.follow {
+                    Some(ref key) => views.get(key).cloned(),
+                    None => Some(view.clone())
+                };
+                if let Some(spectated) = spectated {
+                    views.insert(conn.key.clone(), spectated);
+                }
+            }
         }
     }
 }
\ No newline at end of file
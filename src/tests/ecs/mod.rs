@@ -0,0 +1,697 @@
+//! The tests here involve the ECS side of the engine: blueprints, components and the world.
+
+use crate::core::{BlueprintRegistry, BlueprintFormat, BlueprintError};
+
+const PREFABS: &str = r#"{
+    "enemy": { "components": { "health": { "hp": 100, "armor": 2 }, "visible": { "sprite": 3 } } },
+    "orc": { "extends": "enemy", "components": { "health": { "hp": 50 } } },
+    "loop_a": { "extends": "loop_b" },
+    "loop_b": { "extends": "loop_a" }
+}"#;
+
+#[test]
+fn prefab_overrides_are_merged_over_parent() {
+    let mut registry = BlueprintRegistry::new();
+    registry.load_str(PREFABS, BlueprintFormat::Json, "test").unwrap();
+
+    let orc = registry.resolve("orc").unwrap();
+    assert_eq!(orc["health"]["hp"], 50);
+    assert_eq!(orc["health"]["armor"], 2);
+    assert_eq!(orc["visible"]["sprite"], 3);
+}
+
+#[test]
+fn prefab_inheritance_cycle_is_an_error() {
+    let mut registry = BlueprintRegistry::new();
+    registry.load_str(PREFABS, BlueprintFormat::Json, "test").unwrap();
+
+    match registry.resolve("loop_a") {
+        Err(BlueprintError::InheritanceCycle(_)) => (),
+        other => panic!("expected an inheritance cycle, got {:?}", other)
+    }
+}
+
+#[test]
+fn registered_component_round_trips_through_registry() {
+    use crate::components::Visible;
+    use crate::core::ComponentRegistry;
+    use specs::{World, Builder};
+
+    let mut registry = ComponentRegistry::new();
+    registry.register::<Visible>();
+    let mut world = World::new();
+    registry.get("visible").unwrap().register_with(&mut world);
+
+    let entity = world.create_entity().with(Visible { sprite: 7 }).build();
+    let components = registry.serialize_entity(&world, entity).unwrap();
+    assert_eq!(components["visible"]["sprite"], 7);
+
+    let copy = world.create_entity().build();
+    registry.deserialize_entity(&mut world, copy, components).unwrap();
+    assert_eq!(world.read_storage::<Visible>().get(copy).unwrap().sprite, 7);
+}
+
+#[test]
+fn snapshot_restores_components_and_migrates_old_versions() {
+    use crate::core::{ComponentRegistry, NamedComponent, Snapshot};
+    use specs::{Builder, Component, VecStorage, World};
+    use serde_json::Value;
+
+    #[derive(Serialize, Deserialize)]
+    struct Health {
+        hp: u32,
+        #[serde(default)]
+        armor: u32
+    }
+
+    impl Component for Health {
+        type Storage = VecStorage<Self>;
+    }
+
+    impl NamedComponent for Health {
+        const NAME: &'static str = "health";
+        const VERSION: u32 = 2;
+
+        fn migrate(_from_version: u32, mut value: Value) -> Result<Value, String> {
+            // Version 1 called the field "hit_points".
+            let hp = value["hit_points"].take();
+            Ok(json!({ "hp": hp }))
+        }
+    }
+
+    let mut registry = ComponentRegistry::new();
+    registry.register::<Health>();
+    let mut world = World::new();
+    world.register::<Health>();
+    world.create_entity().with(Health { hp: 10, armor: 1 }).build();
+
+    let saved = Snapshot::capture(&world, &registry, 3).unwrap().to_json().unwrap();
+    let mut old: Value = serde_json::from_str(&saved).unwrap();
+    old["components"]["health"] = json!(1);
+    old["entities"][0]["health"] = json!({ "hit_points": 25 });
+
+    let snapshot = Snapshot::from_json(&old.to_string()).unwrap();
+    assert_eq!(snapshot.tick, 3);
+    snapshot.restore(&mut world, &registry).unwrap();
+
+    use specs::Join;
+    let health = world.read_storage::<Health>();
+    let restored: Vec<_> = health.join().map(|h| (h.hp, h.armor)).collect();
+    assert_eq!(restored, vec![(25, 0)]);
+}
+
+#[test]
+fn connections_move_between_rooms_and_return_when_destroyed() {
+    use crate::core::{Engine, MasterController, SystemExecutor, Connection, ConnectionRole, RoomError};
+
+    struct Idle;
+    impl MasterController for Idle {
+        type ObserverEvent = ();
+    }
+
+    let mut engine = Engine::<()>::new().with_mc(Idle).build().unwrap();
+    engine.world.connections.push(Connection::new("alice", ConnectionRole::Player));
+    engine.create_room("match", SystemExecutor::new(), Idle).unwrap();
+    assert_eq!(engine.create_room("match", SystemExecutor::new(), Idle), Err(RoomError::AlreadyExists("match".to_string())));
+
+    engine.move_to_room("alice", Some("match")).unwrap();
+    assert!(!engine.world.connections.contains("alice"));
+    assert_eq!(engine.rooms().room_of("alice").map(|id| id.as_str()), Some("match"));
+    engine.tick().unwrap();
+    assert_eq!(engine.rooms().get("match").unwrap().tick_count(), 1);
+
+    engine.destroy_room("match").unwrap();
+    assert!(engine.world.connections.contains("alice"));
+    assert_eq!(engine.rooms().room_of("alice"), None);
+    assert_eq!(engine.move_to_room("bob", None), Err(RoomError::UnknownConnection("bob".to_string())));
+}
+
+#[test]
+fn matchmaker_keeps_parties_together_and_respects_rules() {
+    use crate::core::{Matchmaker, MatchmakingQueue, QueueConfig, QueueEntry, SameRegion, RatingWithin};
+
+    let mut matchmaker = Matchmaker::new().with_queue(QueueConfig::new("duel", 2)
+        .with_rule(SameRegion)
+        .with_rule(RatingWithin { max_difference: 100.0, widen_per_tick: 10.0 }));
+    let mut queue = MatchmakingQueue::new();
+    queue.join("duel", QueueEntry::new("a", 1000.0).in_region("eu")).unwrap();
+    queue.join("duel", QueueEntry::new("b", 1050.0).in_region("us")).unwrap();
+    queue.join("duel", QueueEntry::new("c", 1500.0).in_region("eu")).unwrap();
+    queue.join("duel", QueueEntry::new("d", 1040.0).in_region("eu")).unwrap();
+
+    let matches = matchmaker.find_matches(&mut queue);
+    assert_eq!(matches.len(), 1);
+    let keys: Vec<&str> = matches[0].players.iter().map(|p| p.key.as_str()).collect();
+    assert_eq!(keys, vec!["a", "d"]);
+    assert_eq!(queue.len("duel"), 2);
+
+    // After long enough, the rating gap is allowed to close.
+    queue.join("duel", QueueEntry::new("e", 1000.0).in_region("eu")).unwrap();
+    queue.tick = 100;
+    assert_eq!(matchmaker.find_matches(&mut queue).len(), 1);
+    assert_eq!(queue.position("b"), Some(("duel", 0)));
+
+    let mut squads = Matchmaker::new().with_queue(QueueConfig::new("squad", 3));
+    let mut queue = MatchmakingQueue::new();
+    queue.join("squad", QueueEntry::new("x", 0.0).in_party("p")).unwrap();
+    queue.join("squad", QueueEntry::new("y", 0.0)).unwrap();
+    queue.join("squad", QueueEntry::new("z", 0.0).in_party("p")).unwrap();
+    queue.join("squad", QueueEntry::new("w", 0.0)).unwrap();
+    let matches = squads.find_matches(&mut queue);
+    let keys: Vec<&str> = matches[0].players.iter().map(|p| p.key.as_str()).collect();
+    assert_eq!(keys, vec!["x", "z", "y"]);
+
+    // A party that says how big it is waits for its last member, even when a match could be
+    // made without it.
+    let mut queue = MatchmakingQueue::new();
+    queue.join("squad", QueueEntry::new("q1", 0.0).in_party_of("q", 2)).unwrap();
+    queue.join("squad", QueueEntry::new("s1", 0.0)).unwrap();
+    queue.join("squad", QueueEntry::new("s2", 0.0)).unwrap();
+    assert!(squads.find_matches(&mut queue).is_empty());
+    queue.tick = 1;
+    queue.join("squad", QueueEntry::new("q2", 0.0).in_party_of("q", 2)).unwrap();
+    let matches = squads.find_matches(&mut queue);
+    let keys: Vec<&str> = matches[0].players.iter().map(|p| p.key.as_str()).collect();
+    assert_eq!(keys, vec!["q1", "q2", "s1"]);
+}
+
+#[test]
+fn outgoing_messages_only_reach_connections_in_the_world() {
+    use crate::core::{World, SystemExecutor, Connection, ConnectionRole, ClientView, ClientMessage, Outbox, Outbound};
+    use crate::utils::ViewMap;
+
+    let mut world = World::new(SystemExecutor::new().build());
+    world.ecs_world.add_resource(ViewMap::new());
+    world.ecs_world.add_resource(Outbox::new());
+    world.connections.push(Connection::new("a", ConnectionRole::Player));
+    {
+        let mut outbox = world.ecs_world.write_resource::<Outbox>();
+        outbox.send_message("a", ClientMessage { tag: "hello".to_string(), data: serde_json::Value::Null });
+        // "b" is playing in another room.
+        outbox.send_message("b", ClientMessage { tag: "secret".to_string(), data: serde_json::Value::Null });
+        outbox.broadcast_message(ClientMessage { tag: "all".to_string(), data: serde_json::Value::Null });
+    }
+    {
+        let mut views = world.ecs_world.write_resource::<ViewMap>();
+        views.insert("a".to_string(), ClientView::new());
+        views.insert("b".to_string(), ClientView::new());
+    }
+
+    let sent: Vec<(String, String)> = world.take_outgoing().into_iter()
+        .map(|(key, outbound)| match outbound {
+            Outbound::Message(message) => (key, message.tag),
+            Outbound::View(_) => (key, "view".to_string())
+        })
+        .collect();
+    let expected: Vec<(String, String)> = vec![("a", "hello"), ("a", "all"), ("a", "view")].into_iter()
+        .map(|(key, tag)| (key.to_string(), tag.to_string()))
+        .collect();
+    assert_eq!(sent, expected);
+}
+
+#[test]
+fn lobby_runs_ready_check_and_countdown_before_handing_over() {
+    use crate::core::{World, SystemExecutor, MasterController, EngineInstruction, Connection, ConnectionRole, Input,
+                      LobbyController, LobbyConfig, LobbyState, Outbox, Outbound};
+    use crate::utils::{InputMap, ViewMap};
+    use std::collections::VecDeque;
+
+    struct Game {
+        started: bool,
+        over: bool
+    }
+    impl MasterController for Game {
+        type ObserverEvent = ();
+        fn start(&mut self, _world: &mut World, _delta_time: f64) {
+            self.started = true;
+        }
+        fn tick(&mut self, _world: &mut World, _delta_time: f64) -> EngineInstruction {
+            if self.over { EngineInstruction::Restart } else { EngineInstruction::Run { run_dispatcher: true } }
+        }
+    }
+
+    let mut world = World::new(SystemExecutor::new().build());
+    world.ecs_world.add_resource(ViewMap::new());
+    world.ecs_world.add_resource(Outbox::new());
+    let mut lobby = LobbyController::new(LobbyConfig::new(2, 2).countdown(1.0), Game { started: false, over: false });
+    lobby.start(&mut world, 0.0);
+
+    world.connections.push(Connection::new("a", ConnectionRole::Player));
+    lobby.tick(&mut world, 0.1);
+    assert_eq!(lobby.status().state, LobbyState::WaitingForPlayers);
+    world.take_outgoing();
+    world.connections.push(Connection::new("b", ConnectionRole::Player));
+    lobby.tick(&mut world, 0.1);
+    assert_eq!(lobby.status().state, LobbyState::ReadyCheck { remaining: 30.0 });
+    let sent = world.take_outgoing();
+    assert_eq!(sent.len(), 2);
+    match sent[0] {
+        (ref key, Outbound::Message(ref message)) => {
+            assert_eq!(key, "a");
+            assert_eq!(message.tag, "lobby");
+            assert_eq!(message.data["state"]["state"], "ReadyCheck");
+        },
+        _ => panic!("expected the lobby status")
+    }
+
+    let mut inputs = InputMap::new();
+    for key in &["a", "b"] {
+        inputs.insert(key.to_string(), VecDeque::from(vec![Input::Key("r".to_string())]));
+    }
+    world.queue_inputs(inputs);
+    lobby.tick(&mut world, 0.1);
+    assert_eq!(lobby.status().state, LobbyState::Countdown { remaining: 1.0 });
+    assert!(world.pending_inputs().is_empty());
+
+    lobby.tick(&mut world, 0.6);
+    assert!(!lobby.game().started);
+    lobby.tick(&mut world, 0.6);
+    assert_eq!(lobby.status().state, LobbyState::InGame);
+    assert!(lobby.game().started);
+
+    lobby.game().over = true;
+    lobby.tick(&mut world, 0.1);
+    assert_eq!(lobby.status().state, LobbyState::PostGame { remaining: 10.0 });
+}
+
+#[test]
+fn engine_errors_are_returned_instead_of_panicking() {
+    use crate::core::{Engine, MasterController};
+    use crate::error::HyperspeedError;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    struct Idle;
+    impl MasterController for Idle {
+        type ObserverEvent = ();
+    }
+
+    match Engine::<()>::new().build() {
+        Err(HyperspeedError::MissingMasterController) => {},
+        other => panic!("expected a missing master controller, got {:?}", other.err())
+    }
+    match Engine::<()>::new().with_mc(Idle).with_replay("/nonexistent/replay.jsonl").build() {
+        Err(HyperspeedError::Replay(_)) => {},
+        other => panic!("expected the replay to fail to open, got {:?}", other.err())
+    }
+
+    let mut engine = Engine::<()>::new().with_mc(Idle).on_address(SocketAddr::from(([127, 0, 0, 1], 0))).build().unwrap();
+    // Without a server there are no connections to wait for, which isn't an error.
+    engine.tick().unwrap();
+    engine.start_server().unwrap();
+    assert_ne!(engine.local_addrs()[0].port(), 0);
+    engine.tick().unwrap();
+    assert!(engine.shutdown("test over", Duration::from_secs(5)).is_clean());
+    engine.tick().unwrap();
+}
+
+#[test]
+fn prefab_instance_adds_every_component_or_nothing() {
+    use crate::components::Visible;
+    use crate::core::{Blueprint, ComponentRegistry};
+    use specs::{World, Join};
+
+    let mut components = ComponentRegistry::new();
+    components.register::<Visible>();
+    let mut world = World::new();
+    world.register::<Visible>();
+    let mut registry = BlueprintRegistry::new();
+    registry.load_str(PREFABS, BlueprintFormat::Json, "test").unwrap();
+    registry.load_str(r#"{ "marker": { "components": { "visible": { "sprite": 4 } } } }"#, BlueprintFormat::Json, "test").unwrap();
+
+    let marker = registry.instantiate("marker", &components).unwrap().add_to_world(&mut world).unwrap();
+    assert_eq!(world.read_storage::<Visible>().get(marker).unwrap().sprite, 4);
+
+    // `health` isn't registered, so the orc is rejected before anything is created.
+    match registry.instantiate("orc", &components).unwrap().add_to_world(&mut world) {
+        Err(BlueprintError::UnknownComponent { ref component, .. }) if component == "health" => (),
+        other => panic!("expected an unknown component, got {:?}", other)
+    }
+    world.maintain();
+    assert_eq!((&world.entities()).join().count(), 1);
+}
+
+#[test]
+fn scripts_spawn_blueprints_through_the_engine() {
+    use crate::components::Visible;
+    use crate::core::{Engine, MasterController};
+    use crate::script::PythonInterpreter;
+    use specs::Join;
+    use std::fs;
+
+    struct Idle;
+    impl MasterController for Idle {
+        type ObserverEvent = ();
+    }
+
+    let dir = std::env::temp_dir();
+    let path = dir.join("hyperspeed_spawner.py");
+    fs::write(&path, "import hyperspeed\n\ndef spawn_two():\n    hyperspeed.spawn('marker')\n    hyperspeed.spawn('marker')\n").unwrap();
+
+    let mut engine = Engine::<()>::new().with_mc(Idle).with_scripts(PythonInterpreter::new()).build().unwrap();
+    engine.register::<Visible>();
+    engine.blueprints().load_str(r#"{ "marker": { "components": { "visible": { "sprite": 4 } } } }"#, BlueprintFormat::Json, "test").unwrap();
+    {
+        let scripts = engine.scripts().unwrap();
+        scripts.include(dir.to_str().unwrap()).unwrap();
+        let script = scripts.load_module("hyperspeed_spawner").unwrap();
+        scripts.call(script, "spawn_two", cpython::NoArgs).unwrap();
+    }
+
+    engine.tick().unwrap();
+    let sprites: Vec<u64> = engine.world.ecs_world.read_storage::<Visible>().join().map(|v| v.sprite).collect();
+    assert_eq!(sprites, vec![4, 4]);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn script_spawn_requests_go_to_the_world_whose_interpreter_made_them() {
+    use crate::components::Visible;
+    use crate::core::{Engine, MasterController, SystemExecutor};
+    use crate::script::PythonInterpreter;
+    use specs::Join;
+    use std::fs;
+
+    struct Idle;
+    impl MasterController for Idle {
+        type ObserverEvent = ();
+    }
+
+    let dir = std::env::temp_dir();
+    for (name, blueprint) in &[("hyperspeed_spawn_lobby", "lobby_marker"), ("hyperspeed_spawn_match", "match_marker")] {
+        fs::write(dir.join(format!("{}.py", name)), format!("import hyperspeed\n\ndef spawn():\n    hyperspeed.spawn('{}')\n", blueprint)).unwrap();
+    }
+
+    let mut engine = Engine::<()>::new().with_mc(Idle).with_scripts(PythonInterpreter::new()).build().unwrap();
+    engine.register::<Visible>();
+    engine.blueprints().load_str(r#"{ "lobby_marker": { "components": { "visible": { "sprite": 1 } } },
+                                       "match_marker": { "components": { "visible": { "sprite": 2 } } } }"#, BlueprintFormat::Json, "test").unwrap();
+    engine.create_room("match", SystemExecutor::new(), Idle).unwrap();
+    let mut room_scripts = PythonInterpreter::new();
+    room_scripts.include(dir.to_str().unwrap()).unwrap();
+    let room_script = room_scripts.load_module("hyperspeed_spawn_match").unwrap();
+    {
+        let scripts = engine.scripts().unwrap();
+        scripts.include(dir.to_str().unwrap()).unwrap();
+        let script = scripts.load_module("hyperspeed_spawn_lobby").unwrap();
+        scripts.call(script, "spawn", cpython::NoArgs).unwrap();
+    }
+    room_scripts.call(room_script, "spawn", cpython::NoArgs).unwrap();
+
+    // The engine's tick only takes its own interpreter's request.
+    engine.tick().unwrap();
+    engine.spawn_room_script_requests_from("match", &mut room_scripts).unwrap();
+    let lobby: Vec<u64> = engine.world.ecs_world.read_storage::<Visible>().join().map(|v| v.sprite).collect();
+    assert_eq!(lobby, vec![1]);
+    let room_world = &engine.rooms().get("match").unwrap().world.ecs_world;
+    let room: Vec<u64> = room_world.read_storage::<Visible>().join().map(|v| v.sprite).collect();
+    assert_eq!(room, vec![2]);
+
+    for name in &["hyperspeed_spawn_lobby", "hyperspeed_spawn_match"] {
+        fs::remove_file(dir.join(format!("{}.py", name))).unwrap();
+    }
+}
+
+#[test]
+fn engine_reloads_watched_scripts_between_ticks() {
+    use crate::core::{Engine, MasterController};
+    use crate::script::PythonInterpreter;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    struct Idle;
+    impl MasterController for Idle {
+        type ObserverEvent = ();
+    }
+
+    let dir = std::env::temp_dir();
+    let path = dir.join("hyperspeed_engine_watch.py");
+    fs::write(&path, "value = 1\n").unwrap();
+
+    let mut engine = Engine::<()>::new().with_mc(Idle).with_scripts(PythonInterpreter::new()).build().unwrap();
+    let script = {
+        let scripts = engine.scripts().unwrap();
+        scripts.set_watch_mode(true);
+        scripts.include(dir.to_str().unwrap()).unwrap();
+        scripts.load_module("hyperspeed_engine_watch").unwrap()
+    };
+
+    fs::write(&path, "value = 2\n").unwrap();
+    // Some filesystems only store modification times to the second.
+    fs::File::options().write(true).open(&path).unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+    engine.tick().unwrap();
+
+    let scripts = engine.scripts().unwrap();
+    let value = scripts.get_value(script, "value").unwrap();
+    assert_eq!(scripts.convert::<u32>(&value).unwrap(), Box::new(2));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn failed_snapshot_restore_leaves_the_world_untouched() {
+    use crate::components::Visible;
+    use crate::core::{ComponentRegistry, Snapshot};
+    use specs::{World, Builder, Join};
+    use serde_json::Value;
+
+    let mut registry = ComponentRegistry::new();
+    registry.register::<Visible>();
+    let mut world = World::new();
+    world.register::<Visible>();
+    world.create_entity().with(Visible { sprite: 1 }).build();
+    world.create_entity().with(Visible { sprite: 2 }).build();
+
+    // The first entity is fine, the second one isn't.
+    let mut saved: Value = serde_json::from_str(&Snapshot::capture(&world, &registry, 0).unwrap().to_json().unwrap()).unwrap();
+    saved["entities"][1]["visible"] = json!({ "sprite": "not a number" });
+    let snapshot = Snapshot::from_json(&saved.to_string()).unwrap();
+    assert!(snapshot.restore(&mut world, &registry).is_err());
+
+    let sprites: Vec<u64> = world.read_storage::<Visible>().join().map(|v| v.sprite).collect();
+    assert_eq!(sprites, vec![1, 2]);
+}
+
+#[test]
+fn autosaves_rotate_and_recovery_falls_back_to_older_snapshots() {
+    use crate::components::Visible;
+    use crate::core::{Engine, MasterController, AutosaveConfig, latest_valid_snapshot};
+    use crate::error::HyperspeedError;
+    use specs::{Builder, Join};
+    use std::fs;
+
+    struct Idle;
+    impl MasterController for Idle {
+        type ObserverEvent = ();
+    }
+
+    let directory = std::env::temp_dir().join("hyperspeed_autosave_test");
+    let _ = fs::remove_dir_all(&directory);
+    let config = AutosaveConfig::new(&directory).every_ticks(1).keep(2);
+
+    let mut engine = Engine::<()>::new().with_mc(Idle).with_autosave(config.clone()).build().unwrap();
+    engine.world.ecs_world.create_entity().with(Visible { sprite: 9 }).build();
+    for _ in 0..5 {
+        engine.tick().unwrap();
+    }
+    // Dropping the engine waits for the writer thread to finish.
+    drop(engine);
+
+    let mut names: Vec<String> = fs::read_dir(&directory).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    // Files are named by save number, then tick.
+    let snapshot = |save: u64, tick: u64| format!("snapshot-{:020}-{:020}.json", save, tick);
+    assert_eq!(names, vec![snapshot(4, 4), snapshot(5, 5)]);
+
+    // A write that was cut short only ever leaves a temporary file, which recovery ignores and
+    // the next autosaver removes.
+    let unfinished = directory.join(format!("{}.tmp", snapshot(6, 6)));
+    fs::write(&unfinished, "{ \"format\": 1, \"ti").unwrap();
+    assert_eq!(latest_valid_snapshot(&directory).unwrap().1.tick, 5);
+
+    let mut engine = Engine::<()>::new().with_mc(Idle).with_autosave(config.clone()).build().unwrap();
+    assert!(!unfinished.exists());
+    assert_eq!(engine.recover().unwrap(), Some(5));
+    assert_eq!(engine.tick_count(), 5);
+    let sprites: Vec<u64> = engine.world.ecs_world.read_storage::<Visible>().join().map(|v| v.sprite).collect();
+    assert_eq!(sprites, vec![9]);
+    drop(engine);
+
+    // The newest snapshot parses but can't be restored, so the one before it is used instead.
+    let newest = directory.join(snapshot(5, 5));
+    let mut broken: serde_json::Value = serde_json::from_str(&fs::read_to_string(&newest).unwrap()).unwrap();
+    broken["entities"][0]["unregistered"] = json!({});
+    fs::write(&newest, broken.to_string()).unwrap();
+    let mut engine = Engine::<()>::new().with_mc(Idle).with_autosave(config.clone()).build().unwrap();
+    assert_eq!(engine.recover().unwrap(), Some(4));
+    drop(engine);
+
+    // When every snapshot is broken, recovery fails, and so does starting the server with it.
+    fs::write(directory.join(snapshot(4, 4)), "not a snapshot").unwrap();
+    let mut engine = Engine::<()>::new().with_mc(Idle).with_autosave(config.clone()).with_recovery(true).build().unwrap();
+    assert!(engine.recover().is_err());
+    match engine.start_server() {
+        Err(HyperspeedError::Snapshot(_)) => {},
+        other => panic!("expected recovery to fail, got {:?}", other.err())
+    }
+    drop(engine);
+
+    // An engine that starts over saves lower ticks, which still replace the older saves.
+    let mut engine = Engine::<()>::new().with_mc(Idle).with_autosave(config.clone()).build().unwrap();
+    engine.tick().unwrap();
+    engine.tick().unwrap();
+    drop(engine);
+    let mut names: Vec<String> = fs::read_dir(&directory).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec![snapshot(6, 1), snapshot(7, 2)]);
+    assert_eq!(latest_valid_snapshot(&directory).unwrap().1.tick, 2);
+
+    // A recording that can't be created stops the server starting too.
+    let mut engine = Engine::<()>::new().with_mc(Idle).with_recording("/nonexistent/recording.jsonl").build().unwrap();
+    match engine.start_server() {
+        Err(HyperspeedError::Io(_)) => {},
+        other => panic!("expected the recording to fail, got {:?}", other.err())
+    }
+
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn replaying_a_recording_rebuilds_the_same_world() {
+    use crate::components::Visible;
+    use crate::core::{Engine, World, MasterController, EngineInstruction, SystemExecutor};
+    use specs::{Builder, Join};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    // Every tick leaves an entity behind that depends on the tick's time, so a replay only
+    // matches if it used the recorded times.
+    struct Stamper;
+    impl MasterController for Stamper {
+        type ObserverEvent = ();
+        fn tick(&mut self, world: &mut World, delta_time: f64) -> EngineInstruction {
+            world.ecs_world.create_entity().with(Visible { sprite: (delta_time * 1_000_000.0) as u64 }).build();
+            EngineInstruction::Run { run_dispatcher: true }
+        }
+    }
+
+    fn stamps(engine: &Engine<()>) -> Vec<u64> {
+        engine.world.ecs_world.read_storage::<Visible>().join().map(|v| v.sprite).collect()
+    }
+
+    let path = std::env::temp_dir().join("hyperspeed_replay_test.jsonl");
+    let mut live = Engine::<()>::new().with_mc(Stamper)
+        .on_address(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .with_recording(&path)
+        .build().unwrap();
+    live.create_room("arena", SystemExecutor::new(), Stamper).unwrap();
+    live.start_server().unwrap();
+    let _client = TcpStream::connect(live.local_addrs()[0]).unwrap();
+    for _ in 0..500 {
+        live.tick().unwrap();
+        if live.world.connections.contains("default_key") {
+            break;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    live.move_to_room("default_key", Some("arena")).unwrap();
+    for _ in 0..3 {
+        sleep(Duration::from_millis(5));
+        live.tick().unwrap();
+    }
+    live.shutdown("recording over", Duration::from_secs(5));
+
+    let mut replay = Engine::<()>::new().with_mc(Stamper).with_replay(&path).build().unwrap();
+    replay.create_room("arena", SystemExecutor::new(), Stamper).unwrap();
+    replay.start_server().unwrap();
+    while !replay.replay_finished() {
+        replay.tick().unwrap();
+    }
+
+    assert_eq!(replay.tick_count(), live.tick_count());
+    assert_eq!(stamps(&replay), stamps(&live));
+    assert_eq!(replay.rooms().room_of("default_key").map(|id| id.as_str()), Some("arena"));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn spectators_get_the_view_of_the_player_they_follow() {
+    use crate::core::{Connection, ConnectionCollection, ConnectionRole};
+    use crate::systems::ViewSystem;
+    use crate::utils::ViewMap;
+    use crate::components::{Camera, Position, Visible};
+    use specs::{World, RunNow};
+
+    let mut connections = ConnectionCollection::new();
+    connections.push(Connection::new("a", ConnectionRole::Player));
+    connections.push(Connection::new("b", ConnectionRole::Player));
+    let spectators = [("watch_a", Some("a")), ("watch_b", Some("b")), ("watch_ghost", Some("ghost")), ("free", None), ("hidden", None)];
+    for (key, follow) in &spectators {
+        let mut spectator = Connection::new(key, ConnectionRole::Spectator);
+        spectator.follow = follow.map(|f| f.to_string());
+        connections.push(spectator);
+    }
+
+    let mut world = World::new();
+    world.register::<Camera>();
+    world.register::<Position>();
+    world.register::<Visible>();
+    world.add_resource(connections);
+    world.add_resource(ViewMap::new());
+
+    // "b" and "hidden" aren't let through the filter. Spectators following a player without a
+    // view, or a key that isn't connected, get nothing instead of the free camera.
+    let filter = ["a", "watch_a", "watch_b", "watch_ghost", "free"].iter().map(|k| k.to_string()).collect();
+    ViewSystem::new(false, Some(filter)).run_now(&world.res);
+    let views = world.read_resource::<ViewMap>();
+    let mut keys: Vec<&str> = views.keys().map(|k| k.as_str()).collect();
+    keys.sort();
+    assert_eq!(keys, vec!["a", "free", "watch_a"]);
+}
+
+#[test]
+fn matchmaking_turns_away_oversized_parties_and_keeps_room_ids_apart() {
+    use crate::core::{Engine, MasterController, Matchmaker, MatchmakingQueue, QueueConfig, QueueEntry, QueueError,
+                      SystemExecutor, RoomError, MATCH_ROOM_PREFIX};
+
+    struct Idle;
+    impl MasterController for Idle {
+        type ObserverEvent = ();
+    }
+
+    let mut matchmaker = Matchmaker::new().with_queue(QueueConfig::new("duel", 2));
+    let mut queue = MatchmakingQueue::new();
+    queue.set_match_size("duel", 2);
+    queue.join("duel", QueueEntry::new("a", 0.0).in_party("p")).unwrap();
+    queue.join("duel", QueueEntry::new("b", 0.0).in_party("p")).unwrap();
+    assert_eq!(queue.join("duel", QueueEntry::new("c", 0.0).in_party("p")),
+               Err(QueueError::PartyTooLarge { party: "p".to_string(), match_size: 2 }));
+    assert_eq!(queue.position("c"), None);
+    assert!(queue.join("duel", QueueEntry::new("c", 0.0).in_party_of("trio", 3)).is_err());
+
+    // A match that couldn't be started goes back to the front of its queue.
+    queue.tick = 7;
+    queue.join("duel", QueueEntry::new("d", 0.0)).unwrap();
+    let found = matchmaker.find_matches(&mut queue).remove(0);
+    assert!(found.id.starts_with(MATCH_ROOM_PREFIX));
+    queue.put_back(found);
+    let waiting: Vec<(&str, u64)> = queue.entries("duel").iter().map(|e| (e.key.as_str(), e.queued_at)).collect();
+    assert_eq!(waiting, vec![("a", 0), ("b", 0), ("d", 7)]);
+
+    let mut engine = Engine::<()>::new().with_mc(Idle)
+        .with_matchmaking(Matchmaker::new().with_queue(QueueConfig::new("duel", 2)),
+                          |_| (SystemExecutor::new(), Box::new(Idle) as Box<dyn MasterController<ObserverEvent=()>>))
+        .build().unwrap();
+    let id = format!("{}duel-1", MATCH_ROOM_PREFIX);
+    assert_eq!(engine.create_room(&id, SystemExecutor::new(), Idle), Err(RoomError::ReservedID(id.clone())));
+    // The engine tells the queue how big each match is.
+    let mut queue = engine.world.ecs_world.write_resource::<MatchmakingQueue>();
+    queue.join("duel", QueueEntry::new("x", 0.0).in_party("big")).unwrap();
+    queue.join("duel", QueueEntry::new("y", 0.0).in_party("big")).unwrap();
+    assert!(queue.join("duel", QueueEntry::new("z", 0.0).in_party("big")).is_err());
+}
@@ -11,14 +11,87 @@ fn can_send_message_and_get_response() {
 
 }
 
+/// A message for tests that only need a server to exist.
+#[derive(Debug)]
+struct Blank;
+
+impl std::convert::TryFrom<crate::network::ClientMessage> for Blank {
+    type Error = ();
+
+    fn try_from(_: crate::network::ClientMessage) -> Result<Blank, ()> {
+        Ok(Blank)
+    }
+}
+
+impl From<Blank> for crate::network::ClientMessage {
+    fn from(_: Blank) -> Self {
+        crate::network::ClientMessage { bytes: bytes::BytesMut::new() }
+    }
+}
+
 #[test]
 fn server_handles_invalid_address() {
+    use crate::network::Server;
+    use crate::utils::bind::BindError;
 
+    match Server::<Blank>::new().address("not an address").port(0).build() {
+        Err(BindError::InvalidAddress(address)) => assert_eq!(address, "not an address"),
+        other => panic!("expected an invalid address error, got {:?}", other.err())
+    }
 }
 
 #[test]
 fn server_handles_invalid_port() {
+    use crate::network::Server;
+    use crate::utils::bind::BindError;
+    use std::io;
+
+    // Port 0 gets a free port, and the server says which.
+    let server = Server::<Blank>::new().address("127.0.0.1").port(0).build().unwrap();
+    let address = server.local_addrs()[0];
+    assert_ne!(address.port(), 0);
 
+    match Server::<Blank>::new().address("127.0.0.1").port(address.port()).build() {
+        Err(BindError::Io { address: taken, error }) => {
+            assert_eq!(taken, address);
+            assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        },
+        other => panic!("expected the taken port to be refused, got {:?}", other.err())
+    }
+}
+
+#[test]
+fn servers_take_the_first_free_port_in_a_range() {
+    use crate::core::{Engine, MasterController};
+    use crate::network::Server;
+    use crate::utils::bind::BindError;
+    use std::net::{IpAddr, TcpListener};
+
+    struct Idle;
+    impl MasterController for Idle {
+        type ObserverEvent = ();
+    }
+
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let first = taken.local_addr().unwrap().port();
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    // Whoever has the next port, it is taken as well.
+    let _next = TcpListener::bind((ip, first + 1));
+    match Server::<Blank>::new().listen_on_range(ip, first..=first + 1).build() {
+        Err(BindError::NoFreePort { ports, .. }) => assert_eq!(ports, first..=first + 1),
+        other => panic!("expected no free port, got {:?}", other.err())
+    }
+
+    let server = Server::<Blank>::new().address("127.0.0.1").port_range(first..=first + 20).build().unwrap();
+    let port = server.local_addrs()[0].port();
+    assert!(port > first + 1 && port <= first + 20);
+    drop(server);
+
+    let mut engine = Engine::<()>::new().with_mc(Idle).on_address_range(ip, first..=first + 20).build().unwrap();
+    engine.start_server().unwrap();
+    let port = engine.local_addrs()[0].port();
+    assert!(port > first + 1 && port <= first + 20);
+    engine.shutdown("test over", Duration::from_secs(5));
 }
 
 #[test]
@@ -35,9 +108,777 @@ use std::time::Duration;
 
 
 // 5 microseconds is pretty fast, so adjust this if your computer is slow.
-const LATENCY_CAP: Duration = 5 * DURATION::MICROSECOND;
+const LATENCY_CAP: Duration = Duration::from_micros(5);
 
 #[test]
 fn server_latency_below_threshold() {
 
 }
+
+#[test]
+fn udp_reliable_messages_survive_loss_and_arrive_in_order() {
+    use crate::network::{Channel, Packet, UdpConnection};
+    use bytes::Bytes;
+    use std::time::Instant;
+
+    let start = Instant::now();
+    let mut sender = UdpConnection::new(start);
+    let mut receiver = UdpConnection::new(start);
+    let mut received = vec![];
+
+    sender.send(Channel::ReliableOrdered, Bytes::from(&b"first"[..])).unwrap();
+    // The first packet is lost.
+    assert_eq!(sender.poll_outgoing(start).len(), 1);
+
+    sender.send(Channel::ReliableOrdered, Bytes::from(&b"second"[..])).unwrap();
+    for packet in sender.poll_outgoing(start + Duration::from_millis(10)) {
+        received.extend(receiver.receive(Packet::decode(&packet).unwrap(), start));
+    }
+    // "second" can't be delivered before "first".
+    assert!(received.is_empty());
+
+    for packet in sender.poll_outgoing(start + Duration::from_millis(150)) {
+        received.extend(receiver.receive(Packet::decode(&packet).unwrap(), start));
+    }
+    assert_eq!(received, vec![Bytes::from(&b"first"[..]), Bytes::from(&b"second"[..])]);
+
+    // Once the receiver's ack arrives, nothing is left to resend.
+    for packet in receiver.poll_outgoing(start) {
+        sender.receive(Packet::decode(&packet).unwrap(), start);
+    }
+    assert_eq!(sender.unacked(), 0);
+}
+
+#[test]
+fn udp_sequenced_messages_drop_stale_ones() {
+    use crate::network::{Channel, Packet, UdpConnection};
+    use bytes::Bytes;
+    use std::time::Instant;
+
+    let now = Instant::now();
+    let mut sender = UdpConnection::new(now);
+    let mut receiver = UdpConnection::new(now);
+    sender.send(Channel::UnreliableSequenced, Bytes::from(&b"old"[..])).unwrap();
+    let old = sender.poll_outgoing(now).remove(0);
+    sender.send(Channel::UnreliableSequenced, Bytes::from(&b"new"[..])).unwrap();
+    let new = sender.poll_outgoing(now).remove(0);
+
+    assert_eq!(receiver.receive(Packet::decode(&new).unwrap(), now), vec![Bytes::from(&b"new"[..])]);
+    assert!(receiver.receive(Packet::decode(&old).unwrap(), now).is_empty());
+}
+
+#[test]
+fn udp_reliable_messages_far_ahead_are_refused() {
+    use crate::network::{Channel, Packet, UdpConnection};
+    use bytes::Bytes;
+    use std::time::Instant;
+
+    let now = Instant::now();
+    let mut receiver = UdpConnection::new(now);
+    let data = |seq: u16, id: u16| Packet::Data {
+        seq,
+        ack: 0,
+        ack_bits: 0,
+        messages: vec![(Channel::ReliableOrdered, id, Bytes::from(&b"early"[..]))]
+    };
+
+    assert!(receiver.receive(data(1, 5), now).is_empty());
+    assert_eq!(receiver.buffered(), 1);
+    assert!(receiver.receive(data(2, 4000), now).is_empty());
+    assert_eq!(receiver.buffered(), 1);
+}
+
+#[test]
+fn websocket_handshake_answers_with_the_accept_key() {
+    use crate::utils::websocket::{accept_key, parse_handshake, Handshake};
+
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+    let request = b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    assert_eq!(parse_handshake(&request[..20]), Handshake::Incomplete);
+    match parse_handshake(request) {
+        Handshake::Accept { response, consumed } => {
+            assert_eq!(consumed, request.len());
+            assert!(String::from_utf8(response).unwrap().contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        },
+        other => panic!("expected the upgrade to be accepted, got {:?}", other)
+    }
+}
+
+#[test]
+fn websocket_frames_are_unmasked_and_reassembled() {
+    use crate::utils::websocket::{decode_frame, encode_frame, Frame, FrameAssembler, FrameEvent, Opcode};
+
+    // A masked text frame saying "Hello", as a client sends it.
+    let masked = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+    assert!(decode_frame(&masked[..6]).unwrap().is_none());
+    let (frame, used) = decode_frame(&masked).unwrap().unwrap();
+    assert_eq!(used, masked.len());
+    assert_eq!(frame, Frame { fin: true, opcode: Opcode::Text, payload: b"Hello".to_vec() });
+
+    let mut assembler = FrameAssembler::new();
+    let start = Frame { fin: false, opcode: Opcode::Binary, payload: b"Hel".to_vec() };
+    let end = Frame { fin: true, opcode: Opcode::Continuation, payload: b"lo".to_vec() };
+    assert_eq!(assembler.handle(start).unwrap(), FrameEvent::Nothing);
+    assert_eq!(assembler.handle(end.clone()).unwrap(), FrameEvent::Message(b"Hello".to_vec()));
+    assert!(assembler.handle(end).is_err());
+
+    let ping = Frame { fin: true, opcode: Opcode::Ping, payload: b"hi".to_vec() };
+    assert_eq!(assembler.handle(ping).unwrap(), FrameEvent::Reply(encode_frame(Opcode::Pong, b"hi")));
+    let close = Frame { fin: true, opcode: Opcode::Close, payload: vec![] };
+    assert_eq!(assembler.handle(close).unwrap(), FrameEvent::Close);
+}
+
+/// Connects to a WebSocket listener and waits for the upgrade to be accepted.
+fn upgrade_websocket(address: std::net::SocketAddr) -> std::net::TcpStream {
+    use std::io::{Read, Write};
+
+    let mut client = std::net::TcpStream::connect(address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0; 1];
+        client.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    assert!(String::from_utf8(response).unwrap().starts_with("HTTP/1.1 101"));
+    client
+}
+
+#[test]
+fn websocket_clients_are_upgraded_answered_and_closed() {
+    use crate::network::Server;
+    use std::io::{Read, Write};
+
+    let handle = Server::<Blank>::new()
+        .listen_on("127.0.0.1:0".parse().unwrap())
+        .websocket_on("127.0.0.1:0".parse().unwrap())
+        .build()
+        .unwrap()
+        .run();
+    let mut client = upgrade_websocket(handle.websocket_addrs()[0]);
+
+    // A masked ping with an empty mask is answered with a pong carrying the same payload.
+    client.write_all(&[0x89, 0x82, 0, 0, 0, 0, b'h', b'i']).unwrap();
+    let mut pong = [0; 4];
+    client.read_exact(&mut pong).unwrap();
+    assert_eq!(pong, [0x8A, 2, b'h', b'i']);
+
+    // After a close frame the server hangs up.
+    client.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+    let mut rest = [0; 16];
+    assert_eq!(client.read(&mut rest).unwrap(), 0);
+
+    handle.shutdown(|| Blank, Duration::from_secs(1));
+}
+
+#[test]
+fn plain_tcp_clients_are_pinged_and_time_out_when_quiet() {
+    use crate::network::Server;
+    use crate::utils::heartbeat::HeartbeatConfig;
+    use std::io::{BufRead, BufReader, Write};
+    use std::time::Instant;
+
+    let handle = Server::<Blank>::new()
+        .listen_on("127.0.0.1:0".parse().unwrap())
+        .heartbeat(HeartbeatConfig::new(Duration::from_millis(100), Duration::from_millis(600)))
+        .build()
+        .unwrap()
+        .run();
+    let client = std::net::TcpStream::connect(handle.local_addrs()[0]).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(client.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "{\"tag\":\"heartbeat\",\"data\":{\"id\":0}}\n");
+    (&client).write_all(b"{\"heartbeat\":0}\n").unwrap();
+
+    // Answering counts as being heard from. After that the client goes quiet and is dropped,
+    // however many pings it is sent first.
+    let answered = Instant::now();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        assert!(line.starts_with("{\"tag\":\"heartbeat\""));
+    }
+    assert!(answered.elapsed() >= Duration::from_millis(400));
+
+    handle.shutdown(|| Blank, Duration::from_secs(1));
+}
+
+#[test]
+fn tls_reload_keeps_open_connections_and_serves_new_ones_the_new_certificate() {
+    use crate::utils::tls::TlsConfig;
+    use rustls::{Certificate, ClientConfig, ClientSession, StreamOwned};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    let cert_path = std::env::temp_dir().join("hyperspeed_tls_test_cert.pem");
+    let key_path = std::env::temp_dir().join("hyperspeed_tls_test_key.pem");
+    let write_cert = || {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        cert.serialize_der().unwrap()
+    };
+    let connect = |address, trusted: &[u8]| {
+        let mut config = ClientConfig::new();
+        config.root_store.add(&Certificate(trusted.to_vec())).unwrap();
+        let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        StreamOwned::new(ClientSession::new(&Arc::new(config), name), TcpStream::connect(address).unwrap())
+    };
+    let echo = |stream: &mut StreamOwned<ClientSession, TcpStream>| {
+        let mut buffer = [0; 4];
+        stream.write_all(b"ping").and_then(|_| stream.read_exact(&mut buffer)).map(|_| buffer)
+    };
+
+    let first_cert = write_cert();
+    let tls = TlsConfig::from_pem_files(&cert_path, &key_path).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server_tls = tls.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let tls = server_tls.clone();
+            thread::spawn(move || {
+                let mut stream = match tls.accept(stream.unwrap()) {
+                    Ok(stream) => stream,
+                    Err(_) => return
+                };
+                let mut buffer = [0; 4];
+                while stream.read_exact(&mut buffer).is_ok() && stream.write_all(&buffer).is_ok() {}
+            });
+        }
+    });
+
+    let mut old = connect(address, &first_cert);
+    assert_eq!(&echo(&mut old).unwrap(), b"ping");
+
+    let second_cert = write_cert();
+    tls.reload().unwrap();
+    assert_eq!(&echo(&mut old).unwrap(), b"ping");
+    assert!(echo(&mut connect(address, &first_cert)).is_err());
+    assert_eq!(&echo(&mut connect(address, &second_cert)).unwrap(), b"ping");
+}
+
+#[test]
+fn heartbeats_measure_rtt_and_time_out_quiet_clients() {
+    use crate::core::Connection;
+    use crate::utils::heartbeat::{Heartbeat, HeartbeatConfig, HeartbeatReply};
+    use std::time::{Duration, Instant};
+
+    let start = Instant::now();
+    let connection = Connection::default();
+    let config = HeartbeatConfig::new(Duration::from_secs(5), Duration::from_secs(30));
+    let mut heartbeat = Heartbeat::new(config, connection.link.clone(), start);
+
+    assert!(heartbeat.poll(start + Duration::from_secs(1)).is_none());
+    let ping = heartbeat.poll(start + Duration::from_secs(5)).unwrap();
+    heartbeat.answered(&HeartbeatReply { heartbeat: ping.id }, start + Duration::from_millis(5080));
+    assert_eq!(connection.rtt(), Some(Duration::from_millis(80)));
+
+    // An old answer doesn't count as a new measurement.
+    heartbeat.answered(&HeartbeatReply { heartbeat: ping.id }, start + Duration::from_secs(6));
+    assert_eq!(connection.rtt(), Some(Duration::from_millis(80)));
+
+    assert!(!heartbeat.timed_out(start + Duration::from_secs(35)));
+    assert!(heartbeat.timed_out(start + Duration::from_secs(37)));
+}
+
+#[test]
+fn connection_stats_report_rates_and_export_to_prometheus() {
+    use crate::core::{Connection, ConnectionCollection, ConnectionRole, ConnectionStats, render_prometheus};
+    use std::time::{Duration, Instant};
+
+    let mut connections = ConnectionCollection::new();
+    let connection = Connection::new("slow", ConnectionRole::Player);
+    connections.push(connection.clone());
+    let mut stats = ConnectionStats::new();
+    let start = Instant::now();
+    stats.update(&connections, start);
+
+    connection.link.received(300);
+    connection.link.sent_message();
+    connection.link.sent_message();
+    connection.link.record_rtt(Duration::from_millis(120));
+    connection.link.set_send_queue(1);
+    stats.update(&connections, start + Duration::from_secs(2));
+
+    let slow = stats.get("slow").unwrap();
+    assert_eq!(slow.bytes_in_per_sec, 150.0);
+    assert_eq!(slow.messages_out_per_sec, 1.0);
+    assert_eq!(slow.rtt, Some(Duration::from_millis(120)));
+    assert_eq!(slow.send_queue, 1);
+    // Keys are login credentials, so they never show up in the exported metrics.
+    let rendered = render_prometheus(&stats);
+    assert!(rendered.contains("hyperspeed_connections 1\n"));
+    assert!(rendered.contains("hyperspeed_connection_rtt_seconds{stat=\"mean\"} 0.12\n"));
+    assert!(rendered.contains("hyperspeed_connection_bytes_in_per_second{stat=\"sum\"} 150\n"));
+    assert!(!rendered.contains("slow"));
+
+    connections.remove(&"slow".to_string());
+    stats.update(&connections, start + Duration::from_secs(3));
+    assert!(stats.is_empty());
+}
+
+#[test]
+fn rate_limiter_escalates_from_drop_to_warn_to_disconnect() {
+    use crate::utils::ratelimit::{BucketConfig, EscalationPolicy, RateLimitAction, RateLimitConfig, RateLimitKind, RateLimiter};
+    use std::time::{Duration, Instant};
+
+    let policy = EscalationPolicy { warn_after: 2, disconnect_after: 3, forgive_after: Duration::from_secs(10) };
+    let config = RateLimitConfig::new(BucketConfig::new(2.0, 1.0), BucketConfig::new(100.0, 100.0)).policy(policy);
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(config, start);
+
+    assert!(limiter.check(10, start).is_ok());
+    assert!(limiter.check(10, start).is_ok());
+    let actions: Vec<RateLimitAction> = (0..3).map(|_| limiter.check(10, start).unwrap_err().action).collect();
+    assert_eq!(actions, vec![RateLimitAction::Drop, RateLimitAction::Warn, RateLimitAction::Disconnect]);
+
+    // The buckets refill, and old violations are forgiven.
+    let later = start + Duration::from_secs(20);
+    assert!(limiter.check(10, later).is_ok());
+    let violation = limiter.check(500, later).unwrap_err();
+    assert_eq!((violation.kind, violation.action, violation.count), (RateLimitKind::Bytes, RateLimitAction::Drop, 1));
+}
+
+#[test]
+fn clients_over_their_rate_limit_are_reported_as_events() {
+    use crate::network::{ClientEvent, Server};
+    use crate::utils::ratelimit::{BucketConfig, EscalationPolicy, RateLimitAction, RateLimitConfig};
+    use std::io::{Read, Write};
+
+    let policy = EscalationPolicy { warn_after: 2, disconnect_after: 3, forgive_after: Duration::from_secs(10) };
+    let config = RateLimitConfig::new(BucketConfig::new(1.0, 0.001), BucketConfig::new(1000.0, 1000.0)).policy(policy);
+    let handle = Server::<Blank>::new()
+        .listen_on("127.0.0.1:0".parse().unwrap())
+        .websocket_on("127.0.0.1:0".parse().unwrap())
+        .rate_limit(config)
+        .build()
+        .unwrap()
+        .run();
+    let mut client = upgrade_websocket(handle.websocket_addrs()[0]);
+    let frame = [0x82, 0x81, 0, 0, 0, 0, 1];
+    for _ in 0..4 {
+        client.write_all(&frame).unwrap();
+    }
+    // The second message is dropped, the third gets a warning, and the fourth gets the client
+    // disconnected.
+    let mut header = [0; 2];
+    client.read_exact(&mut header).unwrap();
+    assert_eq!(header[0], 0x81);
+    let mut warning = vec![0; header[1] as usize];
+    client.read_exact(&mut warning).unwrap();
+    assert!(warning.starts_with(b"{\"tag\":\"rate_limited\""));
+    let mut rest = [0; 16];
+    assert_eq!(client.read(&mut rest).unwrap(), 0);
+
+    let actions: Vec<RateLimitAction> = handle.events().into_iter()
+        .map(|event| match event {
+            ClientEvent::RateLimited { violation, .. } => violation.action
+        })
+        .collect();
+    assert_eq!(actions, vec![RateLimitAction::Drop, RateLimitAction::Warn, RateLimitAction::Disconnect]);
+
+    handle.shutdown(|| Blank, Duration::from_secs(1));
+}
+
+#[test]
+fn udp_clients_over_their_rate_limit_are_warned_and_disconnected() {
+    use crate::network::{Channel, ClientEvent, Packet, Server, Transport, UdpConnection};
+    use crate::utils::ratelimit::{BucketConfig, EscalationPolicy, RateLimitAction, RateLimitConfig};
+    use bytes::Bytes;
+    use std::time::Instant;
+
+    let policy = EscalationPolicy { warn_after: 2, disconnect_after: 3, forgive_after: Duration::from_secs(10) };
+    let config = RateLimitConfig::new(BucketConfig::new(1.0, 0.001), BucketConfig::new(1000.0, 1000.0)).policy(policy);
+    let handle = Server::<Blank>::new()
+        .listen_on("127.0.0.1:0".parse().unwrap())
+        .transport(Transport::Udp)
+        .rate_limit(config)
+        .build()
+        .unwrap()
+        .run();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.connect(handle.local_addrs()[0]).unwrap();
+    socket.send(&Packet::Connect { salt: 7 }.encode()).unwrap();
+    let mut buffer = [0; 2048];
+    let len = socket.recv(&mut buffer).unwrap();
+    assert_eq!(Packet::decode(&buffer[..len]), Some(Packet::Accept { salt: 7 }));
+
+    let mut connection = UdpConnection::new(Instant::now());
+    for _ in 0..4 {
+        connection.send(Channel::Unreliable, Bytes::from(&b"x"[..])).unwrap();
+        for packet in connection.poll_outgoing(Instant::now()) {
+            socket.send(&packet).unwrap();
+        }
+    }
+    // The second message is dropped, the third gets a warning, and the fourth gets the client
+    // disconnected.
+    let mut received = vec![];
+    loop {
+        let len = socket.recv(&mut buffer).unwrap();
+        match Packet::decode(&buffer[..len]) {
+            Some(Packet::Disconnect) => break,
+            Some(packet) => received.extend(connection.receive(packet, Instant::now())),
+            None => panic!("the server sent a malformed packet")
+        }
+    }
+    assert_eq!(received.len(), 1);
+    assert!(received[0].starts_with(b"{\"tag\":\"rate_limited\""));
+
+    let actions: Vec<RateLimitAction> = handle.events().into_iter()
+        .map(|event| match event {
+            ClientEvent::RateLimited { violation, .. } => violation.action
+        })
+        .collect();
+    assert_eq!(actions, vec![RateLimitAction::Drop, RateLimitAction::Warn, RateLimitAction::Disconnect]);
+
+    handle.shutdown(|| Blank, Duration::from_secs(1));
+}
+
+#[test]
+fn full_send_queues_apply_their_overflow_policy() {
+    use crate::utils::queue::{send_queue, OverflowPolicy, SendError, SendQueueConfig};
+
+    // Odd numbers stand in for views, which newer ones supersede.
+    let is_view: fn(&u32) -> bool = |n| n % 2 == 1;
+
+    let (tx, mut rx) = send_queue(SendQueueConfig::new(3, OverflowPolicy::DropOldest), is_view);
+    for n in &[1, 2, 3] {
+        tx.send(*n).unwrap();
+    }
+    assert_eq!(tx.send(4), Ok(1));
+    assert_eq!(rx.drain(), Ok(vec![2, 3, 4]));
+
+    let (tx, mut rx) = send_queue(SendQueueConfig::new(3, OverflowPolicy::Coalesce), is_view);
+    for n in &[1, 2, 3] {
+        tx.send(*n).unwrap();
+    }
+    assert_eq!(tx.send(5), Ok(2));
+    assert_eq!(tx.send(6), Ok(0));
+    assert_eq!(tx.send(8), Ok(1));
+    assert_eq!(rx.drain(), Ok(vec![2, 5, 6]));
+
+    let (tx, mut rx) = send_queue(SendQueueConfig::new(1, OverflowPolicy::Disconnect), is_view);
+    tx.send(1).unwrap();
+    assert_eq!(tx.send(3), Err(SendError::Overflowed));
+    assert_eq!(rx.drain(), Err(SendError::Overflowed));
+    drop(rx);
+    assert!(tx.is_closed());
+}
+
+#[test]
+fn finished_send_queues_deliver_what_is_left_and_then_end() {
+    use crate::core::{ClientMessage, Goodbye};
+    use crate::utils::queue::{send_queue, SendError, SendQueueConfig};
+    use futures::Stream;
+
+    let (tx, rx) = send_queue(SendQueueConfig::default(), |_: &u32| false);
+    tx.send(1).unwrap();
+    tx.send(2).unwrap();
+    tx.finish();
+    assert_eq!(tx.send(3), Err(SendError::Closed));
+    assert!(!rx.is_finished() && !rx.overflowed());
+    // The stream ends after the last item, instead of waiting for more.
+    let delivered: Vec<u32> = rx.wait().map(|item| item.unwrap()).collect();
+    assert_eq!(delivered, vec![1, 2]);
+
+    // Dropping every sender finishes the queue too.
+    let (tx, mut rx) = send_queue(SendQueueConfig::default(), |_: &u32| false);
+    let other = tx.clone();
+    drop(tx);
+    assert!(!rx.is_finished());
+    drop(other);
+    assert_eq!(rx.drain(), Ok(vec![]));
+    assert!(rx.is_finished());
+
+    // A receiver that is already waiting wakes up when the last sender goes.
+    let (tx, rx) = send_queue(SendQueueConfig::default(), |_: &u32| false);
+    tx.send(1).unwrap();
+    let waiting = std::thread::spawn(move || rx.wait().map(|item| item.unwrap()).collect::<Vec<u32>>());
+    std::thread::sleep(Duration::from_millis(50));
+    drop(tx);
+    assert_eq!(waiting.join().unwrap(), vec![1]);
+
+    let goodbye = ClientMessage::from(&Goodbye::new("restarting")).unwrap();
+    assert_eq!(goodbye.tag, "goodbye");
+    assert_eq!(goodbye.data["reason"], "restarting");
+}
+
+#[test]
+fn spectators_are_capped_and_their_inputs_ignored() {
+    use crate::core::{Engine, MasterController, World, EngineInstruction, StreamData, Input};
+    use crate::utils::server::{ClientStream, read_message_from_stream, StreamReadResult};
+    use bytes::BytesMut;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpStream};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    // Holding the dispatcher back leaves every input waiting in the world, where the test can see it.
+    struct Hold;
+    impl MasterController for Hold {
+        type ObserverEvent = ();
+        fn tick(&mut self, _world: &mut World, _delta_time: f64) -> EngineInstruction {
+            EngineInstruction::Run { run_dispatcher: false }
+        }
+    }
+
+    // Clients log in with their key. Everyone but "player" watches the player.
+    fn login(stream: &mut dyn ClientStream) -> StreamData {
+        let mut buffer = BytesMut::from(vec![0u8; 64]);
+        match read_message_from_stream(stream, &mut buffer) {
+            StreamReadResult::ValidMessage(ref key) if key == "player" => StreamData::do_connect(key.clone()),
+            StreamReadResult::ValidMessage(key) => StreamData::do_spectate(key, Some("player".to_string())),
+            _ => StreamData::dont_connect()
+        }
+    }
+
+    fn tick_until<F: Fn(&Engine<()>) -> bool>(engine: &mut Engine<()>, done: F) {
+        for _ in 0..500 {
+            engine.tick().unwrap();
+            if done(engine) {
+                return;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!("the engine never got there");
+    }
+
+    let mut engine = Engine::<()>::new().with_mc(Hold)
+        .on_address(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .with_stream_handler(login)
+        .max_spectators(1)
+        .build().unwrap();
+    engine.start_server().unwrap();
+    let address = engine.local_addrs()[0];
+
+    let mut player = TcpStream::connect(address).unwrap();
+    player.write_all(b"player!!!").unwrap();
+    let mut watcher = TcpStream::connect(address).unwrap();
+    watcher.write_all(b"watcher!!!").unwrap();
+    tick_until(&mut engine, |e| e.world.connections.contains("player") && e.world.connections.contains("watcher"));
+
+    let mut extra = TcpStream::connect(address).unwrap();
+    extra.write_all(b"extra!!!").unwrap();
+    for _ in 0..30 {
+        engine.tick().unwrap();
+        sleep(Duration::from_millis(10));
+    }
+    assert!(!engine.world.connections.contains("extra"));
+    assert_eq!(engine.world.connections.spectators().count(), 1);
+
+    let input = br#"{"keys":["w"],"clicks":[]}!!!"#;
+    watcher.write_all(input).unwrap();
+    sleep(Duration::from_millis(50));
+    player.write_all(input).unwrap();
+    tick_until(&mut engine, |e| e.world.pending_inputs().contains_key("player"));
+    assert_eq!(engine.world.pending_inputs()["player"], vec![Input::Key("w".to_string())]);
+    assert!(!engine.world.pending_inputs().contains_key("watcher"));
+
+    // A client that hangs up is let go, without its last message being read again.
+    player.shutdown(Shutdown::Write).unwrap();
+    player.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut rest = Vec::new();
+    player.read_to_end(&mut rest).unwrap();
+    engine.tick().unwrap();
+    assert_eq!(engine.world.pending_inputs()["player"], vec![Input::Key("w".to_string())]);
+
+    engine.shutdown("test over", Duration::from_secs(5));
+}
+
+#[test]
+fn outbox_messages_arrive_whole_and_in_order_with_views() {
+    use crate::core::{ClientView, Engine, EngineInstruction, MasterController, OutboundMessage, Outbox, StreamData, World};
+    use crate::utils::ViewMap;
+    use crate::utils::server::ClientStream;
+    use std::io::{BufRead, BufReader};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[derive(Serialize)]
+    struct Score {
+        n: u64,
+        padding: String
+    }
+    impl OutboundMessage for Score {
+        const TAG: &'static str = "score";
+    }
+
+    #[derive(Serialize)]
+    struct Chat {
+        n: u64
+    }
+    impl OutboundMessage for Chat {
+        const TAG: &'static str = "chat";
+    }
+
+    const TICKS: u64 = 20;
+
+    // Every tick queues a score for the player, a broadcast and a view. Scores are bigger than
+    // a socket takes in one write.
+    struct Talk(u64);
+    impl MasterController for Talk {
+        type ObserverEvent = ();
+        fn tick(&mut self, world: &mut World, _delta_time: f64) -> EngineInstruction {
+            if self.0 < TICKS && world.connections.contains("player") {
+                {
+                    let mut outbox = world.ecs_world.write_resource::<Outbox>();
+                    outbox.send("player", &Score { n: self.0, padding: "x".repeat(1 << 20) }).unwrap();
+                    outbox.broadcast(&Chat { n: self.0 }).unwrap();
+                }
+                world.ecs_world.write_resource::<ViewMap>().insert("player".to_string(), ClientView::new());
+                self.0 += 1;
+            }
+            EngineInstruction::Run { run_dispatcher: false }
+        }
+    }
+
+    fn login(_: &mut dyn ClientStream) -> StreamData {
+        StreamData::do_connect("player".to_string())
+    }
+
+    let mut engine = Engine::<()>::new().with_mc(Talk(0))
+        .on_address(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .with_stream_handler(login)
+        .build().unwrap();
+    engine.start_server().unwrap();
+    let client = TcpStream::connect(engine.local_addrs()[0]).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+    // Reads every line but heartbeats as (tag, n), with views as ("view", 0), until the last chat.
+    let (done_tx, done_rx) = channel();
+    thread::spawn(move || {
+        // Starting late lets the socket's buffer fill up, so the server's writes come back short.
+        thread::sleep(Duration::from_millis(300));
+        let mut received = vec![];
+        for line in BufReader::new(client).lines() {
+            let line: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+            let tag = line["tag"].as_str().unwrap_or("view").to_string();
+            let n = line["data"]["n"].as_u64().unwrap_or(0);
+            if tag == "heartbeat" {
+                continue;
+            }
+            if tag == "score" {
+                assert_eq!(line["data"]["padding"].as_str().unwrap().len(), 1 << 20);
+            }
+            let last = tag == "chat" && n == TICKS - 1;
+            received.push((tag, n));
+            if last {
+                break;
+            }
+        }
+        let _ = done_tx.send(received);
+    });
+    let mut received = None;
+    for _ in 0..1000 {
+        engine.tick().unwrap();
+        if let Ok(lines) = done_rx.recv_timeout(Duration::from_millis(10)) {
+            received = Some(lines);
+            break;
+        }
+    }
+    let received = received.expect("the client never got every message");
+
+    // Messages all arrive in the order they were queued. Each tick's view comes after its
+    // messages, never in between them.
+    let messages: Vec<(String, u64)> = received.iter().filter(|(tag, _)| tag != "view").cloned().collect();
+    let expected: Vec<(String, u64)> = (0..TICKS)
+        .flat_map(|n| vec![("score".to_string(), n), ("chat".to_string(), n)])
+        .collect();
+    assert_eq!(messages, expected);
+    for (i, (tag, _)) in received.iter().enumerate() {
+        if tag == "view" {
+            assert_eq!(received[i - 1].0, "chat");
+        }
+    }
+
+    engine.shutdown("test over", Duration::from_secs(5));
+}
+
+#[test]
+fn shutdown_sends_queued_messages_then_the_final_ones_then_a_goodbye() {
+    use crate::core::{Engine, EngineInstruction, MasterController, OutboundMessage, Outbox, StreamData, World};
+    use crate::utils::server::ClientStream;
+    use std::io::{BufRead, BufReader};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread::sleep;
+
+    #[derive(Serialize)]
+    struct Chat {
+        n: u64
+    }
+    impl OutboundMessage for Chat {
+        const TAG: &'static str = "chat";
+    }
+
+    // The shutdown hook sends one last message, as a final save might to say it is done.
+    struct Farewell;
+    impl MasterController for Farewell {
+        type ObserverEvent = ();
+        fn tick(&mut self, _world: &mut World, _delta_time: f64) -> EngineInstruction {
+            EngineInstruction::Run { run_dispatcher: false }
+        }
+        fn shutdown(&mut self, world: &mut World) {
+            world.ecs_world.write_resource::<Outbox>().send("player", &Chat { n: 100 }).unwrap();
+        }
+    }
+
+    fn login(_: &mut dyn ClientStream) -> StreamData {
+        StreamData::do_connect("player".to_string())
+    }
+
+    let mut engine = Engine::<()>::new().with_mc(Farewell)
+        .on_address(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .with_stream_handler(login)
+        .build().unwrap();
+    engine.start_server().unwrap();
+    let client = TcpStream::connect(engine.local_addrs()[0]).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    for _ in 0..500 {
+        engine.tick().unwrap();
+        if engine.world.connections.contains("player") {
+            break;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    assert!(engine.world.connections.contains("player"));
+
+    // These are still waiting in the outbox when the engine shuts down.
+    {
+        let mut outbox = engine.world.ecs_world.write_resource::<Outbox>();
+        for n in 0..3 {
+            outbox.send("player", &Chat { n }).unwrap();
+        }
+    }
+    let report = engine.shutdown("test over", Duration::from_secs(5));
+    assert_eq!(report.clients, 1);
+
+    // Everything but heartbeats, as (tag, n) with the goodbye's reason in place of n, until the
+    // server hangs up.
+    let received: Vec<(String, String)> = BufReader::new(client).lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(&line.unwrap()).unwrap())
+        .filter(|line| line["tag"] != "heartbeat")
+        .map(|line| {
+            let tag = line["tag"].as_str().unwrap().to_string();
+            let detail = if tag == "goodbye" { line["data"]["reason"].as_str().unwrap().to_string() } else { line["data"]["n"].to_string() };
+            (tag, detail)
+        })
+        .collect();
+    let expected: Vec<(String, String)> = vec![("chat", "0"), ("chat", "1"), ("chat", "2"), ("chat", "100"), ("goodbye", "test over")]
+        .into_iter()
+        .map(|(tag, detail)| (tag.to_string(), detail.to_string()))
+        .collect();
+    assert_eq!(received, expected);
+}
@@ -24,3 +24,395 @@ fn evaluation_of_python_function_calling_works() {
 fn python_function_callback_works() {
 
 }
+
+#[test]
+fn script_watcher_detects_modified_source() {
+    use crate::script::ScriptWatcher;
+    use std::fs;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join("hyperspeed_watch_test.py");
+    fs::write(&path, "value = 1\n").unwrap();
+
+    let mut watcher = ScriptWatcher::new();
+    watcher.watch(0, &path);
+    assert!(watcher.changed().is_empty());
+
+    // Some filesystems only store modification times to the second.
+    sleep(Duration::from_millis(1100));
+    fs::write(&path, "value = 2\n").unwrap();
+    assert_eq!(watcher.changed(), vec![0]);
+    assert!(watcher.changed().is_empty());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn sandboxed_script_cannot_import_os() {
+    use crate::script::{PythonInterpreter, SandboxConfig, ScriptError};
+    use std::fs;
+
+    let path = std::env::temp_dir().join("hyperspeed_sandbox_import.py");
+    fs::write(&path, "import os\n").unwrap();
+
+    let mut py = PythonInterpreter::new();
+    match py.load_sandboxed("sandbox_import", &path, SandboxConfig::new()) {
+        Err(ScriptError::ImportDenied(_)) => (),
+        other => panic!("expected the import to be denied, got {:?}", other.map(|_| ()))
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn sandboxed_script_is_aborted_over_budget() {
+    use crate::script::{PythonInterpreter, SandboxConfig, ScriptError};
+    use std::fs;
+
+    let path = std::env::temp_dir().join("hyperspeed_sandbox_budget.py");
+    fs::write(&path, "def spin():\n    while True:\n        pass\n").unwrap();
+
+    let mut py = PythonInterpreter::new();
+    let script = py.load_sandboxed("sandbox_budget", &path, SandboxConfig::new().instruction_budget(Some(1000))).unwrap();
+    match py.call(script, "spin", cpython::NoArgs) {
+        Err(ScriptError::BudgetExceeded(_)) => (),
+        other => panic!("expected the call to be aborted, got {:?}", other.map(|_| ()))
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn python_interpreter_can_move_between_threads() {
+    use crate::script::{PythonInterpreter, ScriptThread};
+
+    fn assert_send<T: Send>() {}
+    assert_send::<PythonInterpreter>();
+
+    let thread = ScriptThread::spawn(PythonInterpreter::new());
+    let result = thread.call(|py| py.include("./examples").is_ok());
+    assert!(result.recv().unwrap());
+}
+
+#[test]
+fn script_errors_are_classified_by_kind() {
+    use crate::script::{PythonInterpreter, ScriptError};
+    use std::fs;
+
+    let dir = std::env::temp_dir();
+    fs::write(dir.join("hyperspeed_error_syntax.py"), "def broken(:\n    pass\n").unwrap();
+    fs::write(dir.join("hyperspeed_error_values.py"), "name = 'not a number'\n\ndef fail():\n    raise ValueError('nope')\n").unwrap();
+
+    let mut py = PythonInterpreter::new();
+    py.include(dir.to_str().unwrap()).unwrap();
+
+    match py.load_module("hyperspeed_error_syntax") {
+        Err(ScriptError::Syntax(_)) => (),
+        other => panic!("expected a syntax error, got {:?}", other)
+    }
+    match py.load_module("hyperspeed_error_no_such_module") {
+        Err(ScriptError::ModuleNotFound(_)) => (),
+        other => panic!("expected a missing module, got {:?}", other)
+    }
+
+    let script = py.load_module("hyperspeed_error_values").unwrap();
+    match py.call(script, "fail", cpython::NoArgs) {
+        Err(ScriptError::Runtime(info)) => assert!(info.message.starts_with("ValueError")),
+        other => panic!("expected a runtime error, got {:?}", other.map(|_| ()))
+    }
+    let name = py.get_value(script, "name").unwrap();
+    match py.convert::<i64>(&name) {
+        Err(ScriptError::Conversion(_)) => (),
+        other => panic!("expected a conversion error, got {:?}", other)
+    }
+
+    fs::remove_file(dir.join("hyperspeed_error_syntax.py")).unwrap();
+    fs::remove_file(dir.join("hyperspeed_error_values.py")).unwrap();
+}
+
+#[test]
+fn script_errors_point_at_the_raising_line() {
+    use crate::script::PythonInterpreter;
+    use std::fs;
+
+    let dir = std::env::temp_dir();
+    let path = dir.join("hyperspeed_error_location.py");
+    fs::write(&path, "def outer():\n    inner()\n\ndef inner():\n    raise RuntimeError('here')\n").unwrap();
+
+    let mut py = PythonInterpreter::new();
+    py.include(dir.to_str().unwrap()).unwrap();
+    let script = py.load_module("hyperspeed_error_location").unwrap();
+    let error = py.call(script, "outer", cpython::NoArgs).expect_err("the call should fail");
+
+    let location = error.info().and_then(|info| info.location.clone()).expect("the error should have a location");
+    assert!(location.file.ends_with("hyperspeed_error_location.py"));
+    assert_eq!(location.line, 5);
+    assert_eq!(error.script(), Some(script));
+    assert!(error.traceback().unwrap().contains("RuntimeError: here"));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn reload_passes_the_old_state_to_on_reload() {
+    use crate::script::PythonInterpreter;
+    use std::fs;
+
+    let dir = std::env::temp_dir();
+    let path = dir.join("hyperspeed_reload_state.py");
+    fs::write(&path, "counter = 41\n").unwrap();
+
+    let mut py = PythonInterpreter::new();
+    py.include(dir.to_str().unwrap()).unwrap();
+    let script = py.load_module("hyperspeed_reload_state").unwrap();
+
+    fs::write(&path, "counter = 0\n\ndef on_reload(old_state):\n    global counter\n    counter = old_state['counter'] + 1\n").unwrap();
+    py.reload(script).unwrap();
+
+    let counter = py.get_value(script, "counter").unwrap();
+    assert_eq!(*py.convert::<i64>(&counter).unwrap(), 42);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn failed_reload_keeps_the_old_module() {
+    use crate::script::{PythonInterpreter, ScriptError};
+    use cpython::{ObjectProtocol, Python};
+    use std::fs;
+
+    let dir = std::env::temp_dir();
+    let path = dir.join("hyperspeed_reload_rollback.py");
+    fs::write(&path, "version = 1\n").unwrap();
+
+    let mut py = PythonInterpreter::new();
+    py.include(dir.to_str().unwrap()).unwrap();
+    let script = py.load_module("hyperspeed_reload_rollback").unwrap();
+
+    fs::write(&path, "version = 2\nraise RuntimeError('broken update')\n").unwrap();
+    match py.reload(script) {
+        Err(ScriptError::Runtime(_)) => (),
+        other => panic!("expected the reload to fail, got {:?}", other)
+    }
+
+    let version = py.get_value(script, "version").unwrap();
+    assert_eq!(*py.convert::<i64>(&version).unwrap(), 1);
+    {
+        let gil = Python::acquire_gil();
+        let python = gil.python();
+        let cached = python.import("sys")
+            .and_then(|sys| sys.get(python, "modules"))
+            .and_then(|modules| modules.get_item(python, "hyperspeed_reload_rollback"))
+            .and_then(|module| module.getattr(python, "version"))
+            .and_then(|v| v.extract::<i64>(python))
+            .unwrap();
+        assert_eq!(cached, 1);
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+/// Writes `source` to a temporary file and loads it as a sandboxed script.
+fn load_sandboxed_source(name: &str, source: &str, sandbox: crate::script::SandboxConfig)
+    -> (crate::script::PythonInterpreter, crate::script::InterpreterResult<crate::script::ScriptID>) {
+    let path = std::env::temp_dir().join(format!("hyperspeed_{}.py", name));
+    std::fs::write(&path, source).unwrap();
+    let mut py = crate::script::PythonInterpreter::new();
+    let result = py.load_sandboxed(name, &path, sandbox);
+    std::fs::remove_file(&path).unwrap();
+    (py, result)
+}
+
+#[test]
+fn sandboxed_script_cannot_reach_frames() {
+    use crate::script::{SandboxConfig, ScriptError};
+
+    let escapes = [
+        ("sandbox_gi_frame", "def g():\n    yield 1\n\nframe = g().gi_frame\n"),
+        ("sandbox_f_back", "def g():\n    yield 1\n\ndef escape(gen):\n    return gen.f_back\n"),
+        ("sandbox_f_globals", "def escape(frame):\n    return frame.f_globals\n"),
+        ("sandbox_f_builtins", "def escape(frame):\n    return frame.f_builtins\n"),
+        ("sandbox_tb_frame", "def escape(e):\n    return e.tb_frame\n"),
+        ("sandbox_tb_next", "def escape(tb):\n    return tb.tb_next\n"),
+        ("sandbox_gi_code", "def escape(gen):\n    return gen.gi_code\n"),
+        ("sandbox_cr_frame", "async def c():\n    pass\n\ndef escape():\n    return c().cr_frame\n"),
+        ("sandbox_ag_frame", "async def a():\n    yield 1\n\ndef escape():\n    return a().ag_frame\n"),
+        ("sandbox_f_locals", "def escape(frame):\n    return frame.f_locals\n"),
+        ("sandbox_f_code", "def escape(frame):\n    return frame.f_code\n"),
+        ("sandbox_getattr_frame", "def g():\n    yield 1\n\nframe = getattr(g(), 'gi_frame')\n"),
+    ];
+    for (name, source) in escapes.iter() {
+        match load_sandboxed_source(name, source, SandboxConfig::new()).1 {
+            Err(ScriptError::SandboxViolation(_)) => (),
+            other => panic!("{} was not rejected, got {:?}", name, other)
+        }
+    }
+}
+
+#[test]
+fn sandboxed_script_cannot_format_attributes() {
+    use crate::script::{SandboxConfig, ScriptError};
+
+    let escapes = [
+        ("sandbox_format", "leak = '{0.__class__}'.format(())\n"),
+        ("sandbox_format_map", "leak = '{t.__class__}'.format_map({'t': ()})\n"),
+        ("sandbox_str_format", "leak = str.format('{0.__class__}', ())\n"),
+        ("sandbox_getattr_format", "leak = getattr('{0.__class__}', 'format')(())\n"),
+    ];
+    for (name, source) in escapes.iter() {
+        match load_sandboxed_source(name, source, SandboxConfig::new()).1 {
+            Err(ScriptError::SandboxViolation(_)) => (),
+            other => panic!("{} was not rejected, got {:?}", name, other)
+        }
+    }
+}
+
+#[test]
+fn sandboxed_script_cannot_catch_budget_through_an_alias() {
+    use crate::script::{SandboxConfig, ScriptError};
+
+    let spin = "def spin():\n    try:\n        while True:\n            pass\n    except E:\n        return 'escaped'\n";
+    // `BaseException` isn't handed to sandboxed scripts at all, so the alias fails to resolve.
+    match load_sandboxed_source("sandbox_alias", &format!("E = BaseException\n{}", spin), SandboxConfig::new()).1 {
+        Err(ScriptError::Runtime(info)) => assert!(info.message.starts_with("NameError")),
+        other => panic!("the alias was not rejected, got {:?}", other)
+    }
+    match load_sandboxed_source("sandbox_mro", &format!("E = Exception.mro()[1]\n{}", spin), SandboxConfig::new()).1 {
+        Err(ScriptError::SandboxViolation(_)) => (),
+        other => panic!("the mro lookup was not rejected, got {:?}", other)
+    }
+
+    let config = SandboxConfig::new().instruction_budget(Some(1000));
+    let (mut py, script) = load_sandboxed_source("sandbox_exception", &format!("E = Exception\n{}", spin), config);
+    match py.call(script.unwrap(), "spin", cpython::NoArgs) {
+        Err(ScriptError::BudgetExceeded(_)) => (),
+        other => panic!("expected the call to be aborted, got {:?}", other.map(|_| ()))
+    }
+}
+
+#[test]
+fn sandboxed_script_cannot_outlast_its_budget() {
+    use crate::script::{SandboxConfig, ScriptError};
+    use std::time::{Duration, Instant};
+
+    // The first abort turns tracing off, so the `finally` block has to be stopped by the watchdog.
+    let source = "def spin():\n    try:\n        while True:\n            pass\n    finally:\n        while True:\n            pass\n\n\
+                  def count():\n    try:\n        n = 0\n        while True:\n            n += 1\n    finally:\n        while True:\n            pass\n\n\
+                  def answer():\n    return 42\n";
+    let budgets = [
+        ("time", SandboxConfig::new().instruction_budget(None).time_budget(Some(Duration::from_millis(50)))),
+        ("lines", SandboxConfig::new().instruction_budget(Some(1000)).time_budget(None)),
+    ];
+    for (budget, config) in budgets.iter() {
+        let (mut py, script) = load_sandboxed_source(&format!("sandbox_outlast_{}", budget), source, config.clone());
+        let script = script.unwrap();
+        for function in ["spin", "count"].iter() {
+            let started = Instant::now();
+            match py.call(script, function, cpython::NoArgs) {
+                Err(ScriptError::BudgetExceeded(_)) => (),
+                other => panic!("{} with a {} budget was not aborted, got {:?}", function, budget, other.map(|_| ()))
+            }
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+        // Nothing from the aborted calls is left to go off in the next one.
+        let answer = py.call(script, "answer", cpython::NoArgs).unwrap();
+        assert_eq!(py.convert::<u32>(&answer).unwrap(), Box::new(42));
+    }
+}
+
+#[test]
+fn sandboxed_script_cannot_define_special_methods() {
+    use crate::script::{SandboxConfig, ScriptError};
+
+    let escapes = [
+        ("sandbox_del", "class C:\n    def __del__(self):\n        pass\n"),
+        ("sandbox_exit", "class C:\n    def __exit__(self, *args):\n        pass\n"),
+        ("sandbox_init_subclass", "class C:\n    def __init_subclass__(cls):\n        pass\n"),
+    ];
+    for (name, source) in escapes.iter() {
+        match load_sandboxed_source(name, source, SandboxConfig::new()).1 {
+            Err(ScriptError::SandboxViolation(_)) => (),
+            other => panic!("{} was not rejected, got {:?}", name, other)
+        }
+    }
+    let init = "class C:\n    def __init__(self):\n        self.x = 1\n";
+    assert!(load_sandboxed_source("sandbox_init", init, SandboxConfig::new()).1.is_ok());
+}
+
+#[test]
+fn sandboxed_script_needs_permission_to_spawn() {
+    use crate::script::{SandboxConfig, ScriptError};
+
+    match load_sandboxed_source("sandbox_spawn_denied", "import hyperspeed\n", SandboxConfig::new()).1 {
+        Err(ScriptError::ImportDenied(_)) => (),
+        other => panic!("expected the import to be denied, got {:?}", other)
+    }
+    let config = SandboxConfig::new().allow_import("hyperspeed");
+    assert!(load_sandboxed_source("sandbox_spawn_allowed", "import hyperspeed\n", config).1.is_ok());
+}
+
+#[test]
+fn entity_scripts_receive_inputs_collisions_and_updates() {
+    use crate::core::Input;
+    use crate::script::{PythonInterpreter, Script, ScriptEvent, ScriptEvents, ScriptError};
+    use crate::utils::InputMap;
+    use specs::{World, Builder};
+    use std::collections::VecDeque;
+    use std::fs;
+
+    let dir = std::env::temp_dir();
+    let path = dir.join("hyperspeed_entity_scripts.py");
+    fs::write(&path, r#"
+calls = []
+
+class Tracker:
+    def on_input(self, entity, input):
+        calls.append(('input', entity, input))
+
+    def on_collision(self, entity, other):
+        calls.append(('collision', entity, other))
+
+    def update(self, entity, dt):
+        calls.append(('update', entity, dt))
+
+class Silent:
+    pass
+
+class Broken:
+    @property
+    def update(self):
+        raise RuntimeError('lookup failed')
+"#).unwrap();
+
+    let mut py = PythonInterpreter::new();
+    py.include(dir.to_str().unwrap()).unwrap();
+    let script = py.load_module("hyperspeed_entity_scripts").unwrap();
+
+    let mut world = World::new();
+    world.register::<Script>();
+    let tracked = world.create_entity().with(Script::new(script, "Tracker").owned_by("p1")).build();
+    let silent = world.create_entity().with(Script::new(script, "Silent")).build();
+    world.create_entity().with(Script::new(script, "Broken")).build();
+
+    let mut inputs = InputMap::new();
+    let queued: VecDeque<Input> = vec![Input::Click { x: 1, y: 2 }, Input::Key("w".to_string())].into_iter().collect();
+    inputs.insert("p1".to_string(), queued);
+    world.add_resource(inputs);
+    world.add_resource::<ScriptEvents>(vec![ScriptEvent::Collision(tracked, silent)]);
+
+    let errors = py.run_entity_scripts(&mut world, 0.5);
+    assert_eq!(errors.len(), 1);
+    match errors[0] {
+        ScriptError::Runtime(ref info) => assert!(info.message.starts_with("RuntimeError")),
+        ref other => panic!("expected the property to raise, got {:?}", other)
+    }
+    assert!(world.read_resource::<ScriptEvents>().is_empty());
+
+    let calls = py.get_value(script, "calls").unwrap();
+    let calls = py.convert::<Vec<(String, u32, cpython::PyObject)>>(&calls).unwrap();
+    let kinds: Vec<(&str, u32)> = calls.iter().map(|(kind, entity, _)| (kind.as_str(), *entity)).collect();
+    assert_eq!(kinds, vec![
+        ("input", tracked.id()),
+        ("input", tracked.id()),
+        ("collision", tracked.id()),
+        ("update", tracked.id())
+    ]);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,80 @@
+//! Errors from opening listeners, shared by the engine's server and `network::Server`.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+
+/// Where a listener binds: an IP address and the ports to try, in order. The first one that is
+/// free is used, so a single port is a range of one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BindAddress {
+    pub ip: IpAddr,
+    pub ports: RangeInclusive<u16>
+}
+
+#[derive(Debug)]
+pub enum BindError {
+    /// The address couldn't be parsed as an IP address.
+    InvalidAddress(String),
+    /// The address couldn't be bound, for example because another program has the port.
+    Io {
+        address: SocketAddr,
+        error: io::Error
+    },
+    /// Every port in a range was taken.
+    NoFreePort {
+        ip: IpAddr,
+        ports: RangeInclusive<u16>
+    }
+}
+
+impl BindAddress {
+    pub fn new(ip: IpAddr, ports: RangeInclusive<u16>) -> Self {
+        BindAddress {
+            ip,
+            ports
+        }
+    }
+
+    /// Binds the first port `bind` succeeds on. Only ports that are in use are skipped: any other
+    /// error, or the error of a single port, is returned as it is. A range that is all taken
+    /// reports `NoFreePort`.
+    pub(crate) fn bind_first<T, F: Fn(&SocketAddr) -> io::Result<T>>(&self, bind: F) -> Result<T, BindError> {
+        let single = self.ports.start() == self.ports.end();
+        for port in self.ports.clone() {
+            let address = SocketAddr::new(self.ip, port);
+            match bind(&address) {
+                Ok(bound) => return Ok(bound),
+                Err(ref error) if !single && error.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(error) => return Err(BindError::Io { address, error })
+            }
+        }
+        Err(BindError::NoFreePort { ip: self.ip, ports: self.ports.clone() })
+    }
+}
+
+impl From<SocketAddr> for BindAddress {
+    fn from(address: SocketAddr) -> Self {
+        BindAddress::new(address.ip(), address.port()..=address.port())
+    }
+}
+
+impl BindError {
+    /// Wraps an error from binding `address`.
+    pub(crate) fn io(address: SocketAddr) -> impl FnOnce(io::Error) -> BindError {
+        move |error| BindError::Io { address, error }
+    }
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindError::InvalidAddress(address) => write!(f, "'{}' isn't a valid IP address", address),
+            BindError::Io { address, error } => write!(f, "Could not listen on {}: {}", address, error),
+            BindError::NoFreePort { ip, ports } => write!(f, "No port from {} to {} was free on {}", ports.start(), ports.end(), ip)
+        }
+    }
+}
+
+impl std::error::Error for BindError {}
@@ -0,0 +1,111 @@
+//! Protocol-level heartbeats for the legacy server, and for plain and TLS clients of
+//! `network::Server`. Every `interval`, the client is sent a `heartbeat` message and is expected
+//! to send its id straight back as `{"heartbeat": id}`. The time the answer takes is the
+//! connection's round trip time. A client that sends nothing at all for `idle_timeout` is
+//! disconnected.
+
+use crate::core::{LinkStats, OutboundMessage};
+use std::time::{Duration, Instant};
+
+/// How long a new client has to finish its TLS or WebSocket handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub idle_timeout: Duration
+}
+
+/// The message clients are sent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeartbeatPing {
+    pub id: u64
+}
+
+/// What clients send back.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeartbeatReply {
+    pub heartbeat: u64
+}
+
+/// Keeps track of one connection's heartbeats. It does no I/O itself: the client thread tells it
+/// what arrived and sends the pings it hands out.
+#[derive(Debug)]
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    link: LinkStats,
+    next_id: u64,
+    // The ping that hasn't been answered yet, and when it was sent.
+    outstanding: Option<(u64, Instant)>,
+    last_sent: Instant,
+    last_heard: Instant
+}
+
+impl OutboundMessage for HeartbeatPing {
+    const TAG: &'static str = "heartbeat";
+}
+
+impl HeartbeatConfig {
+    pub fn new(interval: Duration, idle_timeout: Duration) -> Self {
+        HeartbeatConfig {
+            interval,
+            idle_timeout
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig::new(Duration::from_secs(5), Duration::from_secs(30))
+    }
+}
+
+impl Heartbeat {
+    /// Starts tracking a connection that was just made. Round trip times are written to `link`.
+    pub fn new(config: HeartbeatConfig, link: LinkStats, now: Instant) -> Self {
+        link.heard(now);
+        Heartbeat {
+            config,
+            link,
+            next_id: 0,
+            outstanding: None,
+            last_sent: now,
+            last_heard: now
+        }
+    }
+
+    /// Records that the client sent something, which shows it is still there.
+    pub fn heard(&mut self, now: Instant) {
+        self.last_heard = now;
+        self.link.heard(now);
+    }
+
+    /// Records an answer to a ping. Answers to pings other than the latest one are ignored.
+    pub fn answered(&mut self, reply: &HeartbeatReply, now: Instant) {
+        self.heard(now);
+        match self.outstanding {
+            Some((id, sent)) if id == reply.heartbeat => {
+                self.outstanding = None;
+                self.link.record_rtt(now - sent);
+            },
+            _ => {}
+        }
+    }
+
+    /// Whether the client has been quiet for longer than the idle timeout.
+    pub fn timed_out(&self, now: Instant) -> bool {
+        now - self.last_heard >= self.config.idle_timeout
+    }
+
+    /// Hands out a ping if one is due.
+    pub fn poll(&mut self, now: Instant) -> Option<HeartbeatPing> {
+        if now - self.last_sent < self.config.interval {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.last_sent = now;
+        self.outstanding = Some((id, now));
+        Some(HeartbeatPing { id })
+    }
+}
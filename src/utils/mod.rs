@@ -1,17 +1,21 @@
 use super::*;
+use specs::prelude::*;
 use super::core::*;
 use std::collections::{HashMap, VecDeque};
 
-use std::net::TcpStream;
-
 pub mod server;
+pub mod bind;
+pub mod websocket;
+pub mod tls;
+pub mod heartbeat;
+pub mod ratelimit;
+pub mod queue;
 
 // This is a comprehensive utility function collection to make your
 // Hyperspeed code look nicer.
 
 
 /// A macro that makes writing components easier and consistent.
-
 #[macro_export]
 macro_rules! define_component {
     ($id:ident) => {
@@ -19,10 +23,15 @@ macro_rules! define_component {
         type Storage = VecStorage<Self>;
       }
     };
+    ($id:ident, $name:expr) => {
+      define_component!($id);
+      impl $crate::core::NamedComponent for $id {
+        const NAME: &'static str = $name;
+      }
+    };
 }
 
 /// A shorthand macro for registering multiple components.
-
 #[macro_export]
 macro_rules! register_components {
     ($e:ident, $($c:ty),+) => {
@@ -34,7 +43,9 @@ macro_rules! register_components {
 
 // Closure types
 
-pub type StreamHandler = fn(&mut TcpStream) -> StreamData;
+/// Decides whether a new client may connect, usually by reading a login key from it. It is used
+/// for TCP and WebSocket clients alike.
+pub type StreamHandler = fn(&mut dyn server::ClientStream) -> StreamData;
 
 // Resource fetching
 
@@ -56,6 +67,27 @@ pub type ReadViewMap<'a> = Read<'a, ViewMap>;
 
 pub type WriteViewMap<'a> = Write<'a, ViewMap>;
 
+pub type ReadOutbox<'a> = Read<'a, Outbox>;
+
+pub type WriteOutbox<'a> = Write<'a, Outbox>;
+
+/// The time, in seconds, that the current tick covers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeltaTime(pub f64);
+
+pub type ReadDeltaTime<'a> = Read<'a, DeltaTime>;
+
 pub type ReadConnections<'a> = Read<'a, ConnectionCollection>;
 
-pub type WriteConnections<'a> = Write<'a, ConnectionCollection>;
\ No newline at end of file
+pub type WriteConnections<'a> = Write<'a, ConnectionCollection>;
+
+/// What the server noticed about the world's connections since the last tick.
+pub type ConnectionEvents = Vec<ConnectionEvent>;
+
+pub type ReadConnectionEvents<'a> = Read<'a, ConnectionEvents>;
+
+pub type ReadConnectionStats<'a> = Read<'a, ConnectionStats>;
+
+pub type ReadMatchmaking<'a> = Read<'a, MatchmakingQueue>;
+
+pub type WriteMatchmaking<'a> = Write<'a, MatchmakingQueue>;
\ No newline at end of file
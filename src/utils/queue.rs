@@ -0,0 +1,261 @@
+//! Bounded send queues, one per client. When a client reads more slowly than the server writes,
+//! its queue fills up and the overflow policy decides what gives, so a slow client can't make the
+//! server's memory grow without bound.
+
+use futures::task::AtomicTask;
+use futures::{Async, Poll, Stream};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// What happens when something is sent to a full queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drops the oldest superseded item, such as a view a newer one replaces, or the oldest
+    /// item if nothing is superseded.
+    DropOldest,
+    /// Collapses the queued superseded items into the newest one. If that doesn't make room,
+    /// the new item is dropped.
+    Coalesce,
+    /// Closes the queue, which disconnects the client.
+    Disconnect
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendQueueConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The receiving end has gone.
+    Closed,
+    /// The queue was full and the policy is `Disconnect`. The queue is closed now.
+    Overflowed
+}
+
+struct Inner<T> {
+    items: VecDeque<T>,
+    config: SendQueueConfig,
+    // Nothing more can be sent: the receiver has gone, the queue overflowed, or it was finished.
+    closed: bool,
+    overflowed: bool,
+    // The sender is done, but what is queued still gets delivered.
+    finished: bool,
+    // How many `QueueSender`s are alive. Counted here rather than read off the `Arc`, so a
+    // sender that is being dropped is already gone when it wakes the receiver.
+    senders: usize
+}
+
+struct Shared<T> {
+    inner: Mutex<Inner<T>>,
+    task: AtomicTask,
+    // Whether an item makes older such items pointless, like views.
+    supersedes: fn(&T) -> bool
+}
+
+/// The server's end of a send queue. Clones send into the same queue. Once every clone has been
+/// dropped, the queue is finished, as if `finish` had been called.
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>
+}
+
+/// The client's end of a send queue. Dropping it closes the queue.
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>
+}
+
+/// Makes a send queue. `supersedes` picks out items that newer ones of the same kind replace;
+/// overflow policies drop those first.
+pub fn send_queue<T>(config: SendQueueConfig, supersedes: fn(&T) -> bool) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner {
+            items: VecDeque::new(),
+            config: SendQueueConfig { capacity: config.capacity.max(1), ..config },
+            closed: false,
+            overflowed: false,
+            finished: false,
+            senders: 1
+        }),
+        task: AtomicTask::new(),
+        supersedes
+    });
+    (QueueSender { shared: shared.clone() }, QueueReceiver { shared })
+}
+
+impl SendQueueConfig {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        SendQueueConfig {
+            capacity,
+            policy
+        }
+    }
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        SendQueueConfig::new(256, OverflowPolicy::DropOldest)
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::Closed => write!(f, "The client is gone"),
+            SendError::Overflowed => write!(f, "The client fell too far behind")
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl<T> QueueSender<T> {
+    /// Queues an item. Returns how many items the overflow policy dropped to make room,
+    /// including `item` itself if it didn't fit.
+    pub fn send(&self, item: T) -> Result<usize, SendError> {
+        let supersedes = self.shared.supersedes;
+        let mut inner = self.shared.inner.lock().unwrap();
+        if inner.closed {
+            return Err(SendError::Closed);
+        }
+        let mut dropped = 0;
+        if inner.items.len() >= inner.config.capacity {
+            match inner.config.policy {
+                OverflowPolicy::DropOldest => {
+                    let oldest = inner.items.iter().position(supersedes).unwrap_or(0);
+                    inner.items.remove(oldest);
+                    dropped += 1;
+                },
+                OverflowPolicy::Coalesce => {
+                    // A new superseding item replaces every queued one; otherwise the newest
+                    // queued one is kept.
+                    let keep = if supersedes(&item) { None } else { inner.items.iter().rposition(supersedes) };
+                    let before = inner.items.len();
+                    let mut index = 0;
+                    inner.items.retain(|queued| {
+                        let kept = !supersedes(queued) || Some(index) == keep;
+                        index += 1;
+                        kept
+                    });
+                    dropped += before - inner.items.len();
+                    if inner.items.len() >= inner.config.capacity {
+                        return Ok(dropped + 1);
+                    }
+                },
+                OverflowPolicy::Disconnect => {
+                    inner.closed = true;
+                    inner.overflowed = true;
+                    inner.items.clear();
+                    drop(inner);
+                    self.shared.task.notify();
+                    return Err(SendError::Overflowed);
+                }
+            }
+        }
+        inner.items.push_back(item);
+        drop(inner);
+        self.shared.task.notify();
+        Ok(dropped)
+    }
+
+    /// How many items are waiting.
+    pub fn len(&self) -> usize {
+        self.shared.inner.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.inner.lock().unwrap().closed
+    }
+
+    /// Stops the queue taking anything else. The receiver still gets what is already queued,
+    /// and then sees the queue end.
+    pub fn finish(&self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.closed = true;
+        inner.finished = true;
+        drop(inner);
+        self.shared.task.notify();
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.inner.lock().unwrap().senders += 1;
+        QueueSender {
+            shared: self.shared.clone()
+        }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().senders -= 1;
+        // The receiver may be waiting to hear that this was the last sender.
+        self.shared.task.notify();
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Takes everything that is waiting. An error means the queue was closed because it
+    /// overflowed.
+    pub fn drain(&mut self) -> Result<Vec<T>, SendError> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if inner.overflowed {
+            return Err(SendError::Overflowed);
+        }
+        Ok(inner.items.drain(..).collect())
+    }
+
+    /// Whether the sender has finished and everything it queued has been taken.
+    pub fn is_finished(&self) -> bool {
+        let inner = self.shared.inner.lock().unwrap();
+        self.abandoned(&inner) && inner.items.is_empty()
+    }
+
+    fn abandoned(&self, inner: &Inner<T>) -> bool {
+        inner.finished || inner.senders == 0
+    }
+
+    /// Whether the queue was closed because it overflowed.
+    pub fn overflowed(&self) -> bool {
+        self.shared.inner.lock().unwrap().overflowed
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.inner.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Ends when the queue overflows, or when it has been finished and emptied.
+impl<T> Stream for QueueReceiver<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        self.shared.task.register();
+        let mut inner = self.shared.inner.lock().unwrap();
+        if inner.overflowed {
+            return Ok(Async::Ready(None));
+        }
+        match inner.items.pop_front() {
+            Some(item) => Ok(Async::Ready(Some(item))),
+            None if self.abandoned(&inner) => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().closed = true;
+    }
+}
@@ -0,0 +1,184 @@
+//! Inbound rate limiting. Each client gets two token buckets, one counting messages and one
+//! counting bytes. A message that doesn't fit in both is a violation, and repeated violations
+//! escalate from dropping the message, to warning the client, to disconnecting it.
+
+use crate::core::OutboundMessage;
+use std::time::{Duration, Instant};
+
+/// Lets `burst` units through at once, refilling at `per_second`.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    burst: f64,
+    per_second: f64,
+    tokens: f64,
+    last: Instant
+}
+
+/// A bucket's size and refill rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BucketConfig {
+    pub burst: f64,
+    pub per_second: f64
+}
+
+/// How violations escalate. Violations are counted until the client has gone `forgive_after`
+/// without one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EscalationPolicy {
+    /// From this many violations on, the client is warned as well.
+    pub warn_after: u32,
+    /// At this many violations, the client is disconnected.
+    pub disconnect_after: u32,
+    pub forgive_after: Duration
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    pub messages: BucketConfig,
+    pub bytes: BucketConfig,
+    pub policy: EscalationPolicy
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RateLimitKind {
+    Messages,
+    Bytes
+}
+
+/// What is done about a violation. The message is dropped in every case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RateLimitAction {
+    Drop,
+    Warn,
+    Disconnect
+}
+
+/// A message that went over a limit, and what was done about it. Clients that are warned are
+/// sent this as a `rate_limited` message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitViolation {
+    pub kind: RateLimitKind,
+    pub action: RateLimitAction,
+    /// How many violations in a row this is.
+    pub count: u32
+}
+
+/// One client's buckets and violation count.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    policy: EscalationPolicy,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+    last_violation: Option<Instant>
+}
+
+impl OutboundMessage for RateLimitViolation {
+    const TAG: &'static str = "rate_limited";
+}
+
+impl TokenBucket {
+    pub fn new(config: BucketConfig, now: Instant) -> Self {
+        TokenBucket {
+            burst: config.burst,
+            per_second: config.per_second,
+            tokens: config.burst,
+            last: now
+        }
+    }
+
+    /// Whether `amount` fits in the bucket right now. If it does, it is taken out.
+    pub fn take(&mut self, amount: f64, now: Instant) -> bool {
+        if now > self.last {
+            let refill = (now - self.last).as_secs_f64() * self.per_second;
+            self.tokens = (self.tokens + refill).min(self.burst);
+            self.last = now;
+        }
+        if amount > self.tokens {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+impl BucketConfig {
+    pub fn new(burst: f64, per_second: f64) -> Self {
+        BucketConfig {
+            burst,
+            per_second
+        }
+    }
+}
+
+impl Default for EscalationPolicy {
+    fn default() -> Self {
+        EscalationPolicy {
+            warn_after: 3,
+            disconnect_after: 20,
+            forgive_after: Duration::from_secs(10)
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn new(messages: BucketConfig, bytes: BucketConfig) -> Self {
+        RateLimitConfig {
+            messages,
+            bytes,
+            policy: EscalationPolicy::default()
+        }
+    }
+
+    pub fn policy(mut self, policy: EscalationPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Default for RateLimitConfig {
+    /// Bursts of 60 messages or 64 KiB, then 30 messages or 16 KiB a second.
+    fn default() -> Self {
+        RateLimitConfig::new(BucketConfig::new(60.0, 30.0), BucketConfig::new(65536.0, 16384.0))
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, now: Instant) -> Self {
+        RateLimiter {
+            policy: config.policy,
+            messages: TokenBucket::new(config.messages, now),
+            bytes: TokenBucket::new(config.bytes, now),
+            violations: 0,
+            last_violation: None
+        }
+    }
+
+    /// Checks a message of `bytes` bytes that just arrived. An error means it must be dropped.
+    pub fn check(&mut self, bytes: usize, now: Instant) -> Result<(), RateLimitViolation> {
+        let kind = if !self.messages.take(1.0, now) {
+            RateLimitKind::Messages
+        } else if !self.bytes.take(bytes as f64, now) {
+            RateLimitKind::Bytes
+        } else {
+            return Ok(());
+        };
+        match self.last_violation {
+            Some(last) if now - last < self.policy.forgive_after => self.violations += 1,
+            _ => self.violations = 1
+        }
+        self.last_violation = Some(now);
+        let action = if self.violations >= self.policy.disconnect_after {
+            RateLimitAction::Disconnect
+        } else if self.violations >= self.policy.warn_after {
+            RateLimitAction::Warn
+        } else {
+            RateLimitAction::Drop
+        };
+        Err(RateLimitViolation {
+            kind,
+            action,
+            count: self.violations
+        })
+    }
+}
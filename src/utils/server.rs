@@ -1,7 +1,31 @@
 use std::net::TcpStream;
-use bytes::{BufMut, BytesMut};
-use std::io::{Read, ErrorKind, Write};
-use crate::core::ClientView;
+use bytes::BytesMut;
+use std::io::{self, Read, ErrorKind, Write};
+use serde::Serialize;
+
+/// A connection to a client on the legacy server, over plain TCP or a WebSocket. Stream handlers
+/// get one of these, so the same login code works for every kind of client.
+pub trait ClientStream: Read + Write + Send {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()>;
+
+    /// Whether every read returns exactly one message, so messages don't need the `!!!`
+    /// terminator.
+    fn is_framed(&self) -> bool {
+        false
+    }
+
+    /// Called on every pass of the client's loop, for protocols that need to ping. An error
+    /// closes the connection.
+    fn keep_alive(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ClientStream for TcpStream {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
 
 #[derive(Deserialize)]
 pub struct InputMessage {
@@ -21,7 +45,7 @@ fn find_stream_end_chars(msg: String) -> usize {
             return msg.find(character).unwrap();
         }
     }
-    return 0;
+    0
 }
 
 pub enum StreamReadResult {
@@ -39,36 +63,49 @@ pub enum StreamWriteResult {
 
 use self::StreamReadResult::*;
 
-pub fn read_message_from_stream(stream: &mut TcpStream, buffer: &mut BytesMut) -> StreamReadResult {
-    stream.set_nonblocking(false);
+/// Takes the message out of what a framed stream read. The terminator is optional there.
+fn framed_message(bytes: &[u8]) -> StreamReadResult {
+    let msg = String::from_utf8_lossy(bytes);
+    let msg = msg.trim_end().trim_end_matches("!!!");
+    if msg.is_empty() {
+        return InvalidMessage;
+    }
+    ValidMessage(msg.to_string())
+}
+
+/// Takes the message out of what an unframed stream read, up to its `!!!` terminator. Only
+/// what this read filled in is looked at, so an earlier message is never read twice.
+fn terminated_message(bytes: &[u8]) -> StreamReadResult {
+    let msg = String::from_utf8_lossy(bytes);
+    let msg_len = find_stream_end_chars(msg.to_string());
+    if msg_len == 0 {
+        return InvalidMessage;
+    }
+    ValidMessage(msg.chars().take(msg_len).collect())
+}
+
+pub fn read_message_from_stream<S: ClientStream + ?Sized>(stream: &mut S, buffer: &mut BytesMut) -> StreamReadResult {
+    if let Err(e) = stream.set_nonblocking(false) {
+        return StreamError(e.to_string());
+    }
 
     match stream.read(buffer.as_mut()) {
-        Ok(_) => {
-            let msg = String::from_utf8_lossy(buffer.as_ref());
-            let msg_len = find_stream_end_chars(msg.to_string());
-            if msg_len <= 0 {
-                return InvalidMessage;
-            }
-            let msg: String = msg.chars().take(msg_len).collect();
-            ValidMessage(msg)
-        },
+        Ok(0) => StreamError("the client closed the connection".to_string()),
+        Ok(len) if stream.is_framed() => framed_message(&buffer[..len]),
+        Ok(len) => terminated_message(&buffer[..len]),
         Err(e) => StreamError(e.to_string())
     }
 }
 
-pub fn read_from_message_from_stream_nonblocking(stream: &mut TcpStream, buffer: &mut BytesMut) -> StreamReadResult {
-    stream.set_nonblocking(true);
+pub fn read_from_message_from_stream_nonblocking<S: ClientStream + ?Sized>(stream: &mut S, buffer: &mut BytesMut) -> StreamReadResult {
+    if let Err(e) = stream.set_nonblocking(true) {
+        return StreamError(e.to_string());
+    }
 
     match stream.read(buffer.as_mut()) {
-        Ok(_) => {
-            let msg = String::from_utf8_lossy(buffer.as_ref());
-            let msg_len = find_stream_end_chars(msg.to_string());
-            if msg_len <= 0 {
-                return InvalidMessage;
-            }
-            let msg: String = msg.chars().take(msg_len).collect();
-            ValidMessage(msg)
-        },
+        Ok(0) => StreamError("the client closed the connection".to_string()),
+        Ok(len) if stream.is_framed() => framed_message(&buffer[..len]),
+        Ok(len) => terminated_message(&buffer[..len]),
         Err(e) => {
             match e.kind() {
                 ErrorKind::WouldBlock => NotReady,
@@ -78,26 +115,26 @@ pub fn read_from_message_from_stream_nonblocking(stream: &mut TcpStream, buffer:
     }
 }
 
-pub fn send_view_to_stream(stream: &mut TcpStream, view: ClientView) -> StreamWriteResult {
-    // Serialize view
-    let ser_view = serde_json::to_string(&view);
-    if ser_view.is_err() {
-        return StreamWriteResult::OtherError(
-            format!("Serialization of view failed: {}", ser_view.unwrap_err().to_string()))
-    }
-    let ser_view = ser_view.unwrap() + "\n";
-    loop {
-        match stream.write(ser_view.as_bytes()) {
-            Ok(_) => break,
-            Err(e) => match e.kind() {
-                ErrorKind::WouldBlock => continue,
-                ErrorKind::BrokenPipe
-                | ErrorKind::ConnectionReset
-                | ErrorKind::ConnectionAborted => return StreamWriteResult::SocketClosed,
-                _ => return StreamWriteResult::OtherError(e.to_string())
-            }
+/// Writes a view or message to the client as one line of JSON. A line has to go out whole, or
+/// the client would see it cut short, so it is written in blocking mode. The client loop's next
+/// read puts the stream back the way it needs it.
+pub fn send_to_stream<S: ClientStream + ?Sized, T: Serialize>(stream: &mut S, data: &T) -> StreamWriteResult {
+    let mut line = match serde_json::to_string(data) {
+        Ok(line) => line,
+        Err(e) => return StreamWriteResult::OtherError(format!("Serialization of view failed: {}", e))
+    };
+    line.push('\n');
+    let written = stream.set_nonblocking(false)
+        .and_then(|_| stream.write_all(line.as_bytes()))
+        .and_then(|_| stream.flush());
+    match written {
+        Ok(()) => StreamWriteResult::Ok,
+        Err(e) => match e.kind() {
+            ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::WriteZero => StreamWriteResult::SocketClosed,
+            _ => StreamWriteResult::OtherError(e.to_string())
         }
     }
-    stream.flush();
-    StreamWriteResult::Ok
 }
@@ -0,0 +1,100 @@
+//! TLS for both servers. The certificate and key are loaded from PEM files and can be reloaded
+//! while the server runs. Connections that are already open keep the certificate they started
+//! with; only new connections get the reloaded one.
+
+use super::server::ClientStream;
+use rustls::internal::pemfile;
+use rustls::{Certificate, NoClientAuth, PrivateKey, ServerSession, Session, StreamOwned};
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// A TLS connection on the legacy server.
+pub type TlsStream = StreamOwned<ServerSession, TcpStream>;
+
+/// Where a server's certificate comes from, and the certificate it is using right now. Clones
+/// share the certificate, so keep one to call `reload` on after handing one to a server.
+#[derive(Clone)]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: Arc<RwLock<Arc<rustls::ServerConfig>>>
+}
+
+impl TlsConfig {
+    /// Loads a certificate chain and its private key (PKCS#8 or RSA) from PEM files.
+    pub fn from_pem_files<C: AsRef<Path>, K: AsRef<Path>>(cert_path: C, key_path: K) -> io::Result<Self> {
+        let config = load_server_config(cert_path.as_ref(), key_path.as_ref())?;
+        Ok(TlsConfig {
+            cert_path: cert_path.as_ref().to_path_buf(),
+            key_path: key_path.as_ref().to_path_buf(),
+            current: Arc::new(RwLock::new(Arc::new(config)))
+        })
+    }
+
+    /// Reads the PEM files again. If they can't be loaded, the old certificate stays in use.
+    pub fn reload(&self) -> io::Result<()> {
+        let config = load_server_config(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    /// The configuration new connections are accepted with.
+    pub fn server_config(&self) -> Arc<rustls::ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Runs the handshake on a blocking stream.
+    pub fn accept(&self, mut stream: TcpStream) -> io::Result<TlsStream> {
+        stream.set_nonblocking(false)?;
+        let mut session = ServerSession::new(&self.server_config());
+        while session.is_handshaking() {
+            session.complete_io(&mut stream)?;
+        }
+        Ok(StreamOwned::new(session, stream))
+    }
+
+    /// Runs the handshake on a tokio stream.
+    pub(crate) fn accept_async(&self, stream: tokio::net::TcpStream) -> tokio_rustls::Accept<tokio::net::TcpStream> {
+        tokio_rustls::TlsAcceptor::from(self.server_config()).accept(stream)
+    }
+}
+
+impl ClientStream for TlsStream {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.sock.set_nonblocking(nonblocking)
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn load_server_config(cert_path: &Path, key_path: &Path) -> io::Result<rustls::ServerConfig> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let mut config = rustls::ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)
+        .map_err(|e| invalid_data(format!("{}: {}", cert_path.display(), e)))?;
+    Ok(config)
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid_data(format!("{} is not a PEM file", path.display())))?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("{} has no certificates", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let not_pem = |_| invalid_data(format!("{} is not a PEM file", path.display()));
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?)).map_err(not_pem)?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?)).map_err(not_pem)?;
+    }
+    keys.into_iter().next().ok_or_else(|| invalid_data(format!("{} has no private key", path.display())))
+}
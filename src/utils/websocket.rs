@@ -0,0 +1,363 @@
+//! A small WebSocket (RFC 6455) implementation: the HTTP upgrade and frame encoding, plus a
+//! blocking stream for the legacy server. The codec functions don't do any I/O, so the tokio
+//! server uses them too.
+
+use super::server::ClientStream;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Upgrade requests bigger than this are refused.
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Frames bigger than this close the connection, so a client can't make us buffer forever.
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+const PONG_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>
+}
+
+/// What parsing an upgrade request can come to.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Handshake {
+    /// The request isn't complete yet.
+    Incomplete,
+    /// The request is complete. Send `response`; the first `consumed` bytes were the request.
+    Accept { response: Vec<u8>, consumed: usize },
+    Reject(String)
+}
+
+impl Opcode {
+    fn from_u8(value: u8) -> Option<Opcode> {
+        match value {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA
+        }
+    }
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let digest = sha1::Sha1::from(format!("{}{}", key.trim(), ACCEPT_GUID)).digest().bytes();
+    base64::encode(&digest)
+}
+
+/// Parses an HTTP upgrade request from the start of `buffer`.
+pub fn parse_handshake(buffer: &[u8]) -> Handshake {
+    let end = match buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end + 4,
+        None if buffer.len() > MAX_REQUEST_SIZE => return Handshake::Reject("upgrade request is too large".to_string()),
+        None => return Handshake::Incomplete
+    };
+    let request = String::from_utf8_lossy(&buffer[..end]);
+    let mut lines = request.split("\r\n");
+    if !lines.next().map(|l| l.starts_with("GET ")).unwrap_or(false) {
+        return Handshake::Reject("expected a GET request".to_string());
+    }
+    let mut key = None;
+    let mut upgrade = false;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        match name.as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-key" => key = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    match (upgrade, key) {
+        (true, Some(key)) => Handshake::Accept {
+            response: format!("HTTP/1.1 101 Switching Protocols\r\n\
+                               Upgrade: websocket\r\n\
+                               Connection: Upgrade\r\n\
+                               Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(&key)).into_bytes(),
+            consumed: end
+        },
+        _ => Handshake::Reject("not a WebSocket upgrade request".to_string())
+    }
+}
+
+/// Reads one frame from the start of `buffer`, unmasking it. Returns the frame and the number
+/// of bytes it took up, `Ok(None)` if the frame isn't complete yet, or an error for a frame
+/// that breaks the protocol.
+pub fn decode_frame(buffer: &[u8]) -> io::Result<Option<(Frame, usize)>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = Opcode::from_u8(buffer[0] & 0x0F)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "unknown WebSocket opcode"))?;
+    let masked = buffer[1] & 0x80 != 0;
+    let (len, mut offset) = match buffer[1] & 0x7F {
+        126 => {
+            if buffer.len() < 4 {
+                return Ok(None);
+            }
+            (((buffer[2] as usize) << 8) | buffer[3] as usize, 4)
+        },
+        127 => {
+            if buffer.len() < 10 {
+                return Ok(None);
+            }
+            let mut len = 0u64;
+            for byte in &buffer[2..10] {
+                len = (len << 8) | *byte as u64;
+            }
+            (len as usize, 10)
+        },
+        len => (len as usize, 2)
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "WebSocket frame is too large"));
+    }
+    let mask = if masked {
+        if buffer.len() < offset + 4 {
+            return Ok(None);
+        }
+        let mask = [buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]];
+        offset += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    if buffer.len() < offset + len {
+        return Ok(None);
+    }
+    let mut payload = buffer[offset..offset + len].to_vec();
+    if let Some(mask) = mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok(Some((Frame { fin, opcode, payload }, offset + len)))
+}
+
+/// Encodes a single, unmasked frame, as servers send them.
+pub fn encode_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode.to_u8());
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(126);
+        frame.push((payload.len() >> 8) as u8);
+        frame.push(payload.len() as u8);
+    } else {
+        frame.push(127);
+        for shift in (0..8).rev() {
+            frame.push(((payload.len() as u64) >> (shift * 8)) as u8);
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reassembles messages from frames, and says what to answer control frames with.
+#[derive(Default)]
+pub struct FrameAssembler {
+    partial: Option<Vec<u8>>
+}
+
+/// What a frame means for the connection.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameEvent {
+    Message(Vec<u8>),
+    /// Send this frame back.
+    Reply(Vec<u8>),
+    Pong,
+    Close,
+    /// Part of a message that continues in a later frame.
+    Nothing
+}
+
+impl FrameAssembler {
+    pub fn new() -> Self {
+        FrameAssembler { partial: None }
+    }
+
+    pub fn handle(&mut self, frame: Frame) -> io::Result<FrameEvent> {
+        match frame.opcode {
+            Opcode::Ping => Ok(FrameEvent::Reply(encode_frame(Opcode::Pong, &frame.payload))),
+            Opcode::Pong => Ok(FrameEvent::Pong),
+            Opcode::Close => Ok(FrameEvent::Close),
+            Opcode::Text | Opcode::Binary if frame.fin => Ok(FrameEvent::Message(frame.payload)),
+            Opcode::Text | Opcode::Binary => {
+                self.partial = Some(frame.payload);
+                Ok(FrameEvent::Nothing)
+            },
+            Opcode::Continuation => {
+                let mut message = self.partial.take()
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "continuation frame without a message"))?;
+                if message.len() + frame.payload.len() > MAX_FRAME_SIZE {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "WebSocket message is too large"));
+                }
+                message.extend(frame.payload);
+                if frame.fin {
+                    Ok(FrameEvent::Message(message))
+                } else {
+                    self.partial = Some(message);
+                    Ok(FrameEvent::Nothing)
+                }
+            }
+        }
+    }
+}
+
+/// A WebSocket connection on a blocking stream, usually a `TcpStream` or a `TlsStream`. Each
+/// `read` returns one message, and each `write` is sent as one text frame.
+pub struct WebSocketStream<S = TcpStream> {
+    stream: S,
+    nonblocking: bool,
+    incoming: Vec<u8>,
+    assembler: FrameAssembler,
+    last_ping: Instant,
+    last_pong: Instant
+}
+
+impl<S: ClientStream> WebSocketStream<S> {
+    /// Answers the HTTP upgrade request a client opens with.
+    pub fn accept(mut stream: S) -> io::Result<WebSocketStream<S>> {
+        stream.set_nonblocking(false)?;
+        let mut incoming = vec![];
+        let mut chunk = [0; 1024];
+        loop {
+            match parse_handshake(&incoming) {
+                Handshake::Incomplete => {
+                    let read = stream.read(&mut chunk)?;
+                    if read == 0 {
+                        return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed during WebSocket upgrade"));
+                    }
+                    incoming.extend_from_slice(&chunk[..read]);
+                },
+                Handshake::Accept { response, consumed } => {
+                    stream.write_all(&response)?;
+                    incoming.drain(..consumed);
+                    break;
+                },
+                Handshake::Reject(reason) => {
+                    let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+                    return Err(io::Error::new(ErrorKind::InvalidData, reason));
+                }
+            }
+        }
+        Ok(WebSocketStream {
+            stream,
+            nonblocking: false,
+            incoming,
+            assembler: FrameAssembler::new(),
+            last_ping: Instant::now(),
+            last_pong: Instant::now()
+        })
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        // A frame has to go out whole, so it is written in blocking mode.
+        if self.nonblocking {
+            self.stream.set_nonblocking(false)?;
+        }
+        let result = self.stream.write_all(frame);
+        if self.nonblocking {
+            self.stream.set_nonblocking(true)?;
+        }
+        result
+    }
+}
+
+impl<S: ClientStream> Read for WebSocketStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0; 1024];
+        loop {
+            while let Some((frame, used)) = decode_frame(&self.incoming)? {
+                self.incoming.drain(..used);
+                match self.assembler.handle(frame)? {
+                    // A read of nothing means the connection closed, so empty messages are skipped.
+                    FrameEvent::Message(ref message) if message.is_empty() => {},
+                    FrameEvent::Message(message) => {
+                        // Like a TCP read, anything that doesn't fit in `buf` is cut off.
+                        let len = message.len().min(buf.len());
+                        buf[..len].copy_from_slice(&message[..len]);
+                        return Ok(len);
+                    },
+                    FrameEvent::Reply(reply) => self.write_frame(&reply)?,
+                    FrameEvent::Pong => self.last_pong = Instant::now(),
+                    FrameEvent::Close => {
+                        let _ = self.write_frame(&encode_frame(Opcode::Close, &[]));
+                        return Err(io::Error::new(ErrorKind::ConnectionAborted, "WebSocket closed by client"));
+                    },
+                    FrameEvent::Nothing => {}
+                }
+            }
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(0);
+            }
+            self.incoming.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+impl<S: ClientStream> Write for WebSocketStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_frame(&encode_frame(Opcode::Text, buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: ClientStream> ClientStream for WebSocketStream<S> {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    fn is_framed(&self) -> bool {
+        true
+    }
+
+    fn keep_alive(&mut self) -> io::Result<()> {
+        if self.last_pong.elapsed() >= PONG_TIMEOUT {
+            return Err(io::Error::new(ErrorKind::TimedOut, "WebSocket client stopped answering pings"));
+        }
+        if self.last_ping.elapsed() >= PING_INTERVAL {
+            self.last_ping = Instant::now();
+            self.write_frame(&encode_frame(Opcode::Ping, &[]))?;
+        }
+        Ok(())
+    }
+}